@@ -0,0 +1,152 @@
+//! Protocol of the local API, served by the daemon on a Unix socket.
+//!
+//! A client sends a single request as a JSON line. The daemon answers with
+//! JSON lines, each being one [`Response`]. Only the `events` request gets
+//! more than one response.
+//!
+//! The types are also the schema of JSON and YAML output of lockctl, so
+//! fields should only be added, never renamed or removed.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    events::{DaemonEvent, Event, EventFilter},
+    registry::{ContainerMetadata, PolicySource},
+    ContainerPolicyLevel, Decision, Hook,
+};
+
+/// Default path of the API socket.
+pub static DEFAULT_SOCKET_PATH: &str = "/run/lockc/lockc.sock";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Request {
+    ListContainers,
+    ListProcesses,
+    /// Shows the container with the given ID, ID prefix or name.
+    InspectContainer {
+        container: String,
+    },
+    /// Changes the policy level of the container with the given ID, ID
+    /// prefix or name and shows the container. Allowed only for root.
+    ApplyPolicy {
+        container: String,
+        policy_level: ContainerPolicyLevel,
+    },
+    /// Shows how many times LSM hooks allowed and denied actions in
+    /// containers with IDs starting with the given prefix.
+    Stats {
+        container: Option<String>,
+    },
+    /// Streams recent events matching the filter and, if `follow` is set,
+    /// new events as they come.
+    Events {
+        filter: EventFilter,
+        follow: bool,
+    },
+}
+
+/// Whether lockc restricts the container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EnforcementMode {
+    /// LSM hooks deny actions which the policy level doesn't allow.
+    Enforce,
+    /// The policy level allows all actions, LSM hooks only count them.
+    Unrestricted,
+}
+
+impl From<ContainerPolicyLevel> for EnforcementMode {
+    fn from(policy_level: ContainerPolicyLevel) -> Self {
+        match policy_level {
+            ContainerPolicyLevel::NotFound
+            | ContainerPolicyLevel::Lockc
+            | ContainerPolicyLevel::Privileged => EnforcementMode::Unrestricted,
+            _ => EnforcementMode::Enforce,
+        }
+    }
+}
+
+impl std::fmt::Display for EnforcementMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnforcementMode::Enforce => write!(f, "enforce"),
+            EnforcementMode::Unrestricted => write!(f, "unrestricted"),
+        }
+    }
+}
+
+/// Container registered in lockc.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContainerInfo {
+    pub container_id: String,
+    pub policy_level: ContainerPolicyLevel,
+    pub enforcement_mode: EnforcementMode,
+    /// Unknown if the container has no registry record (i.e. it was
+    /// registered by an older version of lockc).
+    pub policy_source: Option<PolicySource>,
+    #[serde(flatten)]
+    pub metadata: ContainerMetadata,
+    /// Time of registering the container, in seconds since Unix epoch.
+    pub registered_at: Option<u64>,
+}
+
+/// Containerized process registered in lockc.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessInfo {
+    pub pid: i32,
+    pub container_id: String,
+    /// Whether the process is still running. It might be not, if lockc
+    /// missed its exit.
+    pub running: bool,
+    pub exe: Option<String>,
+    /// PID of the parent process, unknown if the process is not running.
+    pub ppid: Option<i32>,
+}
+
+/// What the LSM hook does in the container and how many times it decided.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HookPolicy {
+    pub hook: Hook,
+    pub behavior: String,
+    pub allowed: u64,
+    pub denied: u64,
+}
+
+/// Container with its processes, hooks and recent denials.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContainerDetails {
+    #[serde(flatten)]
+    pub container: ContainerInfo,
+    pub processes: Vec<ProcessInfo>,
+    pub hooks: Vec<HookPolicy>,
+    /// Recent denials, oldest first. Only denials which the daemon still
+    /// keeps in its event history are included.
+    pub recent_denials: Vec<Event>,
+}
+
+/// Number of decisions made by the LSM hook for the container, summed
+/// across CPUs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HookStats {
+    pub container_id: String,
+    /// Name of the container, i.e. `namespace/pod/container` for Kubernetes
+    /// containers.
+    pub container_name: Option<String>,
+    pub hook: Hook,
+    pub decision: Decision,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Containers(Vec<ContainerInfo>),
+    Container(Box<ContainerDetails>),
+    Processes(Vec<ProcessInfo>),
+    Stats(Vec<HookStats>),
+    Event(Box<DaemonEvent>),
+    /// Number of events which the client missed because it was too slow.
+    Lagged(u64),
+    Error(String),
+}
@@ -0,0 +1,356 @@
+//! Events about containers sent by the daemon to its clients: denials made
+//! by LSM programs, containerized processes which could not be registered
+//! and registrations and deletions of containers.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    registry::{ContainerMetadata, ContainerRecord, PolicySource},
+    Action, ContainerPolicyLevel, Decision, DenialEvent, Hook, TrackingFailureEvent,
+};
+
+/// Returns the current time in seconds since Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Denial event in the form exposed to consumers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    /// Time of receiving the event, in seconds since Unix epoch.
+    pub timestamp: u64,
+    pub container_id: String,
+    pub hook: Hook,
+    pub action: Action,
+    pub decision: Decision,
+    pub policy_level: ContainerPolicyLevel,
+    pub pid: i32,
+    pub tgid: i32,
+    pub uid: u32,
+    pub comm: String,
+    /// Device (in major:minor format) involved in the action.
+    pub device: Option<String>,
+    /// Numeric argument of the action (i.e. UID or GID).
+    pub value: Option<u64>,
+    /// Path involved in the action.
+    pub path: Option<String>,
+    /// Network address of the other side of the socket, if it's known.
+    pub address: Option<String>,
+    /// Metadata of the container, filled in from the registry.
+    #[serde(flatten)]
+    pub metadata: ContainerMetadata,
+}
+
+/// Converts the nul terminated buffer into a string.
+fn buf_to_string(buf: &[u8]) -> String {
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+impl From<&DenialEvent> for Event {
+    fn from(event: &DenialEvent) -> Self {
+        let (device, value, path) = match event.action {
+            Action::OpenDevice | Action::CreateDevice => {
+                (Some(event.device.to_string()), None, None)
+            }
+            Action::Setuid
+            | Action::Setgid
+            | Action::SetgroupsLimit
+            | Action::Setgroups
+            | Action::PrivilegeEscalation => (None, Some(event.value), None),
+            Action::BindMount | Action::OpenPath => (None, None, Some(buf_to_string(&event.data))),
+            _ => (None, None, None),
+        };
+        Event {
+            timestamp: now(),
+            container_id: buf_to_string(&event.container_id.id),
+            hook: event.hook,
+            action: event.action,
+            decision: event.decision,
+            policy_level: event.policy_level,
+            pid: event.pid,
+            tgid: event.tgid,
+            uid: event.uid,
+            comm: buf_to_string(&event.comm),
+            device,
+            value,
+            path,
+            address: event
+                .address
+                .to_socket_addr()
+                .map(|address| address.to_string()),
+            metadata: ContainerMetadata::default(),
+        }
+    }
+}
+
+impl Event {
+    /// Returns a human readable description of the action.
+    pub fn action_description(&self) -> String {
+        let device = self.device.as_deref().unwrap_or_default();
+        let value = self.value.unwrap_or_default();
+        let path = self.path.as_deref().unwrap_or_default();
+        match self.action {
+            Action::Syslog => "accessing syslog".to_string(),
+            Action::BindMount => format!("bind mounting {}", path),
+            Action::SetuidRoot => "changing UID to root".to_string(),
+            Action::Setuid => format!("changing UID to {}", value),
+            Action::Setgid => format!("changing GID to {}", value),
+            Action::SetgroupsLimit => format!("setting {} supplementary groups", value),
+            Action::Setgroups => format!("setting supplementary group {}", value),
+            Action::PrivilegeEscalation => {
+                format!("gaining privileges of UID {} on exec", value)
+            }
+            Action::OpenDevice => format!("opening device {}", device),
+            Action::OpenRuntimeSocket => "opening container runtime socket".to_string(),
+            Action::OpenPath => format!("opening {}", path),
+            Action::CreateSetid => "creating setuid/setgid file".to_string(),
+            Action::CreateDevice => format!("creating device {}", device),
+            Action::SetSetid => "setting setuid/setgid bits".to_string(),
+            Action::SetFileCaps => "setting file capabilities".to_string(),
+            Action::ConnectRuntimeSocket => "connecting to container runtime socket".to_string(),
+            Action::Network => match (&self.address, self.hook) {
+                (Some(address), Hook::SocketSendmsg) => {
+                    format!("sending network messages to {}", address)
+                }
+                (Some(address), _) => format!("receiving network messages from {}", address),
+                (None, _) => "network access".to_string(),
+            },
+        }
+    }
+
+    /// Returns a human readable message about the event. The container is
+    /// identified by its name, if it's known.
+    pub fn message(&self) -> String {
+        format!(
+            "{}: {}: {} {}",
+            self.hook,
+            self.metadata
+                .display_name()
+                .unwrap_or_else(|| self.container_id.clone()),
+            self.decision,
+            self.action_description()
+        )
+    }
+}
+
+/// Containerized process which could not be registered, in the form exposed
+/// to consumers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackingFailure {
+    /// Time of receiving the event, in seconds since Unix epoch.
+    pub timestamp: u64,
+    pub container_id: String,
+    pub policy_level: ContainerPolicyLevel,
+    pub pid: i32,
+    pub comm: String,
+    /// Whether the process gets killed instead of escaping the policy.
+    pub killed: bool,
+    /// Metadata of the container, filled in from the registry.
+    #[serde(flatten)]
+    pub metadata: ContainerMetadata,
+}
+
+impl From<&TrackingFailureEvent> for TrackingFailure {
+    fn from(event: &TrackingFailureEvent) -> Self {
+        TrackingFailure {
+            timestamp: now(),
+            container_id: buf_to_string(&event.container_id.id),
+            policy_level: event.policy_level,
+            pid: event.pid,
+            comm: buf_to_string(&event.comm),
+            killed: event.killed,
+            metadata: ContainerMetadata::default(),
+        }
+    }
+}
+
+impl TrackingFailure {
+    /// Returns a human readable description of the consequence.
+    pub fn consequence(&self) -> &'static str {
+        if self.killed {
+            "the process is killed"
+        } else {
+            "the process is not restricted by the policy"
+        }
+    }
+}
+
+/// Registration or deletion of a container.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContainerEvent {
+    /// Time of the registration or deletion, in seconds since Unix epoch.
+    pub timestamp: u64,
+    pub container_id: String,
+    pub policy_level: ContainerPolicyLevel,
+    pub policy_source: PolicySource,
+    #[serde(flatten)]
+    pub metadata: ContainerMetadata,
+}
+
+impl ContainerEvent {
+    pub fn new(container_id: String, record: &ContainerRecord) -> Self {
+        ContainerEvent {
+            timestamp: now(),
+            container_id,
+            policy_level: record.policy_level,
+            policy_source: record.policy_source,
+            metadata: record.metadata.clone(),
+        }
+    }
+}
+
+/// Event published by the daemon.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DaemonEvent {
+    Denial(Event),
+    TrackingFailure(TrackingFailure),
+    ContainerRegistered(ContainerEvent),
+    ContainerDeleted(ContainerEvent),
+    PolicyChanged(ContainerEvent),
+}
+
+impl DaemonEvent {
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            DaemonEvent::Denial(event) => event.timestamp,
+            DaemonEvent::TrackingFailure(event) => event.timestamp,
+            DaemonEvent::ContainerRegistered(event)
+            | DaemonEvent::ContainerDeleted(event)
+            | DaemonEvent::PolicyChanged(event) => event.timestamp,
+        }
+    }
+
+    pub fn container_id(&self) -> &str {
+        match self {
+            DaemonEvent::Denial(event) => &event.container_id,
+            DaemonEvent::TrackingFailure(event) => &event.container_id,
+            DaemonEvent::ContainerRegistered(event)
+            | DaemonEvent::ContainerDeleted(event)
+            | DaemonEvent::PolicyChanged(event) => &event.container_id,
+        }
+    }
+
+    pub fn policy_level(&self) -> ContainerPolicyLevel {
+        match self {
+            DaemonEvent::Denial(event) => event.policy_level,
+            DaemonEvent::TrackingFailure(event) => event.policy_level,
+            DaemonEvent::ContainerRegistered(event)
+            | DaemonEvent::ContainerDeleted(event)
+            | DaemonEvent::PolicyChanged(event) => event.policy_level,
+        }
+    }
+
+    pub fn metadata(&self) -> &ContainerMetadata {
+        match self {
+            DaemonEvent::Denial(event) => &event.metadata,
+            DaemonEvent::TrackingFailure(event) => &event.metadata,
+            DaemonEvent::ContainerRegistered(event)
+            | DaemonEvent::ContainerDeleted(event)
+            | DaemonEvent::PolicyChanged(event) => &event.metadata,
+        }
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut ContainerMetadata {
+        match self {
+            DaemonEvent::Denial(event) => &mut event.metadata,
+            DaemonEvent::TrackingFailure(event) => &mut event.metadata,
+            DaemonEvent::ContainerRegistered(event)
+            | DaemonEvent::ContainerDeleted(event)
+            | DaemonEvent::PolicyChanged(event) => &mut event.metadata,
+        }
+    }
+
+    /// Returns the hook which made the decision. Tracking failures and
+    /// lifecycle events have no hook.
+    pub fn hook(&self) -> Option<Hook> {
+        match self {
+            DaemonEvent::Denial(event) => Some(event.hook),
+            _ => None,
+        }
+    }
+
+    /// Returns a human readable message about the event.
+    pub fn message(&self) -> String {
+        match self {
+            DaemonEvent::Denial(event) => event.message(),
+            DaemonEvent::TrackingFailure(event) => format!(
+                "{}: could not register process {} ({}), {}",
+                event
+                    .metadata
+                    .display_name()
+                    .unwrap_or_else(|| event.container_id.clone()),
+                event.pid,
+                event.comm,
+                event.consequence()
+            ),
+            DaemonEvent::ContainerRegistered(event) => format!(
+                "registered {} ({})",
+                event
+                    .metadata
+                    .display_name()
+                    .unwrap_or_else(|| event.container_id.clone()),
+                event.policy_level
+            ),
+            DaemonEvent::ContainerDeleted(event) => format!(
+                "deleted {}",
+                event
+                    .metadata
+                    .display_name()
+                    .unwrap_or_else(|| event.container_id.clone())
+            ),
+            DaemonEvent::PolicyChanged(event) => format!(
+                "changed policy level of {} to {}",
+                event
+                    .metadata
+                    .display_name()
+                    .unwrap_or_else(|| event.container_id.clone()),
+                event.policy_level
+            ),
+        }
+    }
+}
+
+/// Filter of events. Every set field has to match.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventFilter {
+    /// Prefix of the container ID, or the name of the container or its pod.
+    pub container: Option<String>,
+    /// Kubernetes namespace.
+    pub namespace: Option<String>,
+    /// Hook which made the decision. Excludes tracking failures and
+    /// lifecycle events.
+    pub hook: Option<Hook>,
+    pub policy_level: Option<ContainerPolicyLevel>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &DaemonEvent) -> bool {
+        let metadata = event.metadata();
+        if let Some(container) = &self.container {
+            if !event.container_id().starts_with(container.as_str())
+                && !metadata.matches_name(container)
+            {
+                return false;
+            }
+        }
+        if self.namespace.is_some() && metadata.namespace != self.namespace {
+            return false;
+        }
+        if self.hook.is_some() && event.hook() != self.hook {
+            return false;
+        }
+        if let Some(policy_level) = self.policy_level {
+            if event.policy_level() != policy_level {
+                return false;
+            }
+        }
+        true
+    }
+}
@@ -1,24 +1,58 @@
 #![cfg_attr(not(feature = "user"), no_std)]
 
-/// Max configurable PID limit (for x86_64, for the other architectures it's
-/// less or equal).
-// TODO(vadorovsky): I need to teach aya to be able to resize maps before they
-// are loaded into the kernel. So far aya doesn't differentiate between open()
-// and load(), it opens the ELF object and loads it immediately in one step.
-// I need to change it.
-// After that, we will be able to set the limit again up to the upper possible
-// limit. And resize according to the max PID limit in sysctl.
-// Before it's done - let's stick to the default value to not use too much RAM.
-// pub const PID_MAX_LIMIT: u32 = 4194304;
+#[cfg(feature = "user")]
+pub mod api;
+#[cfg(feature = "user")]
+pub mod events;
+#[cfg(feature = "user")]
+pub mod registry;
+
+/// Default size of eBPF maps keyed by PIDs or containers.
+///
+/// The daemon creates the pinned maps on its own, sized according to
+/// `kernel.pid_max` sysctl and the max number of containers, before loading
+/// the eBPF object. This limit applies only to maps which are not pinned and
+/// to maps created by aya when the object is loaded without the daemon
+/// (i.e. in tests).
 pub const PID_MAX_LIMIT: u32 = 32768;
 
+/// Version of the layout of types stored in pinned eBPF maps. Has to be
+/// bumped (and a migration has to be added to the daemon) every time one of
+/// these types changes.
+pub const MAPS_SCHEMA_VERSION: u32 = 1;
+
 pub const MOUNT_TYPE_LEN: usize = 5;
 
+/// IPv4 address family.
+pub const AF_INET: u16 = 2;
+/// IPv6 address family.
+pub const AF_INET6: u16 = 10;
+
 pub const PATH_LEN: usize = 64;
 
+/// Max length of the command name of a process (`TASK_COMM_LEN`).
+pub const COMM_LEN: usize = 16;
+
+/// Number of LSM hooks used by lockc.
+pub const HOOKS_COUNT: usize = 14;
+
+/// Max number of allowed devices per policy level.
+pub const ALLOWED_DEVICES_LIMIT: u32 = 64;
+
+/// Max number of runtime socket inodes which containers are not allowed to
+/// access.
+pub const RUNTIME_SOCKETS_LIMIT: u32 = 256;
+
+/// Device minor number which matches all minor numbers of the given major.
+pub const DEVICE_MINOR_ANY: u32 = u32::MAX;
+
 const CONTAINER_ID_LEN: usize = 64;
 
-#[cfg_attr(feature = "user", derive(Debug))]
+#[cfg_attr(
+    feature = "user",
+    derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 #[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 #[derive(Copy, Clone)]
 #[repr(C)]
@@ -34,6 +68,19 @@ pub enum ContainerPolicyLevel {
     Privileged,
 }
 
+/// Method of finding the container which the current process belongs to.
+#[cfg_attr(feature = "user", derive(Debug))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Copy, Clone)]
+#[repr(u8)]
+pub enum ProcessTracking {
+    /// Tracking PIDs of containerized processes with fork, exec and exit
+    /// tracepoints.
+    Pid,
+    /// Looking up the cgroup of the current process.
+    Cgroup,
+}
+
 #[cfg(feature = "user")]
 impl std::fmt::Display for ContainerPolicyLevel {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -112,16 +159,67 @@ impl ContainerID {
     }
 }
 
+/// Inclusive range of user or group IDs.
+#[cfg_attr(feature = "user", derive(Debug, PartialEq, Eq))]
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct IdRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl IdRange {
+    /// Range containing all IDs.
+    pub const ANY: IdRange = IdRange {
+        min: 0,
+        max: u32::MAX,
+    };
+
+    /// Creates a range containing only the given ID.
+    pub const fn single(id: u32) -> Self {
+        IdRange { min: id, max: id }
+    }
+
+    pub const fn contains(&self, id: u32) -> bool {
+        id >= self.min && id <= self.max
+    }
+
+    /// Checks whether a process is allowed to change its ID from `old` to
+    /// `new`. Keeping the current ID is always allowed, even if it's out of
+    /// the range (i.e. runc init calling `setuid(0)` in containers running
+    /// as root).
+    pub const fn allows_change(&self, old: u32, new: u32) -> bool {
+        new == old || self.contains(new)
+    }
+}
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct Container {
     pub policy_level: ContainerPolicyLevel,
+    /// UIDs which processes in the container are allowed to switch to.
+    pub uid_range: IdRange,
+    /// GIDs which processes in the container are allowed to switch to.
+    pub gid_range: IdRange,
+}
+
+/// Supplementary group which processes in the given container are allowed to
+/// have.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ContainerGroup {
+    pub container_id: ContainerID,
+    pub gid: u32,
 }
 
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct Process {
     pub container_id: ContainerID,
+    /// Start time of the process in clock ticks since boot (the same as
+    /// `starttime` in `/proc/<pid>/stat`). Used to distinguish the process
+    /// from later processes reusing its PID.
+    pub start_time: u64,
 }
 
 #[derive(Copy, Clone)]
@@ -136,11 +234,454 @@ pub struct Path {
     pub path: [u8; PATH_LEN],
 }
 
+/// LSM hook which made the policy decision.
+#[cfg_attr(
+    feature = "user",
+    derive(
+        Debug,
+        PartialEq,
+        Eq,
+        PartialOrd,
+        Ord,
+        Hash,
+        serde::Serialize,
+        serde::Deserialize
+    ),
+    serde(rename_all = "snake_case")
+)]
+#[cfg_attr(
+    feature = "cli",
+    derive(clap::ValueEnum),
+    value(rename_all = "snake_case")
+)]
+#[derive(Copy, Clone)]
+#[repr(u32)]
+pub enum Hook {
+    Syslog,
+    SbMount,
+    TaskFixSetuid,
+    TaskFixSetgid,
+    TaskFixSetgroups,
+    BprmCredsFromFile,
+    FileOpen,
+    PathMknod,
+    PathChmod,
+    InodeSetattr,
+    InodeSetxattr,
+    UnixStreamConnect,
+    SocketSendmsg,
+    SocketRecvmsg,
+}
+
+impl Hook {
+    pub const ALL: [Hook; HOOKS_COUNT] = [
+        Hook::Syslog,
+        Hook::SbMount,
+        Hook::TaskFixSetuid,
+        Hook::TaskFixSetgid,
+        Hook::TaskFixSetgroups,
+        Hook::BprmCredsFromFile,
+        Hook::FileOpen,
+        Hook::PathMknod,
+        Hook::PathChmod,
+        Hook::InodeSetattr,
+        Hook::InodeSetxattr,
+        Hook::UnixStreamConnect,
+        Hook::SocketSendmsg,
+        Hook::SocketRecvmsg,
+    ];
+}
+
+#[cfg(feature = "user")]
+impl std::fmt::Display for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hook::Syslog => write!(f, "syslog"),
+            Hook::SbMount => write!(f, "sb_mount"),
+            Hook::TaskFixSetuid => write!(f, "task_fix_setuid"),
+            Hook::TaskFixSetgid => write!(f, "task_fix_setgid"),
+            Hook::TaskFixSetgroups => write!(f, "task_fix_setgroups"),
+            Hook::BprmCredsFromFile => write!(f, "bprm_creds_from_file"),
+            Hook::FileOpen => write!(f, "file_open"),
+            Hook::PathMknod => write!(f, "path_mknod"),
+            Hook::PathChmod => write!(f, "path_chmod"),
+            Hook::InodeSetattr => write!(f, "inode_setattr"),
+            Hook::InodeSetxattr => write!(f, "inode_setxattr"),
+            Hook::UnixStreamConnect => write!(f, "unix_stream_connect"),
+            Hook::SocketSendmsg => write!(f, "socket_sendmsg"),
+            Hook::SocketRecvmsg => write!(f, "socket_recvmsg"),
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+impl Hook {
+    /// Describes what the LSM hook does in containers with the given policy
+    /// level. Has to be kept in sync with the eBPF programs.
+    pub fn behavior(&self, policy_level: ContainerPolicyLevel) -> &'static str {
+        use ContainerPolicyLevel::*;
+
+        if let NotFound | Lockc | Privileged = policy_level {
+            return "allow all";
+        }
+        match (self, policy_level) {
+            (Hook::Syslog, _) => "deny accessing kernel logs",
+            (Hook::SbMount, _) => "deny bind mounts, except of container engine directories",
+            (Hook::TaskFixSetuid, Restricted) => {
+                "deny changing UID to root or to UIDs of other users than the container one"
+            }
+            (Hook::TaskFixSetgid, Restricted) => {
+                "deny changing GID to other groups than the container one"
+            }
+            (Hook::TaskFixSetgroups, Restricted) => {
+                "deny setting supplementary groups other than the container ones"
+            }
+            (Hook::BprmCredsFromFile, Restricted) => {
+                "deny gaining privileges when executing setuid/setgid binaries"
+            }
+            (
+                Hook::TaskFixSetuid
+                | Hook::TaskFixSetgid
+                | Hook::TaskFixSetgroups
+                | Hook::BprmCredsFromFile,
+                _,
+            ) => "allow all",
+            (Hook::FileOpen, _) => {
+                "deny opening devices not allowed for the policy level, container runtime \
+                 sockets, most of /sys, /proc/acpi and service account tokens"
+            }
+            (Hook::PathMknod, _) => {
+                "deny creating setuid/setgid files and devices not allowed for the policy level"
+            }
+            (Hook::PathChmod | Hook::InodeSetattr, _) => "deny setting setuid/setgid bits",
+            (Hook::InodeSetxattr, _) => "deny setting file capabilities",
+            (Hook::UnixStreamConnect, _) => "deny connecting to container runtime sockets",
+            (Hook::SocketSendmsg | Hook::SocketRecvmsg, Offline) => "deny all",
+            (Hook::SocketSendmsg | Hook::SocketRecvmsg, _) => "allow all",
+        }
+    }
+}
+
+/// Action which was denied (or allowed) by the LSM hook.
+#[cfg_attr(
+    feature = "user",
+    derive(
+        Debug,
+        PartialEq,
+        Eq,
+        PartialOrd,
+        Ord,
+        Hash,
+        serde::Serialize,
+        serde::Deserialize
+    ),
+    serde(rename_all = "snake_case")
+)]
+#[derive(Copy, Clone)]
+#[repr(u32)]
+pub enum Action {
+    /// Accessing the kernel logs.
+    Syslog,
+    /// Bind mounting the path from `data`.
+    BindMount,
+    /// Switching to the root user.
+    SetuidRoot,
+    /// Switching to the UID from `value`.
+    Setuid,
+    /// Switching to the GID from `value`.
+    Setgid,
+    /// Setting more supplementary groups (number in `value`) than lockc is
+    /// able to check.
+    SetgroupsLimit,
+    /// Setting the supplementary group from `value`.
+    Setgroups,
+    /// Gaining privileges on exec (new effective UID in `value`).
+    PrivilegeEscalation,
+    /// Opening the device from `device`.
+    OpenDevice,
+    /// Opening a container runtime socket.
+    OpenRuntimeSocket,
+    /// Opening the file from `data`.
+    OpenPath,
+    /// Creating a setuid/setgid file.
+    CreateSetid,
+    /// Creating the device node from `device`.
+    CreateDevice,
+    /// Setting setuid/setgid bits.
+    SetSetid,
+    /// Setting file capabilities.
+    SetFileCaps,
+    /// Connecting to a container runtime socket.
+    ConnectRuntimeSocket,
+    /// Sending or receiving network messages.
+    Network,
+}
+
+/// Policy decision made by the LSM hook.
+#[cfg_attr(
+    feature = "user",
+    derive(Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[derive(Copy, Clone)]
+#[repr(u32)]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+#[cfg(feature = "user")]
+impl std::fmt::Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Decision::Allow => write!(f, "allow"),
+            Decision::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+/// Key of the counter of decisions made by the LSM hook for the container.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct HookStatsKey {
+    pub container_id: ContainerID,
+    pub hook: Hook,
+    pub decision: Decision,
+}
+
+/// Character or block device, identified by its major and minor number.
+#[cfg_attr(feature = "user", derive(Debug, PartialEq, Eq, Hash))]
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Device {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl Device {
+    pub const fn new(major: u32, minor: u32) -> Self {
+        Device { major, minor }
+    }
+
+    /// Decodes the device number in the userspace `dev_t` layout (the one
+    /// passed to mknod(2), `new_encode_dev` in the kernel): major in bits
+    /// 8-19, minor in bits 0-7 and 20-31.
+    pub const fn new_decode_dev(dev: u32) -> Self {
+        Device::new((dev & 0xfff00) >> 8, (dev & 0xff) | ((dev >> 12) & 0xfff00))
+    }
+}
+
+/// Event sent by LSM programs to userspace every time they deny an action.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct DenialEvent {
+    pub container_id: ContainerID,
+    pub hook: Hook,
+    pub action: Action,
+    pub decision: Decision,
+    pub policy_level: ContainerPolicyLevel,
+    /// ID of the thread (`pid` in kernel terms).
+    pub pid: i32,
+    /// ID of the process (`tgid` in kernel terms).
+    pub tgid: i32,
+    pub uid: u32,
+    /// Command name of the process, nul terminated.
+    pub comm: [u8; COMM_LEN],
+    /// Device involved in the action.
+    pub device: Device,
+    /// Numeric argument of the action (i.e. UID or GID).
+    pub value: u64,
+    /// Path involved in the action (i.e. opened file or mount source), nul
+    /// terminated.
+    pub data: [u8; PATH_LEN],
+    /// Network address involved in the action.
+    pub address: SockAddr,
+}
+
+/// Event sent to userspace every time a containerized process could not be
+/// registered in `PROCESSES`, so it escapes the policy of its container
+/// (or gets killed in the fail-closed mode).
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct TrackingFailureEvent {
+    pub container_id: ContainerID,
+    pub policy_level: ContainerPolicyLevel,
+    /// ID of the process (`tgid` in kernel terms) which could not be
+    /// registered.
+    pub pid: i32,
+    /// Command name of the current process, nul terminated. That's the
+    /// parent after fork and the process itself after exec.
+    pub comm: [u8; COMM_LEN],
+    /// Whether the process gets killed instead of escaping the policy.
+    pub killed: bool,
+}
+
+/// Inode, identified by the device of its filesystem and its number.
+#[cfg_attr(feature = "user", derive(Debug, PartialEq, Eq, Hash))]
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct InodeID {
+    pub device: Device,
+    pub ino: u64,
+}
+
+#[cfg(feature = "user")]
+#[derive(thiserror::Error, Debug)]
+pub enum ParseDeviceError {
+    #[error("device has to be in the major:minor format")]
+    Format,
+
+    #[error(transparent)]
+    Number(#[from] std::num::ParseIntError),
+}
+
+#[cfg(feature = "user")]
+impl std::str::FromStr for Device {
+    type Err = ParseDeviceError;
+
+    /// Parses the device in `major:minor` format. `*` can be used as a minor
+    /// number to match all minor numbers.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (major, minor) = s.split_once(':').ok_or(ParseDeviceError::Format)?;
+        let major = major.trim().parse()?;
+        let minor = match minor.trim() {
+            "*" => DEVICE_MINOR_ANY,
+            minor => minor.parse()?,
+        };
+        Ok(Device { major, minor })
+    }
+}
+
+#[cfg(feature = "user")]
+impl std::fmt::Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.minor {
+            DEVICE_MINOR_ANY => write!(f, "{}:*", self.major),
+            minor => write!(f, "{}:{}", self.major, minor),
+        }
+    }
+}
+
+/// IPv4 or IPv6 socket address.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct SockAddr {
+    /// `AF_INET` or `AF_INET6`, 0 if there is no address.
+    pub family: u16,
+    /// Port in host byte order.
+    pub port: u16,
+    /// Address in network byte order. IPv4 addresses use the first 4 bytes.
+    pub addr: [u8; 16],
+}
+
+impl SockAddr {
+    pub const fn none() -> Self {
+        SockAddr {
+            family: 0,
+            port: 0,
+            addr: [0; 16],
+        }
+    }
+
+    pub const fn ipv4(addr: [u8; 4], port: u16) -> Self {
+        SockAddr {
+            family: AF_INET,
+            port,
+            addr: [
+                addr[0], addr[1], addr[2], addr[3], 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            ],
+        }
+    }
+
+    pub const fn ipv6(addr: [u8; 16], port: u16) -> Self {
+        SockAddr {
+            family: AF_INET6,
+            port,
+            addr,
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+impl SockAddr {
+    pub fn to_socket_addr(&self) -> Option<std::net::SocketAddr> {
+        let ip = match self.family {
+            AF_INET => {
+                std::net::IpAddr::from([self.addr[0], self.addr[1], self.addr[2], self.addr[3]])
+            }
+            AF_INET6 => std::net::IpAddr::from(self.addr),
+            _ => return None,
+        };
+        Some(std::net::SocketAddr::new(ip, self.port))
+    }
+}
+
 #[cfg(feature = "user")]
 mod user {
     use super::*;
 
     unsafe impl aya::Pod for ContainerID {}
     unsafe impl aya::Pod for Container {}
+    unsafe impl aya::Pod for ContainerGroup {}
     unsafe impl aya::Pod for Process {}
+    unsafe impl aya::Pod for Device {}
+    unsafe impl aya::Pod for InodeID {}
+    unsafe impl aya::Pod for HookStatsKey {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(dev: u32) -> (u32, u32) {
+        let device = Device::new_decode_dev(dev);
+        (device.major, device.minor)
+    }
+
+    #[test]
+    fn new_decode_dev() {
+        // /dev/null
+        assert_eq!(decode(0x103), (1, 3));
+        // Pseudo-terminals, also with minor numbers not fitting in 8 bits.
+        assert_eq!(decode(0x8800), (136, 0));
+        assert_eq!(decode(0x8805), (136, 5));
+        assert_eq!(decode(0x10882c), (136, 300));
+    }
+
+    #[test]
+    #[cfg(feature = "user")]
+    fn sock_addr() {
+        assert_eq!(SockAddr::none().to_socket_addr(), None);
+        assert_eq!(
+            SockAddr::ipv4([10, 0, 0, 1], 443)
+                .to_socket_addr()
+                .unwrap()
+                .to_string(),
+            "10.0.0.1:443"
+        );
+        let mut addr = [0; 16];
+        addr[15] = 1;
+        assert_eq!(
+            SockAddr::ipv6(addr, 53)
+                .to_socket_addr()
+                .unwrap()
+                .to_string(),
+            "[::1]:53"
+        );
+    }
+
+    #[test]
+    fn id_range_change() {
+        // Container running as root.
+        let range = IdRange::single(0);
+        assert!(range.allows_change(0, 0));
+        assert!(!range.allows_change(0, 1000));
+
+        // Container running as a regular user, started by runc as root.
+        let range = IdRange::single(1000);
+        assert!(range.allows_change(0, 1000));
+        assert!(range.allows_change(1000, 1000));
+        assert!(!range.allows_change(1000, 0));
+        assert!(!range.allows_change(1000, 1001));
+    }
 }
@@ -0,0 +1,140 @@
+//! Types of the registry of containers, which the daemon keeps in its state
+//! directory and which lockctl reads to show information about containers.
+
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ContainerPolicyLevel;
+
+/// Name of the registry file in the state directory.
+pub static REGISTRY_FILE: &str = "containers.json";
+
+/// Source of the policy level decision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PolicySource {
+    /// `pod-security.kubernetes.io/enforce` label of the Kubernetes
+    /// namespace.
+    NamespaceLabel,
+    /// kube-system namespace, which is always privileged.
+    KubeSystem,
+    /// `org.lockc.policy` label of the Docker container.
+    DockerLabel,
+    /// Default policy level, used when the container has no label.
+    Default,
+    /// Container created by an unknown engine, which always gets the
+    /// baseline policy level.
+    Fallback,
+    /// Policy level applied with lockctl.
+    Manual,
+}
+
+impl std::fmt::Display for PolicySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicySource::NamespaceLabel => write!(f, "namespace label"),
+            PolicySource::KubeSystem => write!(f, "kube-system"),
+            PolicySource::DockerLabel => write!(f, "docker label"),
+            PolicySource::Default => write!(f, "default"),
+            PolicySource::Fallback => write!(f, "fallback"),
+            PolicySource::Manual => write!(f, "manual"),
+        }
+    }
+}
+
+/// Container engine which created the container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContainerRuntime {
+    Docker,
+    Containerd,
+}
+
+impl std::fmt::Display for ContainerRuntime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContainerRuntime::Docker => write!(f, "docker"),
+            ContainerRuntime::Containerd => write!(f, "containerd"),
+        }
+    }
+}
+
+/// Information about the container provided by the engine which created it.
+///
+/// All fields are optional, so records written by older versions of lockc
+/// can still be read.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContainerMetadata {
+    /// Kubernetes namespace of the pod.
+    pub namespace: Option<String>,
+    /// Name of the Kubernetes pod.
+    pub pod: Option<String>,
+    /// UID of the Kubernetes pod.
+    pub pod_uid: Option<String>,
+    /// Name of the container in the pod, or of the Docker container.
+    pub container_name: Option<String>,
+    pub image: Option<String>,
+    pub runtime: Option<ContainerRuntime>,
+}
+
+impl ContainerMetadata {
+    /// Returns a short human readable name of the container, i.e.
+    /// `namespace/pod/container` for Kubernetes containers.
+    pub fn display_name(&self) -> Option<String> {
+        match (&self.namespace, &self.pod, &self.container_name) {
+            (Some(namespace), Some(pod), Some(name)) => {
+                Some(format!("{}/{}/{}", namespace, pod, name))
+            }
+            (Some(namespace), Some(pod), None) => Some(format!("{}/{}", namespace, pod)),
+            (_, _, Some(name)) => Some(name.clone()),
+            _ => None,
+        }
+    }
+
+    /// Checks whether the container has the given name, pod name or name in
+    /// the `namespace/pod/container` format.
+    pub fn matches_name(&self, name: &str) -> bool {
+        self.container_name.as_deref() == Some(name)
+            || self.pod.as_deref() == Some(name)
+            || self.display_name().as_deref() == Some(name)
+    }
+}
+
+/// Registry entry of a single container.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContainerRecord {
+    pub policy_level: ContainerPolicyLevel,
+    pub policy_source: PolicySource,
+    #[serde(flatten)]
+    pub metadata: ContainerMetadata,
+    /// Time of registering the container, in seconds since Unix epoch.
+    pub registered_at: u64,
+    /// Cgroup of the container, relative to the cgroup root or absolute.
+    /// Used to check whether the container is still running, regardless of
+    /// where runc keeps its state.
+    pub cgroup_path: Option<PathBuf>,
+}
+
+impl ContainerRecord {
+    pub fn new(
+        policy_level: ContainerPolicyLevel,
+        policy_source: PolicySource,
+        metadata: ContainerMetadata,
+    ) -> Self {
+        let registered_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        ContainerRecord {
+            policy_level,
+            policy_source,
+            metadata,
+            registered_at,
+            cgroup_path: None,
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use aya_bpf::{
+    helpers::{bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_get_current_uid_gid},
+    BpfContext,
+};
+
+use lockc_common::{
+    Action, ContainerID, ContainerPolicyLevel, Decision, DenialEvent, Device, Hook, SockAddr,
+    TrackingFailureEvent,
+};
+
+use crate::maps::{CONTAINERS, EVENTS, EVENT_BUF, TRACKING_FAILURE_EVENTS};
+
+/// Prepares the denial event in the per-CPU buffer, with the fields common
+/// for all hooks filled. Fields specific to the action (`device`, `value`,
+/// `data`, `address`) are zeroed and can be filled by the caller before sending the
+/// event with `send_event`.
+#[inline(always)]
+pub(crate) fn denial_event(
+    hook: Hook,
+    action: Action,
+    container_id: ContainerID,
+    policy_level: ContainerPolicyLevel,
+) -> Option<&'static mut DenialEvent> {
+    let event = unsafe { &mut *EVENT_BUF.get_ptr_mut(0)? };
+
+    let pid_tgid = bpf_get_current_pid_tgid();
+    event.container_id = container_id;
+    event.hook = hook;
+    event.action = action;
+    event.decision = Decision::Deny;
+    event.policy_level = policy_level;
+    event.pid = pid_tgid as i32;
+    event.tgid = (pid_tgid >> 32) as i32;
+    event.uid = bpf_get_current_uid_gid() as u32;
+    event.comm = bpf_get_current_comm().unwrap_or_default();
+    event.device = Device::new(0, 0);
+    event.value = 0;
+    event.data[0] = 0;
+    event.address = SockAddr::none();
+
+    Some(event)
+}
+
+/// Sends the event to userspace.
+#[inline(always)]
+pub(crate) fn send_event<C: BpfContext>(ctx: &C, event: &DenialEvent) {
+    unsafe { EVENTS.output(ctx, event, 0) };
+}
+
+/// Sends the denial event which doesn't need any fields specific to the
+/// action.
+#[inline(always)]
+pub(crate) fn send_denial<C: BpfContext>(
+    ctx: &C,
+    hook: Hook,
+    action: Action,
+    container_id: ContainerID,
+    policy_level: ContainerPolicyLevel,
+) {
+    if let Some(event) = denial_event(hook, action, container_id, policy_level) {
+        send_event(ctx, event);
+    }
+}
+
+/// Sends the event about the containerized process with the given PID,
+/// which could not be registered.
+#[inline(always)]
+pub(crate) fn send_tracking_failure<C: BpfContext>(
+    ctx: &C,
+    pid: i32,
+    container_id: ContainerID,
+    killed: bool,
+) {
+    let policy_level = unsafe { CONTAINERS.get(&container_id) }
+        .map(|container| container.policy_level)
+        .unwrap_or(ContainerPolicyLevel::NotFound);
+    let event = TrackingFailureEvent {
+        container_id,
+        policy_level,
+        pid,
+        comm: bpf_get_current_comm().unwrap_or_default(),
+        killed,
+    };
+    unsafe { TRACKING_FAILURE_EVENTS.output(ctx, &event, 0) };
+}
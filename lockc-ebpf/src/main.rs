@@ -1,33 +1,107 @@
 #![no_std]
 #![no_main]
 
+use core::ptr::addr_of;
+
 use aya_bpf::{
     bindings::path,
     cty::{c_char, c_long},
-    helpers::{bpf_d_path, bpf_probe_read_kernel_str_bytes},
+    helpers::{
+        bpf_d_path, bpf_get_current_task_btf, bpf_probe_read_kernel,
+        bpf_probe_read_kernel_str_bytes,
+    },
     macros::lsm,
     programs::LsmContext,
     BpfContext,
 };
 use aya_log_ebpf::{debug, error, info};
 
-use lockc_common::{ContainerPolicyLevel, PATH_LEN};
+use lockc_common::{
+    Action, ContainerGroup, ContainerID, ContainerPolicyLevel, Device, Hook, InodeID, SockAddr,
+    AF_INET, AF_INET6, PATH_LEN,
+};
 
+mod events;
 mod maps;
 mod policy;
 mod proc;
+mod stats;
 #[allow(non_upper_case_globals)]
 #[allow(non_snake_case)]
 #[allow(non_camel_case_types)]
 #[allow(dead_code)]
 mod vmlinux;
 
-use maps::{CONTAINER_INITIAL_SETUID, MOUNT_TYPE_BUF, PATH_BUF};
-use policy::get_container_and_policy_level;
-use vmlinux::{cred, file, socket};
+use events::{denial_event, send_denial, send_event};
+use maps::{CONTAINERS, CONTAINER_GROUPS, MOUNT_TYPE_BUF, PATH_BUF, RUNTIME_SOCKETS};
+use policy::{decode_dev, device_allowed, get_container_and_policy_level};
+use stats::{count_decision, count_run};
+use vmlinux::{
+    cred, dentry, file, iattr, inode, kernel_cap_t, kgid_t, linux_binprm, msghdr, sockaddr, socket,
+    super_block, task_struct, umode_t, unix_sock,
+};
+
+/// Max number of supplementary groups checked by the `task_fix_setgroups`
+/// program. Processes trying to set more groups are denied.
+const NGROUPS_CHECK_LIMIT: i32 = 64;
 
-const AF_INET: u16 = 2;
-const AF_INET6: u16 = 10;
+const S_IFMT: umode_t = 0o170000;
+const S_IFBLK: umode_t = 0o060000;
+const S_IFCHR: umode_t = 0o020000;
+const S_IFDIR: umode_t = 0o040000;
+const S_ISUID: umode_t = 0o4000;
+const S_ISGID: umode_t = 0o2000;
+
+/// `ia_valid` flag of `struct iattr` indicating the change of mode.
+const ATTR_MODE: u32 = 1;
+
+/// Extended attribute holding file capabilities.
+const XATTR_NAME_CAPS: &str = "security.capability";
+
+/// Runs the policy check of the LSM program attached to the given hook. The
+/// container of the current process is looked up once and shared by the
+/// check and the decision counter.
+///
+/// What every program does in each policy level is described for users by
+/// `Hook::behavior` in lockc-common. Update it when changing the behavior of
+/// any program.
+#[inline(always)]
+fn run_program<F>(hook: Hook, ctx: LsmContext, check: F) -> i32
+where
+    F: FnOnce(LsmContext, Option<ContainerID>, ContainerPolicyLevel) -> Result<i32, i32>,
+{
+    count_run(hook);
+    let (container_id, policy_level) = match get_container_and_policy_level() {
+        Ok(res) => res,
+        Err(ret) => return ret,
+    };
+    let ret = match check(ctx, container_id, policy_level) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    };
+    if let Some(container_id) = container_id {
+        count_decision(hook, container_id, ret);
+    }
+    ret
+}
+
+/// Checks whether the given inode mode describes a character or block device.
+#[inline(always)]
+fn is_device(mode: umode_t) -> bool {
+    let file_type = mode & S_IFMT;
+    file_type == S_IFCHR || file_type == S_IFBLK
+}
+
+/// Checks whether the given mode sets setuid or setgid bits on a file.
+/// The setgid bit on directories only makes new files inherit the group, so
+/// it's not considered dangerous.
+#[inline(always)]
+fn is_setid(mode: umode_t, file_type: umode_t) -> bool {
+    if file_type == S_IFDIR {
+        return mode & S_ISUID != 0;
+    }
+    mode & (S_ISUID | S_ISGID) != 0
+}
 
 /// LSM program triggered by attempts to access the kernel logs. Behavior based
 /// on policy levels:
@@ -37,15 +111,14 @@ const AF_INET6: u16 = 10;
 /// * privileged: allow
 #[lsm(name = "syslog")]
 pub fn syslog(ctx: LsmContext) -> i32 {
-    match try_syslog(ctx) {
-        Ok(ret) => ret,
-        Err(ret) => ret,
-    }
+    run_program(Hook::Syslog, ctx, try_syslog)
 }
 
-fn try_syslog(ctx: LsmContext) -> Result<i32, i32> {
-    let (_, policy_level) = get_container_and_policy_level()?;
-
+fn try_syslog(
+    ctx: LsmContext,
+    container_id: Option<ContainerID>,
+    policy_level: ContainerPolicyLevel,
+) -> Result<i32, i32> {
     match policy_level {
         ContainerPolicyLevel::NotFound => {
             return Ok(0);
@@ -53,37 +126,40 @@ fn try_syslog(ctx: LsmContext) -> Result<i32, i32> {
         ContainerPolicyLevel::Lockc => {
             return Ok(0);
         }
-        ContainerPolicyLevel::Restricted => {
-            info!(&ctx, "syslog: deny accessing syslog");
-            return Err(-1);
-        }
-        ContainerPolicyLevel::Offline => {
-            info!(&ctx, "syslog: deny accessing syslog");
-            return Err(-1);
-        }
-        ContainerPolicyLevel::Baseline => {
-            info!(&ctx, "syslog: deny accessing syslog");
-            return Err(-1);
-        }
+        ContainerPolicyLevel::Restricted => {}
+        ContainerPolicyLevel::Offline => {}
+        ContainerPolicyLevel::Baseline => {}
         ContainerPolicyLevel::Privileged => {
             return Ok(0);
         }
     }
+
+    if let Some(container_id) = container_id {
+        send_denial(
+            &ctx,
+            Hook::Syslog,
+            Action::Syslog,
+            container_id,
+            policy_level,
+        );
+    }
+    info!(&ctx, "syslog: deny accessing syslog");
+
+    Err(-1)
 }
 
 /// LSM program triggered by any mount attempt. It denies bind mounts to
 /// restricted and baseline containers.
 #[lsm(name = "sb_mount")]
 pub fn sb_mount(ctx: LsmContext) -> i32 {
-    match try_sb_mount(ctx) {
-        Ok(ret) => ret,
-        Err(ret) => ret,
-    }
+    run_program(Hook::SbMount, ctx, try_sb_mount)
 }
 
-fn try_sb_mount(ctx: LsmContext) -> Result<i32, i32> {
-    let (container_id, policy_level) = get_container_and_policy_level()?;
-
+fn try_sb_mount(
+    ctx: LsmContext,
+    container_id: Option<ContainerID>,
+    policy_level: ContainerPolicyLevel,
+) -> Result<i32, i32> {
     match policy_level {
         ContainerPolicyLevel::NotFound => {
             return Ok(0);
@@ -114,10 +190,12 @@ fn try_sb_mount(ctx: LsmContext) -> Result<i32, i32> {
         return Ok(0);
     }
 
+    let buf = unsafe {
+        let buf_ptr = PATH_BUF.get_ptr_mut(0).ok_or(0)?;
+        &mut *buf_ptr
+    };
     let src_path = unsafe {
         let dev_name: *const c_char = ctx.arg(0);
-        let buf_ptr = PATH_BUF.get_ptr_mut(0).ok_or(0)?;
-        let buf = &mut *buf_ptr;
         core::str::from_utf8_unchecked(
             bpf_probe_read_kernel_str_bytes(dev_name as *const u8, &mut buf.path)
                 .map_err(|e| e as i32)?,
@@ -134,6 +212,11 @@ fn try_sb_mount(ctx: LsmContext) -> Result<i32, i32> {
     }
 
     let container_id = container_id.ok_or(-1)?;
+    if let Some(event) = denial_event(Hook::SbMount, Action::BindMount, container_id, policy_level)
+    {
+        event.data = buf.path;
+        send_event(&ctx, event);
+    }
     let container_id = unsafe { container_id.as_str() };
     error!(
         &ctx,
@@ -143,19 +226,106 @@ fn try_sb_mount(ctx: LsmContext) -> Result<i32, i32> {
     Err(-1)
 }
 
-/// LSM program triggered when user attempts to change the UID. It denies
-/// changing the UID to 0 (logging in as root) in restricted and baseline
-/// containers.
+/// LSM program triggered when user attempts to change the UID. In restricted
+/// containers, it denies changing the UID to 0 (logging in as root) and to
+/// any UID other than the one defined in the container config (`runAsUser`).
+/// Keeping the current UID is allowed, so containers running as root can
+/// start.
 #[lsm(name = "task_fix_setuid")]
 pub fn task_fix_setuid(ctx: LsmContext) -> i32 {
-    match { try_task_fix_setuid(ctx) } {
-        Ok(ret) => ret,
-        Err(ret) => ret,
+    run_program(Hook::TaskFixSetuid, ctx, try_task_fix_setuid)
+}
+
+fn try_task_fix_setuid(
+    ctx: LsmContext,
+    container_id: Option<ContainerID>,
+    policy_level: ContainerPolicyLevel,
+) -> Result<i32, i32> {
+    match policy_level {
+        ContainerPolicyLevel::NotFound => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Lockc => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Restricted => {}
+        ContainerPolicyLevel::Offline => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Baseline => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Privileged => {
+            return Ok(0);
+        }
+    }
+
+    let container_id = container_id.ok_or(-1)?;
+    let container = unsafe { CONTAINERS.get(&container_id) }.ok_or(-1)?;
+
+    let new: *const cred = unsafe { ctx.arg(0) };
+    let old: *const cred = unsafe { ctx.arg(1) };
+    let uids = unsafe {
+        [
+            ((*old).uid.val, (*new).uid.val),
+            ((*old).euid.val, (*new).euid.val),
+            ((*old).suid.val, (*new).suid.val),
+            ((*old).fsuid.val, (*new).fsuid.val),
+        ]
+    };
+
+    for (old_uid, uid) in uids {
+        if container.uid_range.allows_change(old_uid, uid) {
+            continue;
+        }
+        if uid == 0 {
+            send_denial(
+                &ctx,
+                Hook::TaskFixSetuid,
+                Action::SetuidRoot,
+                container_id,
+                policy_level,
+            );
+            let container_id = unsafe { container_id.as_str() };
+            error!(
+                &ctx,
+                "task_fix_setuid: {}: deny logging as root", container_id
+            );
+            return Err(-1);
+        }
+        if let Some(event) = denial_event(
+            Hook::TaskFixSetuid,
+            Action::Setuid,
+            container_id,
+            policy_level,
+        ) {
+            event.value = uid as u64;
+            send_event(&ctx, event);
+        }
+        let container_id = unsafe { container_id.as_str() };
+        error!(
+            &ctx,
+            "task_fix_setuid: {}: deny changing UID to {}", container_id, uid
+        );
+        return Err(-1);
     }
+
+    Ok(0)
 }
 
-fn try_task_fix_setuid(ctx: LsmContext) -> Result<i32, i32> {
-    let (container_id, policy_level) = get_container_and_policy_level()?;
+/// LSM program triggered when user attempts to change the GID. It denies
+/// changing the GID to any GID other than the one defined in the container
+/// config (`runAsGroup`) in restricted containers.
+#[lsm(name = "task_fix_setgid")]
+pub fn task_fix_setgid(ctx: LsmContext) -> i32 {
+    run_program(Hook::TaskFixSetgid, ctx, try_task_fix_setgid)
+}
+
+fn try_task_fix_setgid(
+    ctx: LsmContext,
+    container_id: Option<ContainerID>,
+    policy_level: ContainerPolicyLevel,
+) -> Result<i32, i32> {
     match policy_level {
         ContainerPolicyLevel::NotFound => {
             return Ok(0);
@@ -176,36 +346,216 @@ fn try_task_fix_setuid(ctx: LsmContext) -> Result<i32, i32> {
     }
 
     let container_id = container_id.ok_or(-1)?;
+    let container = unsafe { CONTAINERS.get(&container_id) }.ok_or(-1)?;
 
     let new: *const cred = unsafe { ctx.arg(0) };
-    let uid_new = unsafe { (*new).uid.val };
+    let old: *const cred = unsafe { ctx.arg(1) };
+    let gids = unsafe {
+        [
+            ((*old).gid.val, (*new).gid.val),
+            ((*old).egid.val, (*new).egid.val),
+            ((*old).sgid.val, (*new).sgid.val),
+            ((*old).fsgid.val, (*new).fsgid.val),
+        ]
+    };
 
-    if let Some(initial_setuid) = unsafe { CONTAINER_INITIAL_SETUID.get(&container_id) } {
-        if *initial_setuid {
-            if uid_new == 0 {
-                let container_id = unsafe { container_id.as_str() };
-                error!(
-                    &ctx,
-                    "task_fix_setuid: {}: deny logging as root", container_id
-                );
-                return Err(-1);
+    for (old_gid, gid) in gids {
+        if !container.gid_range.allows_change(old_gid, gid) {
+            if let Some(event) = denial_event(
+                Hook::TaskFixSetgid,
+                Action::Setgid,
+                container_id,
+                policy_level,
+            ) {
+                event.value = gid as u64;
+                send_event(&ctx, event);
             }
+            let container_id = unsafe { container_id.as_str() };
+            error!(
+                &ctx,
+                "task_fix_setgid: {}: deny changing GID to {}", container_id, gid
+            );
+            return Err(-1);
+        }
+    }
+
+    Ok(0)
+}
+
+/// LSM program triggered when user attempts to change supplementary groups.
+/// It denies setting groups other than the ones defined in the container
+/// config (`supplementalGroups`, `fsGroup`) in restricted containers.
+#[lsm(name = "task_fix_setgroups")]
+pub fn task_fix_setgroups(ctx: LsmContext) -> i32 {
+    run_program(Hook::TaskFixSetgroups, ctx, try_task_fix_setgroups)
+}
+
+fn try_task_fix_setgroups(
+    ctx: LsmContext,
+    container_id: Option<ContainerID>,
+    policy_level: ContainerPolicyLevel,
+) -> Result<i32, i32> {
+    match policy_level {
+        ContainerPolicyLevel::NotFound => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Lockc => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Restricted => {}
+        ContainerPolicyLevel::Offline => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Baseline => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Privileged => {
+            return Ok(0);
+        }
+    }
+
+    let container_id = container_id.ok_or(-1)?;
+    let container = unsafe { CONTAINERS.get(&container_id) }.ok_or(-1)?;
+
+    let new: *const cred = unsafe { ctx.arg(0) };
+    let group_info = unsafe { (*new).group_info };
+    let ngroups = unsafe { (*group_info).ngroups };
+    if ngroups > NGROUPS_CHECK_LIMIT {
+        if let Some(event) = denial_event(
+            Hook::TaskFixSetgroups,
+            Action::SetgroupsLimit,
+            container_id,
+            policy_level,
+        ) {
+            event.value = ngroups as u64;
+            send_event(&ctx, event);
+        }
+        let container_id = unsafe { container_id.as_str() };
+        error!(
+            &ctx,
+            "task_fix_setgroups: {}: deny setting {} groups", container_id, ngroups
+        );
+        return Err(-1);
+    }
+
+    let gids = unsafe { (*group_info).gid.as_ptr() };
+    for i in 0..NGROUPS_CHECK_LIMIT {
+        if i >= ngroups {
+            break;
+        }
+        let gid: kgid_t =
+            unsafe { bpf_probe_read_kernel(gids.offset(i as isize)).map_err(|e| e as i32)? };
+        let gid = gid.val;
+
+        if container.gid_range.contains(gid) {
+            continue;
+        }
+        let group = ContainerGroup { container_id, gid };
+        if unsafe { CONTAINER_GROUPS.get(&group) }.is_some() {
+            continue;
         }
-    } else {
-        debug!(
+
+        if let Some(event) = denial_event(
+            Hook::TaskFixSetgroups,
+            Action::Setgroups,
+            container_id,
+            policy_level,
+        ) {
+            event.value = gid as u64;
+            send_event(&ctx, event);
+        }
+        let container_id = unsafe { container_id.as_str() };
+        error!(
             &ctx,
-            "task_fix_setuid: an initial setuid, policy not enforced"
+            "task_fix_setgroups: {}: deny setting group {}", container_id, gid
         );
-        unsafe {
-            CONTAINER_INITIAL_SETUID
-                .insert(&container_id, &true, 0)
-                .map_err(|e| e as i32)?
-        };
+        return Err(-1);
     }
 
     Ok(0)
 }
 
+/// Checks whether the `new` capability set contains any capability which is
+/// not present in the `old` one.
+#[inline(always)]
+fn caps_raised(new: &kernel_cap_t, old: &kernel_cap_t) -> bool {
+    (new.cap[0] & !old.cap[0]) != 0 || (new.cap[1] & !old.cap[1]) != 0
+}
+
+/// LSM program triggered when credentials for a new program are computed
+/// during exec. It denies executing setuid, setgid and file capability
+/// binaries which would raise the effective UID, GID or capabilities in
+/// restricted containers (`allowPrivilegeEscalation: false`), regardless of
+/// whether the runtime set no_new_privs.
+///
+/// At this point, the new credentials are already computed by the
+/// capabilities LSM, but not committed yet, so the exec can still be denied.
+#[lsm(name = "bprm_creds_from_file")]
+pub fn bprm_creds_from_file(ctx: LsmContext) -> i32 {
+    run_program(Hook::BprmCredsFromFile, ctx, try_bprm_creds_from_file)
+}
+
+fn try_bprm_creds_from_file(
+    ctx: LsmContext,
+    container_id: Option<ContainerID>,
+    policy_level: ContainerPolicyLevel,
+) -> Result<i32, i32> {
+    match policy_level {
+        ContainerPolicyLevel::NotFound => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Lockc => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Restricted => {}
+        ContainerPolicyLevel::Offline => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Baseline => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Privileged => {
+            return Ok(0);
+        }
+    }
+
+    let bprm: *const linux_binprm = unsafe { ctx.arg(0) };
+    let new = unsafe { &*(*bprm).cred };
+    let old = unsafe {
+        let task = bpf_get_current_task_btf() as *const task_struct;
+        &*(*task).cred
+    };
+
+    if new.euid.val == old.euid.val
+        && new.egid.val == old.egid.val
+        && !caps_raised(&new.cap_permitted, &old.cap_permitted)
+        && !caps_raised(&new.cap_effective, &old.cap_effective)
+    {
+        return Ok(0);
+    }
+
+    let container_id = container_id.ok_or(-1)?;
+    if let Some(event) = denial_event(
+        Hook::BprmCredsFromFile,
+        Action::PrivilegeEscalation,
+        container_id,
+        policy_level,
+    ) {
+        event.value = new.euid.val as u64;
+        send_event(&ctx, event);
+    }
+    let container_id = unsafe { container_id.as_str() };
+    error!(
+        &ctx,
+        "bprm_creds_from_file: {}: deny gaining privileges on exec (euid: {} -> {})",
+        container_id,
+        old.euid.val,
+        new.euid.val
+    );
+
+    Err(-1)
+}
+
 // TODO(vadorovsky): Remove this once the following PR is merged:
 // https://github.com/aya-rs/aya/pull/257
 #[inline(always)]
@@ -219,18 +569,19 @@ pub fn my_bpf_d_path(path: *mut path, buf: &mut [u8]) -> Result<usize, c_long> {
 }
 
 /// LSM program triggered by opening a file. It denies access to directories
-/// which might leak information about host (/sys/fs, /proc/acpi etc.) to
-/// restricted and baseline containers.
+/// which might leak information about host (/sys/fs, /proc/acpi etc.) and to
+/// devices which are not allowed for the policy level to restricted and
+/// baseline containers.
 #[lsm(name = "file_open")]
 pub fn file_open(ctx: LsmContext) -> i32 {
-    match { try_file_open(ctx) } {
-        Ok(ret) => ret,
-        Err(ret) => ret,
-    }
+    run_program(Hook::FileOpen, ctx, try_file_open)
 }
 
-fn try_file_open(ctx: LsmContext) -> Result<i32, i32> {
-    let (container_id, policy_level) = get_container_and_policy_level()?;
+fn try_file_open(
+    ctx: LsmContext,
+    container_id: Option<ContainerID>,
+    policy_level: ContainerPolicyLevel,
+) -> Result<i32, i32> {
     match policy_level {
         ContainerPolicyLevel::NotFound => {
             return Ok(0);
@@ -246,13 +597,65 @@ fn try_file_open(ctx: LsmContext) -> Result<i32, i32> {
         }
     }
 
+    let container_id = container_id.ok_or(-1)?;
+
+    let f: *const file = unsafe { ctx.arg(0) };
+
+    // Device nodes can be created anywhere, so they are checked by their
+    // major and minor numbers instead of paths.
+    let inode = unsafe { (*f).f_inode };
+    if is_device(unsafe { (*inode).i_mode }) {
+        let device = decode_dev(unsafe { (*inode).i_rdev });
+        if !device_allowed(policy_level, &device) {
+            if let Some(event) = denial_event(
+                Hook::FileOpen,
+                Action::OpenDevice,
+                container_id,
+                policy_level,
+            ) {
+                event.device = device;
+                send_event(&ctx, event);
+            }
+            let container_id = unsafe { container_id.as_str() };
+            error!(
+                &ctx,
+                "file_open: {}: deny opening device {}:{}",
+                container_id,
+                device.major,
+                device.minor
+            );
+            return Err(-1);
+        }
+    }
+
+    // Container runtime sockets are checked by their inodes, because they
+    // might be mounted under any path.
+    let inode_id = InodeID {
+        device: decode_dev(unsafe { (*(*inode).i_sb).s_dev }),
+        ino: unsafe { (*inode).i_ino },
+    };
+    if unsafe { RUNTIME_SOCKETS.get(&inode_id) }.is_some() {
+        send_denial(
+            &ctx,
+            Hook::FileOpen,
+            Action::OpenRuntimeSocket,
+            container_id,
+            policy_level,
+        );
+        let container_id = unsafe { container_id.as_str() };
+        error!(
+            &ctx,
+            "file_open: {}: deny opening container runtime socket", container_id
+        );
+        return Err(-1);
+    }
+
     let buf = unsafe {
         let buf_ptr = PATH_BUF.get_ptr_mut(0).ok_or(0)?;
         &mut *buf_ptr
     };
 
     let p = unsafe {
-        let f: *const file = ctx.arg(0);
         let p = &(*f).f_path as *const _ as *mut path;
         let len = my_bpf_d_path(p, &mut buf.path).map_err(|_| 0)?;
         if len >= PATH_LEN {
@@ -261,9 +664,6 @@ fn try_file_open(ctx: LsmContext) -> Result<i32, i32> {
         core::str::from_utf8_unchecked(&buf.path[..len])
     };
 
-    let container_id = container_id.ok_or(-1)?;
-    let container_id = unsafe { container_id.as_str() };
-
     if p.starts_with("/sys/devices")
         || p.starts_with("/sys/fs/cgroup")
         || p.starts_with("/sys/kernel/mm")
@@ -275,6 +675,13 @@ fn try_file_open(ctx: LsmContext) -> Result<i32, i32> {
         || p.starts_with("/sys/")
         || p.starts_with("/var/run/secrets/kubernetes.io")
     {
+        if let Some(event) =
+            denial_event(Hook::FileOpen, Action::OpenPath, container_id, policy_level)
+        {
+            event.data = buf.path;
+            send_event(&ctx, event);
+        }
+        let container_id = unsafe { container_id.as_str() };
         error!(&ctx, "file_open: {}: deny opening {}", container_id, p);
         return Err(-1);
     }
@@ -282,16 +689,404 @@ fn try_file_open(ctx: LsmContext) -> Result<i32, i32> {
     Ok(0)
 }
 
+/// LSM program triggered by creating a filesystem node. It denies creating
+/// device nodes which are not allowed for the policy level and files with
+/// setuid/setgid bits to restricted and baseline containers.
+#[lsm(name = "path_mknod")]
+pub fn path_mknod(ctx: LsmContext) -> i32 {
+    run_program(Hook::PathMknod, ctx, try_path_mknod)
+}
+
+fn try_path_mknod(
+    ctx: LsmContext,
+    container_id: Option<ContainerID>,
+    policy_level: ContainerPolicyLevel,
+) -> Result<i32, i32> {
+    match policy_level {
+        ContainerPolicyLevel::NotFound => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Lockc => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Restricted => {}
+        ContainerPolicyLevel::Offline => {}
+        ContainerPolicyLevel::Baseline => {}
+        ContainerPolicyLevel::Privileged => {
+            return Ok(0);
+        }
+    }
+
+    let mode: umode_t = unsafe { ctx.arg(2) };
+    if is_setid(mode, mode & S_IFMT) {
+        let container_id = container_id.ok_or(-1)?;
+        send_denial(
+            &ctx,
+            Hook::PathMknod,
+            Action::CreateSetid,
+            container_id,
+            policy_level,
+        );
+        let container_id = unsafe { container_id.as_str() };
+        error!(
+            &ctx,
+            "path_mknod: {}: deny creating setuid/setgid file", container_id
+        );
+        return Err(-1);
+    }
+    if !is_device(mode) {
+        return Ok(0);
+    }
+
+    // Unlike `i_rdev`, the device number passed to mknod(2) is in the
+    // userspace layout.
+    let dev: u32 = unsafe { ctx.arg(3) };
+    let device = Device::new_decode_dev(dev);
+    if device_allowed(policy_level, &device) {
+        return Ok(0);
+    }
+
+    let container_id = container_id.ok_or(-1)?;
+    if let Some(event) = denial_event(
+        Hook::PathMknod,
+        Action::CreateDevice,
+        container_id,
+        policy_level,
+    ) {
+        event.device = device;
+        send_event(&ctx, event);
+    }
+    let container_id = unsafe { container_id.as_str() };
+    error!(
+        &ctx,
+        "path_mknod: {}: deny creating device {}:{}", container_id, device.major, device.minor
+    );
+
+    Err(-1)
+}
+
+/// LSM program triggered by changing the mode of a file. It denies setting
+/// setuid/setgid bits to restricted and baseline containers.
+#[lsm(name = "path_chmod")]
+pub fn path_chmod(ctx: LsmContext) -> i32 {
+    run_program(Hook::PathChmod, ctx, try_path_chmod)
+}
+
+fn try_path_chmod(
+    ctx: LsmContext,
+    container_id: Option<ContainerID>,
+    policy_level: ContainerPolicyLevel,
+) -> Result<i32, i32> {
+    match policy_level {
+        ContainerPolicyLevel::NotFound => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Lockc => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Restricted => {}
+        ContainerPolicyLevel::Offline => {}
+        ContainerPolicyLevel::Baseline => {}
+        ContainerPolicyLevel::Privileged => {
+            return Ok(0);
+        }
+    }
+
+    let p: *const vmlinux::path = unsafe { ctx.arg(0) };
+    let mode: umode_t = unsafe { ctx.arg(1) };
+    let file_type = unsafe { (*(*(*p).dentry).d_inode).i_mode } & S_IFMT;
+    if !is_setid(mode, file_type) {
+        return Ok(0);
+    }
+
+    let container_id = container_id.ok_or(-1)?;
+    send_denial(
+        &ctx,
+        Hook::PathChmod,
+        Action::SetSetid,
+        container_id,
+        policy_level,
+    );
+    let container_id = unsafe { container_id.as_str() };
+    error!(
+        &ctx,
+        "path_chmod: {}: deny setting setuid/setgid bits", container_id
+    );
+
+    Err(-1)
+}
+
+/// LSM program triggered by changing attributes of an inode. It denies
+/// setting setuid/setgid bits to restricted and baseline containers. Unlike
+/// `path_chmod`, it covers all the ways of changing the mode (i.e. with
+/// `fchmod`).
+#[lsm(name = "inode_setattr")]
+pub fn inode_setattr(ctx: LsmContext) -> i32 {
+    run_program(Hook::InodeSetattr, ctx, try_inode_setattr)
+}
+
+fn try_inode_setattr(
+    ctx: LsmContext,
+    container_id: Option<ContainerID>,
+    policy_level: ContainerPolicyLevel,
+) -> Result<i32, i32> {
+    match policy_level {
+        ContainerPolicyLevel::NotFound => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Lockc => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Restricted => {}
+        ContainerPolicyLevel::Offline => {}
+        ContainerPolicyLevel::Baseline => {}
+        ContainerPolicyLevel::Privileged => {
+            return Ok(0);
+        }
+    }
+
+    let dentry: *const dentry = unsafe { ctx.arg(0) };
+    let attr: *const iattr = unsafe { ctx.arg(1) };
+    if unsafe { (*attr).ia_valid } & ATTR_MODE == 0 {
+        return Ok(0);
+    }
+    let mode = unsafe { (*attr).ia_mode };
+    let file_type = unsafe { (*(*dentry).d_inode).i_mode } & S_IFMT;
+    if !is_setid(mode, file_type) {
+        return Ok(0);
+    }
+
+    let container_id = container_id.ok_or(-1)?;
+    send_denial(
+        &ctx,
+        Hook::InodeSetattr,
+        Action::SetSetid,
+        container_id,
+        policy_level,
+    );
+    let container_id = unsafe { container_id.as_str() };
+    error!(
+        &ctx,
+        "inode_setattr: {}: deny setting setuid/setgid bits", container_id
+    );
+
+    Err(-1)
+}
+
+/// LSM program triggered by setting an extended attribute. It denies writing
+/// file capabilities (`security.capability`) to restricted and baseline
+/// containers.
+#[lsm(name = "inode_setxattr")]
+pub fn inode_setxattr(ctx: LsmContext) -> i32 {
+    run_program(Hook::InodeSetxattr, ctx, try_inode_setxattr)
+}
+
+fn try_inode_setxattr(
+    ctx: LsmContext,
+    container_id: Option<ContainerID>,
+    policy_level: ContainerPolicyLevel,
+) -> Result<i32, i32> {
+    match policy_level {
+        ContainerPolicyLevel::NotFound => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Lockc => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Restricted => {}
+        ContainerPolicyLevel::Offline => {}
+        ContainerPolicyLevel::Baseline => {}
+        ContainerPolicyLevel::Privileged => {
+            return Ok(0);
+        }
+    }
+
+    let name = unsafe {
+        let name: *const c_char = ctx.arg(2);
+        let buf_ptr = PATH_BUF.get_ptr_mut(0).ok_or(0)?;
+        let buf = &mut *buf_ptr;
+        core::str::from_utf8_unchecked(
+            bpf_probe_read_kernel_str_bytes(name as *const u8, &mut buf.path)
+                .map_err(|e| e as i32)?,
+        )
+    };
+    if name != XATTR_NAME_CAPS {
+        return Ok(0);
+    }
+
+    let container_id = container_id.ok_or(-1)?;
+    send_denial(
+        &ctx,
+        Hook::InodeSetxattr,
+        Action::SetFileCaps,
+        container_id,
+        policy_level,
+    );
+    let container_id = unsafe { container_id.as_str() };
+    error!(
+        &ctx,
+        "inode_setxattr: {}: deny setting file capabilities", container_id
+    );
+
+    Err(-1)
+}
+
+/// LSM program triggered by connecting to a Unix stream socket. It denies
+/// connecting to container runtime sockets (containerd, docker, cri-o, k3s)
+/// to all containers except privileged ones, regardless of the path under
+/// which the socket was mounted into the container.
+#[lsm(name = "unix_stream_connect")]
+pub fn unix_stream_connect(ctx: LsmContext) -> i32 {
+    run_program(Hook::UnixStreamConnect, ctx, try_unix_stream_connect)
+}
+
+fn try_unix_stream_connect(
+    ctx: LsmContext,
+    container_id: Option<ContainerID>,
+    policy_level: ContainerPolicyLevel,
+) -> Result<i32, i32> {
+    match policy_level {
+        ContainerPolicyLevel::NotFound => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Lockc => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Restricted => {}
+        ContainerPolicyLevel::Offline => {}
+        ContainerPolicyLevel::Baseline => {}
+        ContainerPolicyLevel::Privileged => {
+            return Ok(0);
+        }
+    }
+
+    // `other` is the listening socket, embedded in `struct unix_sock`
+    // together with the path of the socket file.
+    let other: *const unix_sock = unsafe { ctx.arg(1) };
+    let inode_id = unsafe {
+        let dentry: *mut dentry =
+            bpf_probe_read_kernel(addr_of!((*other).path.dentry)).map_err(|e| e as i32)?;
+        if dentry.is_null() {
+            // Abstract or unbound socket.
+            return Ok(0);
+        }
+        let inode: *mut inode =
+            bpf_probe_read_kernel(addr_of!((*dentry).d_inode)).map_err(|e| e as i32)?;
+        let sb: *mut super_block =
+            bpf_probe_read_kernel(addr_of!((*inode).i_sb)).map_err(|e| e as i32)?;
+        InodeID {
+            device: decode_dev(bpf_probe_read_kernel(addr_of!((*sb).s_dev)).map_err(|e| e as i32)?),
+            ino: bpf_probe_read_kernel(addr_of!((*inode).i_ino)).map_err(|e| e as i32)?,
+        }
+    };
+
+    if unsafe { RUNTIME_SOCKETS.get(&inode_id) }.is_none() {
+        return Ok(0);
+    }
+
+    let container_id = container_id.ok_or(-1)?;
+    send_denial(
+        &ctx,
+        Hook::UnixStreamConnect,
+        Action::ConnectRuntimeSocket,
+        container_id,
+        policy_level,
+    );
+    let container_id = unsafe { container_id.as_str() };
+    error!(
+        &ctx,
+        "unix_stream_connect: {}: deny connecting to container runtime socket", container_id
+    );
+
+    Err(-1)
+}
+
+/// `struct sockaddr_in6`, which is missing in the generated bindings.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SockaddrIn6 {
+    sin6_family: u16,
+    sin6_port: u16,
+    sin6_flowinfo: u32,
+    sin6_addr: [u8; 16],
+    sin6_scope_id: u32,
+}
+
+/// Returns the destination address of the message, which is set when
+/// sending over a socket which is not connected.
+///
+/// # Safety
+///
+/// The message pointer has to be a valid BTF pointer.
+#[inline(always)]
+unsafe fn message_address(msg: *const msghdr) -> Option<SockAddr> {
+    let name = (*msg).msg_name as *const sockaddr;
+    if name.is_null() {
+        return None;
+    }
+    match bpf_probe_read_kernel(addr_of!((*name).sa_family)).ok()? {
+        AF_INET => {
+            let data = bpf_probe_read_kernel(name).ok()?.sa_data;
+            Some(SockAddr::ipv4(
+                [data[2] as u8, data[3] as u8, data[4] as u8, data[5] as u8],
+                u16::from_be_bytes([data[0] as u8, data[1] as u8]),
+            ))
+        }
+        AF_INET6 => {
+            let addr = bpf_probe_read_kernel(name as *const SockaddrIn6).ok()?;
+            Some(SockAddr::ipv6(addr.sin6_addr, u16::from_be(addr.sin6_port)))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the address of the peer of the socket, if it's connected.
+///
+/// # Safety
+///
+/// The socket pointer has to be a valid BTF pointer.
+#[inline(always)]
+unsafe fn peer_address(sock: *const socket) -> SockAddr {
+    let sk = &(*(*sock).sk).__sk_common;
+    let port = u16::from_be(sk.__bindgen_anon_3.__bindgen_anon_1.skc_dport);
+    if port == 0 {
+        return SockAddr::none();
+    }
+    match sk.skc_family {
+        AF_INET => SockAddr::ipv4(
+            sk.__bindgen_anon_1.__bindgen_anon_1.skc_daddr.to_ne_bytes(),
+            port,
+        ),
+        AF_INET6 => SockAddr::ipv6(sk.skc_v6_daddr.in6_u.u6_addr8, port),
+        _ => SockAddr::none(),
+    }
+}
+
+/// Sends the denial of network access with the address of the other side.
+#[inline(always)]
+fn send_network_denial(
+    ctx: &LsmContext,
+    hook: Hook,
+    container_id: ContainerID,
+    policy_level: ContainerPolicyLevel,
+    address: SockAddr,
+) {
+    if let Some(event) = denial_event(hook, Action::Network, container_id, policy_level) {
+        event.address = address;
+        send_event(ctx, event);
+    }
+}
+
 #[lsm(name = "socket_sendmsg")]
 pub fn socket_sendmsg(ctx: LsmContext) -> i32 {
-    match { try_socket_sendmsg(ctx) } {
-        Ok(ret) => ret,
-        Err(ret) => ret,
-    }
+    run_program(Hook::SocketSendmsg, ctx, try_socket_sendmsg)
 }
 
-fn try_socket_sendmsg(ctx: LsmContext) -> Result<i32, i32> {
-    let (container_id, policy_level) = get_container_and_policy_level()?;
+fn try_socket_sendmsg(
+    ctx: LsmContext,
+    container_id: Option<ContainerID>,
+    policy_level: ContainerPolicyLevel,
+) -> Result<i32, i32> {
     match policy_level {
         ContainerPolicyLevel::NotFound => {
             return Ok(0);
@@ -301,6 +1096,18 @@ fn try_socket_sendmsg(ctx: LsmContext) -> Result<i32, i32> {
         }
         ContainerPolicyLevel::Restricted => {}
         ContainerPolicyLevel::Offline => {
+            if let Some(container_id) = container_id {
+                let sock: *const socket = unsafe { ctx.arg(0) };
+                let msg: *const msghdr = unsafe { ctx.arg(1) };
+                let address = unsafe { message_address(msg).unwrap_or_else(|| peer_address(sock)) };
+                send_network_denial(
+                    &ctx,
+                    Hook::SocketSendmsg,
+                    container_id,
+                    policy_level,
+                    address,
+                );
+            }
             return Err(-1);
         }
         ContainerPolicyLevel::Baseline => {}
@@ -324,14 +1131,14 @@ fn try_socket_sendmsg(ctx: LsmContext) -> Result<i32, i32> {
 
 #[lsm(name = "socket_recvmsg")]
 pub fn socket_recvmsg(ctx: LsmContext) -> i32 {
-    match { try_socket_recvmsg(ctx) } {
-        Ok(ret) => ret,
-        Err(ret) => ret,
-    }
+    run_program(Hook::SocketRecvmsg, ctx, try_socket_recvmsg)
 }
 
-fn try_socket_recvmsg(ctx: LsmContext) -> Result<i32, i32> {
-    let (container_id, policy_level) = get_container_and_policy_level()?;
+fn try_socket_recvmsg(
+    ctx: LsmContext,
+    container_id: Option<ContainerID>,
+    policy_level: ContainerPolicyLevel,
+) -> Result<i32, i32> {
     match policy_level {
         ContainerPolicyLevel::NotFound => {
             return Ok(0);
@@ -341,6 +1148,17 @@ fn try_socket_recvmsg(ctx: LsmContext) -> Result<i32, i32> {
         }
         ContainerPolicyLevel::Restricted => {}
         ContainerPolicyLevel::Offline => {
+            if let Some(container_id) = container_id {
+                let sock: *const socket = unsafe { ctx.arg(0) };
+                let address = unsafe { peer_address(sock) };
+                send_network_denial(
+                    &ctx,
+                    Hook::SocketRecvmsg,
+                    container_id,
+                    policy_level,
+                    address,
+                );
+            }
             return Err(-1);
         }
         ContainerPolicyLevel::Baseline => {}
@@ -1,9 +1,13 @@
 use aya_bpf::{
     macros::map,
-    maps::{HashMap, PerCpuArray},
+    maps::{HashMap, PerCpuArray, PerCpuHashMap, PerfEventArray},
 };
 
-use lockc_common::{Container, ContainerID, MountType, Path, Process, PID_MAX_LIMIT};
+use lockc_common::{
+    Container, ContainerGroup, ContainerID, DenialEvent, Device, HookStatsKey, InodeID, MountType,
+    Path, Process, TrackingFailureEvent, ALLOWED_DEVICES_LIMIT, HOOKS_COUNT, PID_MAX_LIMIT,
+    RUNTIME_SOCKETS_LIMIT,
+};
 
 /// BPF map containing the info about a policy which should be enforced on the
 /// given container.
@@ -11,18 +15,93 @@ use lockc_common::{Container, ContainerID, MountType, Path, Process, PID_MAX_LIM
 pub(crate) static mut CONTAINERS: HashMap<ContainerID, Container> =
     HashMap::pinned(PID_MAX_LIMIT, 0);
 
-/// BPF map which maps the PID to a container it belongs to. The value of this
+/// BPF map which maps the PID (TGID, in kernel terms, shared by all threads
+/// of the process) to a container it belongs to. The value of this
 /// map, which represents the container, is a key of `containers` BPF map, so
 /// it can be used immediately for lookups in `containers` map.
+///
+/// It's a hash map limited to `PID_MAX_LIMIT` entries rather than task local
+/// storage, because the kernel accepts task storage maps only with BTF
+/// definitions, which `#[map]` doesn't emit. The cgroup-based process
+/// tracking is the way to avoid the size limit.
 #[map]
 pub(crate) static mut PROCESSES: HashMap<i32, Process> = HashMap::pinned(PID_MAX_LIMIT, 0);
 
+/// BPF map containing containerized processes which could not be registered
+/// in `PROCESSES` (i.e. because it was full). Used only in the fail-closed
+/// mode, where such processes are killed instead of escaping the policy.
+/// Sized by userspace like `PROCESSES`, it never evicts entries - a process
+/// which doesn't fit is killed right away.
+#[map]
+pub(crate) static mut UNTRACKED_PROCESSES: HashMap<i32, Process> =
+    HashMap::pinned(PID_MAX_LIMIT, 0);
+
+/// BPF map which maps the cgroup ID to a container it belongs to. Used
+/// instead of `PROCESSES` when containers are identified by cgroups.
+#[map]
+pub(crate) static mut CGROUPS: HashMap<u64, ContainerID> = HashMap::pinned(PID_MAX_LIMIT, 0);
+
+/// BPF map containing supplementary groups (taken from `additionalGids` in
+/// the OCI config) which processes in the given container are allowed to
+/// have.
+#[map]
+pub(crate) static mut CONTAINER_GROUPS: HashMap<ContainerGroup, u8> =
+    HashMap::pinned(PID_MAX_LIMIT, 0);
+
+/// BPF map containing character and block devices which restricted
+/// containers are allowed to open and create.
+#[map]
+pub(crate) static mut ALLOWED_DEVICES_RESTRICTED: HashMap<Device, u8> =
+    HashMap::with_max_entries(ALLOWED_DEVICES_LIMIT, 0);
+
+/// BPF map containing character and block devices which baseline containers
+/// are allowed to open and create.
+#[map]
+pub(crate) static mut ALLOWED_DEVICES_BASELINE: HashMap<Device, u8> =
+    HashMap::with_max_entries(ALLOWED_DEVICES_LIMIT, 0);
+
+/// BPF map containing inodes of container runtime sockets (and directories
+/// containing them) which containers are not allowed to access.
 #[map]
-pub(crate) static mut CONTAINER_INITIAL_SETUID: HashMap<ContainerID, bool> =
-    HashMap::with_max_entries(PID_MAX_LIMIT, 0);
+pub(crate) static mut RUNTIME_SOCKETS: HashMap<InodeID, u8> =
+    HashMap::with_max_entries(RUNTIME_SOCKETS_LIMIT, 0);
 
 #[map]
 pub(crate) static mut MOUNT_TYPE_BUF: PerCpuArray<MountType> = PerCpuArray::with_max_entries(1, 0);
 
 #[map]
 pub(crate) static mut PATH_BUF: PerCpuArray<Path> = PerCpuArray::with_max_entries(1, 0);
+
+/// BPF map used to send denial events to userspace.
+///
+/// It's a perf event array rather than a ring buffer, because aya 0.11, used
+/// by the daemon, can't read ring buffers. Newer aya versions can, but
+/// change the API of every map the daemon uses.
+#[map]
+pub(crate) static mut EVENTS: PerfEventArray<DenialEvent> = PerfEventArray::new(0);
+
+/// Buffer for the denial event, which is too big to fit on the stack
+/// together with the rest of the program's data.
+#[map]
+pub(crate) static mut EVENT_BUF: PerCpuArray<DenialEvent> = PerCpuArray::with_max_entries(1, 0);
+
+/// BPF map counting runs of LSM programs, indexed by the hook.
+#[map]
+pub(crate) static mut PROGRAM_RUNS: PerCpuArray<u64> =
+    PerCpuArray::with_max_entries(HOOKS_COUNT as u32, 0);
+
+/// BPF map used to send events about containerized processes which could
+/// not be registered in `PROCESSES` to userspace.
+#[map]
+pub(crate) static mut TRACKING_FAILURE_EVENTS: PerfEventArray<TrackingFailureEvent> =
+    PerfEventArray::new(0);
+
+/// BPF map counting containerized processes which could not be registered
+/// in `PROCESSES`, including children of such processes.
+#[map]
+pub(crate) static mut TRACKING_FAILURES: PerCpuArray<u64> = PerCpuArray::with_max_entries(1, 0);
+
+/// BPF map counting decisions made by LSM programs for each container.
+#[map]
+pub(crate) static mut HOOK_STATS: PerCpuHashMap<HookStatsKey, u64> =
+    PerCpuHashMap::pinned(PID_MAX_LIMIT, 0);
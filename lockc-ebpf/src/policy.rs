@@ -1,8 +1,151 @@
-use aya_bpf::helpers::bpf_get_current_pid_tgid;
+use aya_bpf::helpers::{
+    bpf_get_current_ancestor_cgroup_id, bpf_get_current_cgroup_id, bpf_get_current_pid_tgid,
+    bpf_get_current_task_btf, bpf_send_signal,
+};
 
-use lockc_common::{ContainerID, ContainerPolicyLevel};
+use lockc_common::{
+    ContainerID, ContainerPolicyLevel, Device, Process, ProcessTracking, DEVICE_MINOR_ANY,
+};
 
-use crate::maps::*;
+use crate::{maps::*, vmlinux::task_struct};
+
+/// Method of finding containers of processes, set by userspace when loading
+/// the programs.
+#[no_mangle]
+static PROCESS_TRACKING: u8 = ProcessTracking::Pid as u8;
+
+/// Whether containerized processes which could not be registered should be
+/// killed, set by userspace when loading the programs.
+#[no_mangle]
+static FAIL_CLOSED: u8 = 0;
+
+const SIGKILL: u32 = 9;
+
+/// Number of clock ticks per second (`USER_HZ`), which is the unit of process
+/// start time in procfs.
+const USER_HZ: u64 = 100;
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// Max depth of cgroup hierarchy checked when looking for a container
+/// cgroup among the ancestors of the current cgroup.
+const CGROUP_LEVEL_LIMIT: i32 = 16;
+
+/// Number of bits used for the minor number in the kernel's internal `dev_t`
+/// representation.
+const MINORBITS: u32 = 20;
+const MINORMASK: u32 = (1 << MINORBITS) - 1;
+
+/// Splits the kernel's internal `dev_t` (i.e. `i_rdev` or `s_dev`) into a
+/// major and minor number. Device numbers coming from userspace (i.e. the
+/// mknod(2) argument) have to be decoded with [`Device::new_decode_dev`].
+#[inline(always)]
+pub(crate) fn decode_dev(dev: u32) -> Device {
+    Device::new(dev >> MINORBITS, dev & MINORMASK)
+}
+
+/// Checks whether containers are identified by cgroups instead of PIDs.
+#[inline(always)]
+pub(crate) fn cgroup_tracking() -> bool {
+    unsafe { core::ptr::read_volatile(&PROCESS_TRACKING) == ProcessTracking::Cgroup as u8 }
+}
+
+/// Checks whether the fail-closed mode is enabled.
+#[inline(always)]
+pub(crate) fn fail_closed() -> bool {
+    unsafe { core::ptr::read_volatile(&FAIL_CLOSED) != 0 }
+}
+
+/// Kills the current process. `bpf_send_signal` can't signal other tasks.
+#[inline(always)]
+pub(crate) fn kill_current() {
+    unsafe { bpf_send_signal(SIGKILL) };
+}
+
+/// Returns the start time of the process which the given task belongs to, in
+/// clock ticks since boot (the same as `starttime` in `/proc/<pid>/stat`).
+///
+/// # Safety
+///
+/// The task pointer has to be a valid BTF pointer.
+#[inline(always)]
+pub(crate) unsafe fn process_start_time(task: *const task_struct) -> u64 {
+    (*(*task).group_leader).start_boottime / (NSEC_PER_SEC / USER_HZ)
+}
+
+/// Finds the container of the registered process with the given PID. If the
+/// map entry was left by a previous process with the same PID, it's removed
+/// and the process is treated as not found.
+#[inline(always)]
+pub(crate) fn get_process_container(pid: i32, start_time: u64) -> Option<ContainerID> {
+    let process = unsafe { PROCESSES.get(&pid) }?;
+    if process.start_time == start_time {
+        return Some(process.container_id);
+    }
+
+    let _ = unsafe { PROCESSES.remove(&pid) };
+    None
+}
+
+/// Finds the containerized process with the given PID which could not be
+/// registered. Stale entries are removed the same way as in
+/// `get_process_container`.
+#[inline(always)]
+pub(crate) fn get_untracked_process(pid: i32, start_time: u64) -> Option<Process> {
+    let process = unsafe { UNTRACKED_PROCESSES.get(&pid) }?;
+    if process.start_time == start_time {
+        return Some(*process);
+    }
+
+    let _ = unsafe { UNTRACKED_PROCESSES.remove(&pid) };
+    None
+}
+
+/// Finds the container of the current process by its PID. Threads are
+/// looked up by the PID of their thread group (TGID), the same as the one
+/// used in userspace.
+///
+/// In the fail-closed mode, containerized processes which could not be
+/// registered are killed and an error is returned.
+#[inline(always)]
+fn find_container_by_pid() -> Result<Option<ContainerID>, i32> {
+    let tgid = (bpf_get_current_pid_tgid() >> 32) as i32;
+    let start_time =
+        unsafe { process_start_time(bpf_get_current_task_btf() as *const task_struct) };
+    if let Some(container_id) = get_process_container(tgid, start_time) {
+        return Ok(Some(container_id));
+    }
+
+    if fail_closed() && get_untracked_process(tgid, start_time).is_some() {
+        kill_current();
+        return Err(-1);
+    }
+
+    Ok(None)
+}
+
+/// Finds the container of the current process by its cgroup. Processes
+/// might create nested cgroups inside the container cgroup, so the ancestors
+/// of the current cgroup are checked too.
+#[inline(always)]
+fn find_container_by_cgroup() -> Option<ContainerID> {
+    let cgroup_id = unsafe { bpf_get_current_cgroup_id() };
+    if let Some(container_id) = unsafe { CGROUPS.get(&cgroup_id) } {
+        return Some(*container_id);
+    }
+
+    for level in 0..CGROUP_LEVEL_LIMIT {
+        let cgroup_id = unsafe { bpf_get_current_ancestor_cgroup_id(level) };
+        // The level is deeper than the current cgroup.
+        if cgroup_id == 0 {
+            break;
+        }
+        if let Some(container_id) = unsafe { CGROUPS.get(&cgroup_id) } {
+            return Some(*container_id);
+        }
+    }
+
+    None
+}
 
 /// Finds the policy level for the current LSM hook.
 ///
@@ -13,16 +156,37 @@ use crate::maps::*;
 #[inline(always)]
 pub(crate) fn get_container_and_policy_level(
 ) -> Result<(Option<ContainerID>, ContainerPolicyLevel), i32> {
-    let pid = bpf_get_current_pid_tgid() as u32;
-    let process_o = unsafe { PROCESSES.get(&(pid as i32)) };
-    match process_o {
-        Some(process) => {
-            let container_o = unsafe { CONTAINERS.get(&process.container_id) };
+    let container_id_o = if cgroup_tracking() {
+        find_container_by_cgroup()
+    } else {
+        find_container_by_pid()?
+    };
+    match container_id_o {
+        Some(container_id) => {
+            let container_o = unsafe { CONTAINERS.get(&container_id) };
             match container_o {
-                Some(container) => Ok((Some(process.container_id), container.policy_level)),
+                Some(container) => Ok((Some(container_id), container.policy_level)),
                 None => Err(-2),
             }
         }
         None => Ok((None, ContainerPolicyLevel::NotFound)),
     }
 }
+
+/// Checks whether the given device is in the allowlist of the given policy
+/// level. Privileged containers are allowed to access all devices.
+#[inline(always)]
+pub(crate) fn device_allowed(policy_level: ContainerPolicyLevel, device: &Device) -> bool {
+    let any_minor = Device::new(device.major, DEVICE_MINOR_ANY);
+    match policy_level {
+        ContainerPolicyLevel::Restricted | ContainerPolicyLevel::Offline => unsafe {
+            ALLOWED_DEVICES_RESTRICTED.get(device).is_some()
+                || ALLOWED_DEVICES_RESTRICTED.get(&any_minor).is_some()
+        },
+        ContainerPolicyLevel::Baseline => unsafe {
+            ALLOWED_DEVICES_BASELINE.get(device).is_some()
+                || ALLOWED_DEVICES_BASELINE.get(&any_minor).is_some()
+        },
+        _ => true,
+    }
+}
@@ -1,9 +1,42 @@
 use aya_bpf::{macros::btf_tracepoint, programs::BtfTracePointContext};
-use aya_log_ebpf::debug;
+use aya_log_ebpf::{debug, error};
 
 use lockc_common::Process;
 
-use crate::{maps::*, vmlinux::task_struct};
+use crate::{
+    events::send_tracking_failure,
+    maps::*,
+    policy::{
+        cgroup_tracking, fail_closed, get_process_container, get_untracked_process, kill_current,
+        process_start_time,
+    },
+    stats::count_tracking_failure,
+    vmlinux::task_struct,
+};
+
+/// Registers the containerized process which could not be registered in
+/// `PROCESSES` as untracked, so LSM programs kill it. If that fails too, the
+/// current task is killed right away - after exec it's the process itself,
+/// after fork it's the parent, which is containerized as well.
+#[inline(always)]
+fn register_untracked_process(
+    ctx: &BtfTracePointContext,
+    pid: i32,
+    process: &Process,
+) -> Result<i32, i32> {
+    if let Err(e) = unsafe { UNTRACKED_PROCESSES.insert(&pid, process, 0) } {
+        error!(
+            ctx,
+            "could not register untracked process, killing: pid: {}, container_id: {}, error: {}",
+            pid,
+            unsafe { process.container_id.as_str() },
+            e
+        );
+        kill_current();
+        return Err(e as i32);
+    }
+    Ok(0)
+}
 
 /// Monitors all new tasks/functions created in the system and checks whether
 /// it's a child of some already containerized process (either the container
@@ -12,30 +45,88 @@ use crate::{maps::*, vmlinux::task_struct};
 ///
 /// # Arguments
 ///
-/// * `ppid` - PID of the parent task
-/// * `child` - PID of the new task
+/// * `parent` - parent task
+/// * `child` - new task
 #[inline]
-fn handle_new_process(ctx: BtfTracePointContext, ppid: i32, pid: i32) -> Result<i32, i32> {
+fn handle_new_process(
+    ctx: BtfTracePointContext,
+    parent: *const task_struct,
+    child: *const task_struct,
+) -> Result<i32, i32> {
+    // Containers are identified by cgroups, there is no need to track PIDs.
+    if cgroup_tracking() {
+        return Ok(0);
+    }
+
+    let ppid = unsafe { (*parent).tgid };
+    let pid = unsafe { (*child).tgid };
+    let start_time = unsafe { process_start_time(child) };
+
     // Check if parent process is containerized (already registeed in BPF map).
-    // If not, don't do anything.
-    if let Some(parent) = unsafe { PROCESSES.get(&ppid) } {
-        // Check if child process is already registered. If yes, don't do
-        // anything.
-        let child_lookup = unsafe { PROCESSES.get(&pid) };
-        if child_lookup.is_some() {
+    // If not, don't do anything, except of removing an entry which might be
+    // left by a previous process with the same PID.
+    let parent_start_time = unsafe { process_start_time(parent) };
+    let container_id = match get_process_container(ppid, parent_start_time) {
+        Some(container_id) => container_id,
+        None => {
+            get_process_container(pid, start_time);
+            // Children of untracked processes have to be untracked as well.
+            if fail_closed() {
+                if let Some(parent) = get_untracked_process(ppid, parent_start_time) {
+                    let child = Process {
+                        container_id: parent.container_id,
+                        start_time,
+                    };
+                    count_tracking_failure();
+                    send_tracking_failure(&ctx, pid, parent.container_id, true);
+                    register_untracked_process(&ctx, pid, &child)?;
+                }
+            }
             return Ok(0);
         }
+    };
 
-        // Register a new process.
-        let container_id = parent.container_id;
-        debug!(
+    // Check if child process is already registered. If yes, don't do
+    // anything.
+    if get_process_container(pid, start_time).is_some() {
+        return Ok(0);
+    }
+
+    // Register a new process.
+    debug!(
+        &ctx,
+        "new containerized process: pid: {}, container_id: {}",
+        pid,
+        unsafe { container_id.as_str() }
+    );
+    let child = Process {
+        container_id,
+        start_time,
+    };
+    if let Err(e) = unsafe { PROCESSES.insert(&pid, &child, 0) } {
+        // Most likely the map is full. The process is going to escape
+        // the policy, so make it visible.
+        error!(
             &ctx,
-            "new containerized process: pid: {}, container_id: {}",
+            "could not register containerized process: pid: {}, container_id: {}, error: {}",
             pid,
-            unsafe { container_id.as_str() }
+            unsafe { container_id.as_str() },
+            e
         );
-        let child = Process { container_id };
-        unsafe { PROCESSES.insert(&pid, &child, 0).map_err(|e| e as i32)? };
+        count_tracking_failure();
+        send_tracking_failure(&ctx, pid, container_id, fail_closed());
+        // In the fail-closed mode, keep the process in the fallback map, so
+        // LSM programs kill it instead of treating it as not containerized.
+        if fail_closed() {
+            register_untracked_process(&ctx, pid, &child)?;
+            error!(
+                &ctx,
+                "process is going to be killed: pid: {}, container_id: {}",
+                pid,
+                unsafe { container_id.as_str() }
+            );
+        }
+        return Err(e as i32);
     }
 
     Ok(0)
@@ -56,10 +147,13 @@ fn try_sched_process_fork(ctx: BtfTracePointContext) -> Result<i32, i32> {
     let parent_task: *const task_struct = unsafe { ctx.arg(0) };
     let child_task: *const task_struct = unsafe { ctx.arg(1) };
 
-    let ppid = unsafe { (*parent_task).pid };
-    let pid = unsafe { (*child_task).pid };
+    // A new thread belongs to the same thread group as its parent, which is
+    // already registered (or not) under the TGID.
+    if unsafe { (*parent_task).tgid == (*child_task).tgid } {
+        return Ok(0);
+    }
 
-    handle_new_process(ctx, ppid, pid)
+    handle_new_process(ctx, parent_task, child_task)
 }
 
 /// Tracepoint program triggered by running a new proccess with a binary
@@ -77,10 +171,9 @@ pub fn sched_process_exec(ctx: BtfTracePointContext) -> i32 {
 fn try_sched_process_exec(ctx: BtfTracePointContext) -> Result<i32, i32> {
     let task: *const task_struct = unsafe { ctx.arg(0) };
 
-    let ppid = unsafe { (*(*task).parent).pid };
-    let pid = unsafe { (*task).pid };
+    let parent_task = unsafe { (*task).parent };
 
-    handle_new_process(ctx, ppid, pid)
+    handle_new_process(ctx, parent_task, task)
 }
 
 /// Tracepoint program triggered by a process exiting.
@@ -98,8 +191,18 @@ pub fn sched_process_exit(ctx: BtfTracePointContext) -> i32 {
 fn try_sched_process_exit(ctx: BtfTracePointContext) -> Result<i32, i32> {
     let task: *const task_struct = unsafe { ctx.arg(0) };
 
-    let pid = unsafe { (*task).pid };
+    // The tracepoint is triggered by every exiting thread. Remove the
+    // process only when the last thread of the group exits.
+    let live = unsafe { (*(*task).signal).live.counter };
+    if live > 0 {
+        return Ok(0);
+    }
+
+    let pid = unsafe { (*task).tgid };
 
+    if fail_closed() {
+        let _ = unsafe { UNTRACKED_PROCESSES.remove(&pid) };
+    }
     unsafe { PROCESSES.remove(&pid).map_err(|e| e as i32)? };
 
     Ok(0)
@@ -0,0 +1,43 @@
+use lockc_common::{ContainerID, Decision, Hook, HookStatsKey};
+
+use crate::maps::{HOOK_STATS, PROGRAM_RUNS, TRACKING_FAILURES};
+
+/// Increments the counter of runs of the LSM program attached to the given
+/// hook.
+#[inline(always)]
+pub(crate) fn count_run(hook: Hook) {
+    if let Some(runs) = unsafe { PROGRAM_RUNS.get_ptr_mut(hook as u32) } {
+        unsafe { *runs += 1 };
+    }
+}
+
+/// Increments the counter of containerized processes which could not be
+/// registered, read by userspace.
+#[inline(always)]
+pub(crate) fn count_tracking_failure() {
+    if let Some(failures) = unsafe { TRACKING_FAILURES.get_ptr_mut(0) } {
+        unsafe { *failures += 1 };
+    }
+}
+
+/// Increments the counter of decisions made by the LSM program attached to
+/// the given hook for the given container, based on the value returned by
+/// the program.
+#[inline(always)]
+pub(crate) fn count_decision(hook: Hook, container_id: ContainerID, ret: i32) {
+    let key = HookStatsKey {
+        container_id,
+        hook,
+        decision: if ret == 0 {
+            Decision::Allow
+        } else {
+            Decision::Deny
+        },
+    };
+    match unsafe { HOOK_STATS.get_ptr_mut(&key) } {
+        Some(count) => unsafe { *count += 1 },
+        None => {
+            let _ = unsafe { HOOK_STATS.insert(&key, &1, 0) };
+        }
+    }
+}
@@ -0,0 +1,888 @@
+//! Local API served on a Unix socket, used by lockctl. The protocol is
+//! defined in [`lockc_common::api`].
+//!
+//! Requests which need eBPF maps or the registry are sent as [`ApiCommand`]s
+//! to the eBPF thread, which owns them. That way all changes made by
+//! lockctl go through the daemon, which keeps its state in sync. The eBPF
+//! thread only takes a snapshot of the maps, processes are described with
+//! their state in /proc by the API server.
+
+use std::{
+    fs,
+    future::Future,
+    io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use aya::Bpf;
+use nix::unistd::{chown, Group};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{unix::OwnedWriteHalf, UnixListener, UnixStream},
+    sync::{broadcast, mpsc, oneshot},
+    task,
+};
+use tracing::{debug, error, info, warn};
+
+use lockc_common::{
+    api::{
+        ContainerDetails, ContainerInfo, EnforcementMode, HookPolicy, HookStats, ProcessInfo,
+        Request, Response,
+    },
+    events::{Event, EventFilter},
+    Container, ContainerPolicyLevel, Decision, Hook, HookStatsKey, Process, ProcessTracking,
+};
+
+use crate::{
+    cgroups::{cgroup_pids, CgroupError},
+    events::{ContainerEvent, DaemonEvent, EventHub},
+    gc::process_running,
+    maps::{containers, hook_stats, processes, set_policy_level, MapOperationError},
+    registry::Registry,
+    runc::runc_container_cgroup,
+};
+
+/// Number of API requests which can wait for the eBPF thread.
+pub const API_CHANNEL_CAPACITY: usize = 32;
+
+/// Max number of recent denials shown when inspecting a container.
+const RECENT_DENIALS: usize = 10;
+
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Nix(#[from] nix::Error),
+
+    #[error(transparent)]
+    MapOperation(#[from] MapOperationError),
+
+    #[error(transparent)]
+    Cgroup(#[from] CgroupError),
+
+    #[error("group {0} not found")]
+    GroupNotFound(String),
+
+    #[error("container {0} not found")]
+    ContainerNotFound(String),
+
+    #[error("{0} matches more than one container, use the container ID")]
+    AmbiguousContainer(String),
+
+    #[error("cgroup of the container not found")]
+    CgroupNotFound,
+
+    #[error("request has to be handled by the API server")]
+    UnexpectedRequest,
+}
+
+/// Request sent by the API server to the eBPF thread.
+pub struct ApiCommand {
+    pub request: Request,
+    pub responder_tx: oneshot::Sender<ApiReply>,
+}
+
+/// Reply of the eBPF thread. Registered processes are sent as entries of
+/// the map, which get described by the API server.
+pub enum ApiReply {
+    Response(Response),
+    Processes(Vec<(i32, Process)>),
+    Container(Box<ContainerDetails>, ContainerProcesses),
+}
+
+/// Processes of the inspected container, depending on how lockc tracks
+/// them.
+pub enum ContainerProcesses {
+    /// Entries of the `PROCESSES` map.
+    Registered(Vec<(i32, Process)>),
+    /// Processes in the cgroup of the container with the given ID.
+    Cgroup(String),
+}
+
+impl From<Response> for ApiReply {
+    fn from(response: Response) -> Self {
+        ApiReply::Response(response)
+    }
+}
+
+impl ApiReply {
+    /// Builds the response, reading the state of processes from /proc.
+    fn into_response(self) -> Response {
+        match self {
+            ApiReply::Response(response) => response,
+            ApiReply::Processes(processes) => Response::Processes(describe_processes(processes)),
+            ApiReply::Container(mut details, ContainerProcesses::Registered(processes)) => {
+                details.processes = describe_processes(processes);
+                Response::Container(details)
+            }
+            ApiReply::Container(mut details, ContainerProcesses::Cgroup(container_id)) => {
+                let res = runc_container_cgroup(&container_id)
+                    .ok_or(ApiError::CgroupNotFound)
+                    .and_then(|cgroup_path| describe_cgroup_processes(&container_id, cgroup_path));
+                match res {
+                    Ok(processes) => details.processes = processes,
+                    Err(e) => warn!(
+                        container = container_id.as_str(),
+                        error = e.to_string().as_str(),
+                        "could not read processes of the container cgroup"
+                    ),
+                }
+                Response::Container(details)
+            }
+        }
+    }
+}
+
+/// Binds the API socket and returns the future accepting connections. The
+/// socket is accessible by root and, optionally, by members of the given
+/// group, who can only read.
+pub fn serve_api(
+    path: &Path,
+    group: Option<&str>,
+    hub: Arc<EventHub>,
+    api_tx: mpsc::Sender<ApiCommand>,
+) -> Result<impl Future<Output = ()>, ApiError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // The socket might be left by the previous instance of lockc.
+    match fs::remove_file(path) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(ApiError::from(e)),
+    }
+    let listener = UnixListener::bind(path)?;
+    match group {
+        Some(group) => {
+            let gid = Group::from_name(group)?
+                .ok_or_else(|| ApiError::GroupNotFound(group.to_string()))?
+                .gid;
+            chown(path, None, Some(gid))?;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+        }
+        None => fs::set_permissions(path, fs::Permissions::from_mode(0o600))?,
+    }
+
+    Ok(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let hub = hub.clone();
+                    let api_tx = api_tx.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, hub, api_tx).await {
+                            debug!(error = e.to_string().as_str(), "API connection closed");
+                        }
+                    });
+                }
+                Err(e) => error!(
+                    error = e.to_string().as_str(),
+                    "could not accept API connection"
+                ),
+            }
+        }
+    })
+}
+
+async fn write_response(writer: &mut OwnedWriteHalf, response: &Response) -> Result<(), ApiError> {
+    let mut data = serde_json::to_vec(response)?;
+    data.push(b'\n');
+    writer.write_all(&data).await?;
+    Ok(())
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    hub: Arc<EventHub>,
+    api_tx: mpsc::Sender<ApiCommand>,
+) -> Result<(), ApiError> {
+    let cred = stream.peer_cred()?;
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+
+    let request: Request = match serde_json::from_str(&line) {
+        Ok(request) => request,
+        Err(e) => {
+            let response = Response::Error(format!("invalid request: {}", e));
+            return write_response(&mut writer, &response).await;
+        }
+    };
+
+    match request {
+        Request::Events { filter, follow } => {
+            return stream_events(&mut writer, &hub, &filter, follow).await;
+        }
+        Request::ApplyPolicy {
+            ref container,
+            policy_level,
+        } => {
+            if cred.uid() != 0 {
+                let response =
+                    Response::Error("permission denied: only root can apply policies".to_string());
+                return write_response(&mut writer, &response).await;
+            }
+            info!(
+                container = container.as_str(),
+                policy_level = policy_level.to_string().as_str(),
+                uid = cred.uid(),
+                pid = cred.pid().unwrap_or_default(),
+                "policy level change requested"
+            );
+        }
+        _ => {}
+    }
+
+    let (responder_tx, responder_rx) = oneshot::channel();
+    let reply = match api_tx
+        .send(ApiCommand {
+            request,
+            responder_tx,
+        })
+        .await
+    {
+        Ok(_) => responder_rx.await.ok(),
+        Err(_) => None,
+    };
+    let response = match reply {
+        Some(reply) => task::spawn_blocking(move || reply.into_response())
+            .await
+            .unwrap_or_else(|e| Response::Error(e.to_string())),
+        None => Response::Error("lockc is shutting down".to_string()),
+    };
+    write_response(&mut writer, &response).await
+}
+
+async fn stream_events(
+    writer: &mut OwnedWriteHalf,
+    hub: &EventHub,
+    filter: &EventFilter,
+    follow: bool,
+) -> Result<(), ApiError> {
+    let (history, mut events_rx) = hub.history_and_subscribe();
+    for event in history {
+        if filter.matches(&event) {
+            write_response(writer, &Response::Event(Box::new(event))).await?;
+        }
+    }
+    if !follow {
+        return Ok(());
+    }
+
+    loop {
+        match events_rx.recv().await {
+            Ok(event) => {
+                if filter.matches(&event) {
+                    write_response(writer, &Response::Event(Box::new(event))).await?;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                write_response(writer, &Response::Lagged(skipped)).await?;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// Handles the API request in the eBPF thread.
+pub fn handle_request(
+    bpf: &mut Bpf,
+    registry: &mut Registry,
+    hub: &EventHub,
+    process_tracking: ProcessTracking,
+    request: Request,
+) -> ApiReply {
+    let res = match request {
+        Request::ListContainers => list_containers(bpf, registry)
+            .map(Response::Containers)
+            .map(ApiReply::from),
+        Request::ListProcesses => list_processes(bpf),
+        Request::InspectContainer { container } => {
+            inspect_container(bpf, registry, hub, process_tracking, &container)
+        }
+        Request::ApplyPolicy {
+            container,
+            policy_level,
+        } => apply_policy(
+            bpf,
+            registry,
+            hub,
+            process_tracking,
+            &container,
+            policy_level,
+        ),
+        Request::Stats { container } => stats(bpf, registry, container.as_deref())
+            .map(Response::Stats)
+            .map(ApiReply::from),
+        Request::Events { .. } => Err(ApiError::UnexpectedRequest),
+    };
+    res.unwrap_or_else(|e| Response::Error(e.to_string()).into())
+}
+
+/// Finds the ID of the registered container by its ID, ID prefix or name.
+fn resolve_container(
+    containers: &[(String, Container)],
+    registry: &Registry,
+    container: &str,
+) -> Result<String, ApiError> {
+    if containers.iter().any(|(id, _)| id == container) {
+        return Ok(container.to_string());
+    }
+    let found = containers
+        .iter()
+        .map(|(id, _)| id)
+        .filter(|id| {
+            id.starts_with(container)
+                || registry
+                    .get(id)
+                    .map(|record| record.metadata.matches_name(container))
+                    .unwrap_or(false)
+        })
+        .collect::<Vec<_>>();
+    match found.as_slice() {
+        [id] => Ok(id.to_string()),
+        [] => Err(ApiError::ContainerNotFound(container.to_string())),
+        _ => Err(ApiError::AmbiguousContainer(container.to_string())),
+    }
+}
+
+fn container_info(registry: &Registry, container_id: &str, container: &Container) -> ContainerInfo {
+    let record = registry.get(container_id);
+    ContainerInfo {
+        container_id: container_id.to_string(),
+        policy_level: container.policy_level,
+        enforcement_mode: EnforcementMode::from(container.policy_level),
+        policy_source: record.map(|record| record.policy_source),
+        metadata: record
+            .map(|record| record.metadata.clone())
+            .unwrap_or_default(),
+        registered_at: record.map(|record| record.registered_at),
+    }
+}
+
+fn list_containers(bpf: &Bpf, registry: &Registry) -> Result<Vec<ContainerInfo>, ApiError> {
+    let mut containers = containers(bpf)?
+        .iter()
+        .map(|(container_id, container)| container_info(registry, container_id, container))
+        .collect::<Vec<_>>();
+    containers.sort_by(|a, b| a.container_id.cmp(&b.container_id));
+    Ok(containers)
+}
+
+fn list_processes(bpf: &Bpf) -> Result<ApiReply, ApiError> {
+    Ok(ApiReply::Processes(processes(bpf)?))
+}
+
+fn process_container_id(process: &Process) -> &str {
+    process
+        .container_id
+        .as_str()
+        .unwrap_or_default()
+        .trim_end_matches('\0')
+}
+
+fn process_info(pid: i32, container_id: &str, running: bool) -> ProcessInfo {
+    let (exe, ppid) = match running.then(|| procfs::process::Process::new(pid)) {
+        Some(Ok(p)) => (
+            p.exe().map(|exe| exe.to_string_lossy().to_string()).ok(),
+            p.stat().map(|stat| stat.ppid).ok(),
+        ),
+        _ => (None, None),
+    };
+    ProcessInfo {
+        pid,
+        container_id: container_id.to_string(),
+        running,
+        exe,
+        ppid,
+    }
+}
+
+/// Describes the registered processes with their state in /proc.
+fn describe_processes(processes: Vec<(i32, Process)>) -> Vec<ProcessInfo> {
+    let mut res = processes
+        .iter()
+        .map(|(pid, process)| {
+            // The PID might be reused by another process if lockc missed the
+            // exit of the registered one.
+            let running = process_running(*pid, process);
+            process_info(*pid, process_container_id(process), running)
+        })
+        .collect::<Vec<_>>();
+    res.sort_by_key(|process| process.pid);
+    res
+}
+
+/// Describes processes in the given cgroup (absolute path) of the container
+/// and its descendants. They are not registered in any map when lockc
+/// tracks cgroups.
+fn describe_cgroup_processes(
+    container_id: &str,
+    cgroup_path: PathBuf,
+) -> Result<Vec<ProcessInfo>, ApiError> {
+    let mut res = cgroup_pids(cgroup_path)?
+        .into_iter()
+        .map(|pid| process_info(pid, container_id, true))
+        .collect::<Vec<_>>();
+    res.sort_by_key(|process| process.pid);
+    Ok(res)
+}
+
+/// Describes what every LSM hook does in the container with the given
+/// policy level and sums its decisions.
+fn hook_policies(
+    container_id: &str,
+    policy_level: ContainerPolicyLevel,
+    stats: &[(HookStatsKey, u64)],
+) -> Vec<HookPolicy> {
+    Hook::ALL
+        .iter()
+        .map(|hook| {
+            let mut policy = HookPolicy {
+                hook: *hook,
+                behavior: hook.behavior(policy_level).to_string(),
+                allowed: 0,
+                denied: 0,
+            };
+            for (key, count) in stats {
+                if key.hook != *hook
+                    || key
+                        .container_id
+                        .as_str()
+                        .unwrap_or_default()
+                        .trim_end_matches('\0')
+                        != container_id
+                {
+                    continue;
+                }
+                match key.decision {
+                    Decision::Allow => policy.allowed += count,
+                    Decision::Deny => policy.denied += count,
+                }
+            }
+            policy
+        })
+        .collect()
+}
+
+/// Returns the most recent denials in the container, oldest first.
+fn recent_denials(hub: &EventHub, container_id: &str) -> Vec<Event> {
+    let mut denials = hub
+        .history()
+        .into_iter()
+        .rev()
+        .filter_map(|event| match event {
+            DaemonEvent::Denial(event) if event.container_id == container_id => Some(event),
+            _ => None,
+        })
+        .take(RECENT_DENIALS)
+        .collect::<Vec<_>>();
+    denials.reverse();
+    denials
+}
+
+/// Describes the container. Its processes are returned as map entries, or
+/// as the container ID if cgroups are tracked. The API server reads their
+/// state.
+fn inspect_container(
+    bpf: &Bpf,
+    registry: &Registry,
+    hub: &EventHub,
+    process_tracking: ProcessTracking,
+    container: &str,
+) -> Result<ApiReply, ApiError> {
+    let containers = containers(bpf)?;
+    let container_id = resolve_container(&containers, registry, container)?;
+    let (_, container) = containers
+        .iter()
+        .find(|(id, _)| *id == container_id)
+        .ok_or_else(|| ApiError::ContainerNotFound(container_id.clone()))?;
+
+    let processes = match process_tracking {
+        ProcessTracking::Pid => ContainerProcesses::Registered(
+            processes(bpf)?
+                .into_iter()
+                .filter(|(_, process)| process_container_id(process) == container_id)
+                .collect(),
+        ),
+        ProcessTracking::Cgroup => ContainerProcesses::Cgroup(container_id.clone()),
+    };
+    let details = ContainerDetails {
+        container: container_info(registry, &container_id, container),
+        processes: Vec::new(),
+        hooks: hook_policies(&container_id, container.policy_level, &hook_stats(bpf)?),
+        recent_denials: recent_denials(hub, &container_id),
+    };
+    Ok(ApiReply::Container(Box::new(details), processes))
+}
+
+fn apply_policy(
+    bpf: &mut Bpf,
+    registry: &mut Registry,
+    hub: &EventHub,
+    process_tracking: ProcessTracking,
+    container: &str,
+    policy_level: ContainerPolicyLevel,
+) -> Result<ApiReply, ApiError> {
+    let container_id = resolve_container(&containers(bpf)?, registry, container)?;
+    set_policy_level(bpf, &container_id, policy_level)?;
+    registry.set_policy_level(&container_id, policy_level);
+    if let Some(record) = registry.get(&container_id) {
+        hub.publish(DaemonEvent::PolicyChanged(ContainerEvent::new(
+            container_id.clone(),
+            record,
+        )));
+    }
+    inspect_container(bpf, registry, hub, process_tracking, &container_id)
+}
+
+fn stats(
+    bpf: &Bpf,
+    registry: &Registry,
+    container: Option<&str>,
+) -> Result<Vec<HookStats>, ApiError> {
+    let mut res = Vec::new();
+    for (key, count) in hook_stats(bpf)? {
+        let container_id = key
+            .container_id
+            .as_str()
+            .unwrap_or_default()
+            .trim_end_matches('\0');
+        if let Some(prefix) = container {
+            if !container_id.starts_with(prefix) {
+                continue;
+            }
+        }
+        res.push(HookStats {
+            container_id: container_id.to_string(),
+            container_name: registry
+                .get(container_id)
+                .and_then(|record| record.metadata.display_name()),
+            hook: key.hook,
+            decision: key.decision,
+            count,
+        });
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use tokio::{io::Lines, net::unix::OwnedReadHalf};
+
+    use lockc_common::{
+        registry::{ContainerMetadata, ContainerRecord, PolicySource},
+        Action, ContainerID, IdRange, HOOKS_COUNT,
+    };
+
+    use super::*;
+
+    fn container_event(container_id: &str) -> DaemonEvent {
+        let record = ContainerRecord::new(
+            ContainerPolicyLevel::Baseline,
+            PolicySource::Default,
+            ContainerMetadata::default(),
+        );
+        DaemonEvent::ContainerRegistered(ContainerEvent::new(container_id.to_string(), &record))
+    }
+
+    /// Starts the API server in the given directory.
+    fn serve(dir: &Path) -> (Arc<EventHub>, mpsc::Receiver<ApiCommand>) {
+        let hub = Arc::new(EventHub::default());
+        let (api_tx, api_rx) = mpsc::channel(API_CHANNEL_CAPACITY);
+        tokio::spawn(serve_api(&dir.join("lockc.sock"), None, hub.clone(), api_tx).unwrap());
+        (hub, api_rx)
+    }
+
+    async fn request(dir: &Path, request: &Request) -> Lines<BufReader<OwnedReadHalf>> {
+        let stream = UnixStream::connect(dir.join("lockc.sock")).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut data = serde_json::to_vec(request).unwrap();
+        data.push(b'\n');
+        writer.write_all(&data).await.unwrap();
+        BufReader::new(reader).lines()
+    }
+
+    fn container_id(response: Option<Response>) -> Option<String> {
+        match response? {
+            Response::Event(event) => Some(event.container_id().to_string()),
+            _ => None,
+        }
+    }
+
+    async fn next_response(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Option<Response> {
+        let line = lines.next_line().await.unwrap()?;
+        Some(serde_json::from_str(&line).unwrap())
+    }
+
+    #[tokio::test]
+    async fn api_events() {
+        let dir = tempdir().unwrap();
+        let (hub, _api_rx) = serve(dir.path());
+
+        hub.publish(container_event("5833851e673d"));
+        hub.publish(container_event("f4fb5ea0ec56"));
+
+        let filter = EventFilter {
+            container: Some("5833".to_string()),
+            ..Default::default()
+        };
+
+        // Without following, only the matching recent events are sent.
+        let mut lines = request(
+            dir.path(),
+            &Request::Events {
+                filter: filter.clone(),
+                follow: false,
+            },
+        )
+        .await;
+        assert_eq!(
+            container_id(next_response(&mut lines).await),
+            Some("5833851e673d".to_string())
+        );
+        assert_eq!(next_response(&mut lines).await, None);
+
+        let mut lines = request(
+            dir.path(),
+            &Request::Events {
+                filter,
+                follow: true,
+            },
+        )
+        .await;
+        assert_eq!(
+            container_id(next_response(&mut lines).await),
+            Some("5833851e673d".to_string())
+        );
+        hub.publish(container_event("f4fb5ea0ec56"));
+        hub.publish(container_event("5833851e673d45fa"));
+        assert_eq!(
+            container_id(next_response(&mut lines).await),
+            Some("5833851e673d45fa".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn api_forward_request() {
+        let dir = tempdir().unwrap();
+        let (_hub, mut api_rx) = serve(dir.path());
+        tokio::spawn(async move {
+            while let Some(cmd) = api_rx.recv().await {
+                assert_eq!(cmd.request, Request::ListContainers);
+                assert!(cmd
+                    .responder_tx
+                    .send(Response::Containers(vec![]).into())
+                    .is_ok());
+            }
+        });
+
+        let mut lines = request(dir.path(), &Request::ListContainers).await;
+        assert_eq!(
+            next_response(&mut lines).await,
+            Some(Response::Containers(vec![]))
+        );
+        assert_eq!(next_response(&mut lines).await, None);
+    }
+
+    #[tokio::test]
+    async fn api_invalid_request() {
+        let dir = tempdir().unwrap();
+        let (_hub, _api_rx) = serve(dir.path());
+
+        let stream = UnixStream::connect(dir.path().join("lockc.sock"))
+            .await
+            .unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(b"{\"method\": \"foo\"}\n").await.unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        assert!(matches!(
+            next_response(&mut lines).await,
+            Some(Response::Error(_))
+        ));
+    }
+
+    #[test]
+    fn resolve_container_name() {
+        let dir = tempdir().unwrap();
+        let (mut registry, _) = Registry::load(dir.path()).unwrap();
+        let container = Container {
+            policy_level: ContainerPolicyLevel::Baseline,
+            uid_range: IdRange::single(0),
+            gid_range: IdRange::single(0),
+        };
+        let containers = vec![
+            ("5833851e673d".to_string(), container),
+            ("5833f4fb5ea0".to_string(), container),
+        ];
+        registry.insert(
+            "5833851e673d".to_string(),
+            ContainerRecord::new(
+                ContainerPolicyLevel::Baseline,
+                PolicySource::Default,
+                ContainerMetadata {
+                    namespace: Some("default".to_string()),
+                    pod: Some("nginx".to_string()),
+                    container_name: Some("web".to_string()),
+                    ..Default::default()
+                },
+            ),
+        );
+
+        for name in ["5833851e673d", "58338", "web", "nginx", "default/nginx/web"] {
+            assert_eq!(
+                resolve_container(&containers, &registry, name).unwrap(),
+                "5833851e673d"
+            );
+        }
+        assert!(matches!(
+            resolve_container(&containers, &registry, "5833"),
+            Err(ApiError::AmbiguousContainer(_))
+        ));
+        assert!(matches!(
+            resolve_container(&containers, &registry, "redis"),
+            Err(ApiError::ContainerNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn hook_policies_count() {
+        let key = |container_id: &str, hook, decision| HookStatsKey {
+            container_id: ContainerID::new(container_id).unwrap(),
+            hook,
+            decision,
+        };
+        let stats = vec![
+            (key("5833851e673d", Hook::FileOpen, Decision::Allow), 10),
+            (key("5833851e673d", Hook::FileOpen, Decision::Deny), 2),
+            (key("f4fb5ea0ec56", Hook::FileOpen, Decision::Deny), 5),
+        ];
+
+        let policies = hook_policies("5833851e673d", ContainerPolicyLevel::Offline, &stats);
+        assert_eq!(policies.len(), HOOKS_COUNT);
+        let file_open = policies
+            .iter()
+            .find(|policy| policy.hook == Hook::FileOpen)
+            .unwrap();
+        assert_eq!((file_open.allowed, file_open.denied), (10, 2));
+        let sendmsg = policies
+            .iter()
+            .find(|policy| policy.hook == Hook::SocketSendmsg)
+            .unwrap();
+        assert_eq!(sendmsg.behavior, "deny all");
+        assert_eq!((sendmsg.allowed, sendmsg.denied), (0, 0));
+    }
+
+    #[test]
+    fn reply_describes_processes() {
+        let process = |container_id: &str| Process {
+            container_id: ContainerID::new(container_id).unwrap(),
+            start_time: 0,
+        };
+        let details = ContainerDetails {
+            container: ContainerInfo {
+                container_id: "5833851e673d".to_string(),
+                policy_level: ContainerPolicyLevel::Baseline,
+                enforcement_mode: EnforcementMode::Enforce,
+                policy_source: None,
+                metadata: ContainerMetadata::default(),
+                registered_at: None,
+            },
+            processes: Vec::new(),
+            hooks: Vec::new(),
+            recent_denials: Vec::new(),
+        };
+        // PIDs are never bigger than 2^22, so the processes are not running.
+        let reply = ApiReply::Container(
+            Box::new(details),
+            ContainerProcesses::Registered(vec![
+                (i32::MAX, process("5833851e673d")),
+                (i32::MAX - 1, process("5833851e673d")),
+            ]),
+        );
+
+        match reply.into_response() {
+            Response::Container(details) => assert_eq!(
+                details.processes,
+                vec![
+                    ProcessInfo {
+                        pid: i32::MAX - 1,
+                        container_id: "5833851e673d".to_string(),
+                        running: false,
+                        exe: None,
+                        ppid: None,
+                    },
+                    ProcessInfo {
+                        pid: i32::MAX,
+                        container_id: "5833851e673d".to_string(),
+                        running: false,
+                        exe: None,
+                        ppid: None,
+                    },
+                ]
+            ),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn describe_cgroup() {
+        let dir = tempdir().unwrap();
+        let pid = std::process::id() as i32;
+        fs::write(dir.path().join("cgroup.procs"), format!("{}\n", pid)).unwrap();
+
+        let processes =
+            describe_cgroup_processes("5833851e673d", dir.path().to_path_buf()).unwrap();
+        assert_eq!(processes.len(), 1);
+        assert_eq!(processes[0].pid, pid);
+        assert_eq!(processes[0].container_id, "5833851e673d");
+        assert!(processes[0].running);
+        assert!(processes[0].exe.is_some());
+        assert!(processes[0].ppid.is_some());
+    }
+
+    #[test]
+    fn recent_denials_container() {
+        let denial = |container_id: &str, pid| {
+            DaemonEvent::Denial(Event {
+                timestamp: 0,
+                container_id: container_id.to_string(),
+                hook: Hook::Syslog,
+                action: Action::Syslog,
+                decision: Decision::Deny,
+                policy_level: ContainerPolicyLevel::Baseline,
+                pid,
+                tgid: pid,
+                uid: 0,
+                comm: "dmesg".to_string(),
+                device: None,
+                value: None,
+                path: None,
+                address: None,
+                metadata: ContainerMetadata::default(),
+            })
+        };
+        let hub = EventHub::default();
+        hub.publish(container_event("5833851e673d"));
+        for pid in 0..20 {
+            hub.publish(denial("5833851e673d", pid));
+            hub.publish(denial("f4fb5ea0ec56", pid));
+        }
+
+        let denials = recent_denials(&hub, "5833851e673d");
+        assert_eq!(
+            denials.iter().map(|event| event.pid).collect::<Vec<_>>(),
+            (10..20).collect::<Vec<_>>()
+        );
+        assert!(recent_denials(&hub, "c2a7d3e8").is_empty());
+    }
+}
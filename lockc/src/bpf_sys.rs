@@ -0,0 +1,248 @@
+//! Wrappers of bpf(2) syscall operations which are not supported by aya.
+
+use std::{
+    ffi::CString,
+    io, mem,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    },
+    path::Path,
+};
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_LOOKUP_ELEM: libc::c_long = 1;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_MAP_GET_NEXT_KEY: libc::c_long = 4;
+const BPF_OBJ_PIN: libc::c_long = 6;
+const BPF_OBJ_GET: libc::c_long = 7;
+const BPF_OBJ_GET_INFO_BY_FD: libc::c_long = 15;
+const BPF_RAW_TRACEPOINT_OPEN: libc::c_long = 17;
+
+pub const BPF_MAP_TYPE_HASH: u32 = 1;
+pub const BPF_MAP_TYPE_ARRAY: u32 = 2;
+pub const BPF_MAP_TYPE_PERCPU_HASH: u32 = 5;
+
+/// Allocate hash map elements on demand instead of preallocating all of
+/// them when the map is created.
+pub const BPF_F_NO_PREALLOC: u32 = 1;
+
+/// Fail the update if an element with the given key exists.
+const BPF_NOEXIST: u64 = 1;
+
+const BPF_OBJ_NAME_LEN: usize = 16;
+
+#[repr(C)]
+#[derive(Default)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+    inner_map_fd: u32,
+    numa_node: u32,
+    map_name: [u8; BPF_OBJ_NAME_LEN],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MapElemAttr {
+    map_fd: u32,
+    key: u64,
+    /// Value or the next key, depending on the operation.
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ObjAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct RawTracepointAttr {
+    name: u64,
+    prog_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct InfoAttr {
+    bpf_fd: u32,
+    info_len: u32,
+    info: u64,
+}
+
+/// Beginning of `struct bpf_map_info`. The kernel fills only as many fields
+/// as we ask for.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct MapInfo {
+    pub map_type: u32,
+    pub id: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+}
+
+fn sys_bpf<T>(cmd: libc::c_long, attr: &mut T) -> io::Result<libc::c_long> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut T as *mut libc::c_void,
+            mem::size_of::<T>(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+fn path_cstring<P: AsRef<Path>>(path: P) -> io::Result<CString> {
+    CString::new(path.as_ref().as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Definition of a BPF map.
+pub struct MapDef<'a> {
+    pub name: &'a str,
+    pub map_type: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+}
+
+/// Creates a new BPF map.
+pub fn map_create(def: &MapDef) -> io::Result<OwnedFd> {
+    let mut attr = MapCreateAttr {
+        map_type: def.map_type,
+        key_size: def.key_size,
+        value_size: def.value_size,
+        max_entries: def.max_entries,
+        map_flags: def.map_flags,
+        ..Default::default()
+    };
+    // The name has to be nul terminated.
+    let name = def.name.as_bytes();
+    let len = name.len().min(BPF_OBJ_NAME_LEN - 1);
+    attr.map_name[..len].copy_from_slice(&name[..len]);
+
+    let fd = sys_bpf(BPF_MAP_CREATE, &mut attr)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// Looks up the element with the given key. Returns `false` if there is no
+/// such element.
+pub fn map_lookup_elem(fd: &OwnedFd, key: &[u8], value: &mut [u8]) -> io::Result<bool> {
+    let mut attr = MapElemAttr {
+        map_fd: fd.as_raw_fd() as u32,
+        key: key.as_ptr() as u64,
+        value: value.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    match sys_bpf(BPF_MAP_LOOKUP_ELEM, &mut attr) {
+        Ok(_) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Creates or updates the element with the given key.
+pub fn map_update_elem(fd: &OwnedFd, key: &[u8], value: &[u8]) -> io::Result<()> {
+    let mut attr = MapElemAttr {
+        map_fd: fd.as_raw_fd() as u32,
+        key: key.as_ptr() as u64,
+        value: value.as_ptr() as u64,
+        ..Default::default()
+    };
+    sys_bpf(BPF_MAP_UPDATE_ELEM, &mut attr)?;
+    Ok(())
+}
+
+/// Creates the element with the given key. Returns `false`, without updating
+/// it, if there is already such element.
+pub fn map_insert_elem(fd: &OwnedFd, key: &[u8], value: &[u8]) -> io::Result<bool> {
+    let mut attr = MapElemAttr {
+        map_fd: fd.as_raw_fd() as u32,
+        key: key.as_ptr() as u64,
+        value: value.as_ptr() as u64,
+        flags: BPF_NOEXIST,
+    };
+    match sys_bpf(BPF_MAP_UPDATE_ELEM, &mut attr) {
+        Ok(_) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Finds the key following the given one (or the first key, if none is
+/// given). Returns `false` if there are no more keys.
+pub fn map_get_next_key(fd: &OwnedFd, key: Option<&[u8]>, next_key: &mut [u8]) -> io::Result<bool> {
+    let mut attr = MapElemAttr {
+        map_fd: fd.as_raw_fd() as u32,
+        key: key.map(|key| key.as_ptr() as u64).unwrap_or_default(),
+        value: next_key.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    match sys_bpf(BPF_MAP_GET_NEXT_KEY, &mut attr) {
+        Ok(_) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Pins the BPF object (map, program or link) in BPFFS.
+pub fn obj_pin<P: AsRef<Path>>(fd: &OwnedFd, path: P) -> io::Result<()> {
+    let path = path_cstring(path)?;
+    let mut attr = ObjAttr {
+        pathname: path.as_ptr() as u64,
+        bpf_fd: fd.as_raw_fd() as u32,
+        ..Default::default()
+    };
+    sys_bpf(BPF_OBJ_PIN, &mut attr)?;
+    Ok(())
+}
+
+/// Opens the BPF object (map, program or link) pinned in BPFFS.
+pub fn obj_get<P: AsRef<Path>>(path: P) -> io::Result<OwnedFd> {
+    let path = path_cstring(path)?;
+    let mut attr = ObjAttr {
+        pathname: path.as_ptr() as u64,
+        ..Default::default()
+    };
+    let fd = sys_bpf(BPF_OBJ_GET, &mut attr)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// Returns information about the given BPF map.
+pub fn map_info(fd: &OwnedFd) -> io::Result<MapInfo> {
+    let mut info = MapInfo::default();
+    let mut attr = InfoAttr {
+        bpf_fd: fd.as_raw_fd() as u32,
+        info_len: mem::size_of::<MapInfo>() as u32,
+        info: &mut info as *mut MapInfo as u64,
+    };
+    sys_bpf(BPF_OBJ_GET_INFO_BY_FD, &mut attr)?;
+    Ok(info)
+}
+
+/// Attaches the BTF-enabled program (LSM or BTF tracepoint) to the hook
+/// specified when loading it. Returns the file descriptor of the link,
+/// which can be pinned to keep the program attached after closing it.
+pub fn raw_tracepoint_open(prog_fd: RawFd) -> io::Result<OwnedFd> {
+    let mut attr = RawTracepointAttr {
+        prog_fd: prog_fd as u32,
+        ..Default::default()
+    };
+    let fd = sys_bpf(BPF_RAW_TRACEPOINT_OPEN, &mut attr)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
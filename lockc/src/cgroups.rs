@@ -0,0 +1,222 @@
+use std::{
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+use walkdir::WalkDir;
+
+/// Mount point of the cgroup v2 hierarchy.
+static CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Slice used by runc when the systemd cgroups path doesn't specify any.
+static SYSTEMD_DEFAULT_SLICE: &str = "system.slice";
+
+#[derive(Error, Debug)]
+pub enum CgroupError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
+    #[error("invalid systemd slice name: {0}")]
+    SystemdSlice(String),
+
+    #[error("invalid systemd cgroups path: {0}")]
+    SystemdPath(String),
+
+    #[error(transparent)]
+    WalkDir(#[from] walkdir::Error),
+
+    #[error("could not parse PID in cgroup.procs")]
+    Pid(#[from] std::num::ParseIntError),
+}
+
+/// Expands the name of a systemd slice into the path of nested slices, in
+/// the same way as systemd does. For example,
+/// `kubepods-burstable-pod1.slice` becomes
+/// `kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod1.slice`.
+fn expand_slice(slice: &str) -> Result<PathBuf, CgroupError> {
+    // Root slice.
+    if slice == "-.slice" {
+        return Ok(PathBuf::new());
+    }
+
+    let name = slice
+        .strip_suffix(".slice")
+        .ok_or_else(|| CgroupError::SystemdSlice(slice.to_string()))?;
+    if name.is_empty() || name.contains('/') {
+        return Err(CgroupError::SystemdSlice(slice.to_string()));
+    }
+
+    let mut path = PathBuf::new();
+    let mut prefix = String::new();
+    for component in name.split('-') {
+        if component.is_empty() {
+            return Err(CgroupError::SystemdSlice(slice.to_string()));
+        }
+        if !prefix.is_empty() {
+            prefix.push('-');
+        }
+        prefix.push_str(component);
+        path.push(format!("{}.slice", prefix));
+    }
+
+    Ok(path)
+}
+
+/// Converts the systemd cgroups path (in `slice:prefix:name` format) into
+/// the path relative to the cgroup root.
+fn systemd_cgroup_path(cgroups_path: &str) -> Result<PathBuf, CgroupError> {
+    let parts: Vec<&str> = cgroups_path.split(':').collect();
+    if parts.len() != 3 {
+        return Err(CgroupError::SystemdPath(cgroups_path.to_string()));
+    }
+    let (slice, prefix, name) = (parts[0], parts[1], parts[2]);
+
+    let slice = if slice.is_empty() {
+        SYSTEMD_DEFAULT_SLICE
+    } else {
+        slice
+    };
+    let unit = if name.ends_with(".slice") {
+        name.to_string()
+    } else if prefix.is_empty() {
+        format!("{}.scope", name)
+    } else {
+        format!("{}-{}.scope", prefix, name)
+    };
+
+    Ok(expand_slice(slice)?.join(unit))
+}
+
+/// Converts the `cgroupsPath` from the OCI config into the path relative to
+/// the cgroup root. Both cgroupfs paths (i.e. `/docker/<id>`) and systemd
+/// paths (i.e. `system.slice:docker:<id>`) are supported.
+pub fn cgroup_path(cgroups_path: &str) -> Result<PathBuf, CgroupError> {
+    if cgroups_path.contains(':') {
+        return systemd_cgroup_path(cgroups_path);
+    }
+    Ok(PathBuf::from(cgroups_path.trim_start_matches('/')))
+}
+
+/// Returns the ID of the given cgroup (relative to the cgroup root, or
+/// absolute). In cgroup v2, the ID is the inode number of the cgroup
+/// directory.
+pub fn cgroup_id<P: AsRef<Path>>(path: P) -> Result<u64, CgroupError> {
+    let metadata = fs::metadata(Path::new(CGROUP_ROOT).join(path))?;
+    Ok(metadata.ino())
+}
+
+/// Returns PIDs of all processes in the given cgroup (absolute path) and its
+/// descendants.
+pub fn cgroup_pids<P: AsRef<Path>>(path: P) -> Result<Vec<i32>, CgroupError> {
+    let mut pids = Vec::new();
+    for entry in WalkDir::new(path) {
+        let entry = entry?;
+        if entry.file_name() != "cgroup.procs" {
+            continue;
+        }
+        for line in fs::read_to_string(entry.path())?.lines() {
+            pids.push(line.parse()?);
+        }
+    }
+    Ok(pids)
+}
+
+/// Checks whether any process runs in the given cgroup (relative to the
+/// cgroup root, or absolute) or its descendants.
+pub fn cgroup_populated<P: AsRef<Path>>(path: P) -> Result<bool, CgroupError> {
+    let path = Path::new(CGROUP_ROOT).join(path);
+    match fs::read_to_string(path.join("cgroup.events")) {
+        Ok(events) => Ok(events.lines().any(|line| line == "populated 1")),
+        // There is no events file in cgroup v1.
+        Err(e) if e.kind() == io::ErrorKind::NotFound && path.is_dir() => {
+            Ok(!cgroup_pids(path)?.is_empty())
+        }
+        Err(e) => Err(CgroupError::from(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn expand_slice_nested() {
+        assert_eq!(
+            expand_slice("kubepods-burstable-pod1.slice").unwrap(),
+            PathBuf::from("kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod1.slice")
+        );
+        assert_eq!(
+            expand_slice("system.slice").unwrap(),
+            PathBuf::from("system.slice")
+        );
+        assert_eq!(expand_slice("-.slice").unwrap(), PathBuf::new());
+    }
+
+    #[test]
+    fn expand_slice_invalid() {
+        assert!(expand_slice("system").is_err());
+        assert!(expand_slice("kubepods--pod1.slice").is_err());
+        assert!(expand_slice("a/b.slice").is_err());
+    }
+
+    #[test]
+    fn cgroup_path_cgroupfs() {
+        assert_eq!(
+            cgroup_path("/docker/5833851e673d").unwrap(),
+            PathBuf::from("docker/5833851e673d")
+        );
+    }
+
+    #[test]
+    fn cgroup_path_systemd() {
+        assert_eq!(
+            cgroup_path("system.slice:docker:5833851e673d").unwrap(),
+            PathBuf::from("system.slice/docker-5833851e673d.scope")
+        );
+        assert_eq!(
+            cgroup_path("kubepods-besteffort-pod1.slice:cri-containerd:5833851e673d").unwrap(),
+            PathBuf::from(
+                "kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod1.slice/cri-containerd-5833851e673d.scope"
+            )
+        );
+        assert_eq!(
+            cgroup_path(":runc:5833851e673d").unwrap(),
+            PathBuf::from("system.slice/runc-5833851e673d.scope")
+        );
+        assert!(cgroup_path("system.slice:docker").is_err());
+    }
+
+    #[test]
+    fn cgroup_pids_nested() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("cgroup.procs"), "1\n42\n").unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        fs::write(dir.path().join("nested").join("cgroup.procs"), "43\n").unwrap();
+
+        let mut pids = cgroup_pids(dir.path()).unwrap();
+        pids.sort_unstable();
+        assert_eq!(pids, vec![1, 42, 43]);
+    }
+
+    #[test]
+    fn cgroup_populated_events() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("cgroup.events"), "populated 1\nfrozen 0\n").unwrap();
+        assert!(cgroup_populated(dir.path()).unwrap());
+        fs::write(dir.path().join("cgroup.events"), "populated 0\nfrozen 0\n").unwrap();
+        assert!(!cgroup_populated(dir.path()).unwrap());
+
+        // cgroup v1
+        fs::remove_file(dir.path().join("cgroup.events")).unwrap();
+        fs::write(dir.path().join("cgroup.procs"), "42\n").unwrap();
+        assert!(cgroup_populated(dir.path()).unwrap());
+        fs::write(dir.path().join("cgroup.procs"), "").unwrap();
+        assert!(!cgroup_populated(dir.path()).unwrap());
+
+        assert!(cgroup_populated(dir.path().join("removed")).is_err());
+    }
+}
@@ -1,8 +1,6 @@
 use tokio::sync::oneshot;
 
-use lockc_common::ContainerPolicyLevel;
-
-use crate::maps::MapOperationError;
+use crate::{maps::MapOperationError, registry::ContainerRecord, runc::ContainerCredentials};
 
 /// Set of commands that the other tokio threads can use to request eBPF map
 /// operations.
@@ -11,7 +9,9 @@ pub enum EbpfCommand {
     AddContainer {
         container_id: String,
         pid: i32,
-        policy_level: ContainerPolicyLevel,
+        start_time: u64,
+        record: ContainerRecord,
+        credentials: ContainerCredentials,
         responder_tx: oneshot::Sender<Result<(), MapOperationError>>,
     },
     DeleteContainer {
@@ -21,6 +21,12 @@ pub enum EbpfCommand {
     AddProcess {
         container_id: String,
         pid: i32,
+        start_time: u64,
+        responder_tx: oneshot::Sender<Result<(), MapOperationError>>,
+    },
+    AddCgroup {
+        container_id: String,
+        cgroup_id: u64,
         responder_tx: oneshot::Sender<Result<(), MapOperationError>>,
     },
 }
@@ -0,0 +1,418 @@
+//! Structured events sent by LSM programs every time they deny an action
+//! and by tracepoint programs every time they can't register a
+//! containerized process.
+//!
+//! Events are read from the `EVENTS` and `TRACKING_FAILURE_EVENTS` perf
+//! event arrays, enriched with the metadata of the container from the
+//! registry and published by the [`EventHub`] together with lifecycle events
+//! of containers, so every consumer (logs, metrics, CLI) gets the same
+//! stream.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use aya::{
+    maps::{
+        perf::{AsyncPerfEventArray, PerfBufferError},
+        MapError,
+    },
+    util::online_cpus,
+    Bpf,
+};
+use bytes::BytesMut;
+use thiserror::Error;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info, warn};
+
+use lockc_common::{DenialEvent, TrackingFailureEvent};
+
+pub use lockc_common::events::{ContainerEvent, DaemonEvent, Event, EventFilter, TrackingFailure};
+
+/// Number of events which can be buffered in the broadcast channel before
+/// slow consumers start missing them.
+pub const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Number of recent events kept for clients which connect later.
+const EVENT_HISTORY_LEN: usize = 256;
+
+/// Number of buffers used for reading events from one CPU at once.
+const PERF_BUFFERS: usize = 10;
+
+#[derive(Error, Debug)]
+pub enum EventsError {
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Map(#[from] MapError),
+
+    #[error(transparent)]
+    PerfBuffer(#[from] PerfBufferError),
+}
+
+/// Publishes events to subscribers and keeps the most recent ones, so
+/// clients can see what happened before they connected.
+pub struct EventHub {
+    history: Mutex<VecDeque<DaemonEvent>>,
+    events_tx: broadcast::Sender<DaemonEvent>,
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        let (events_tx, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        EventHub {
+            history: Mutex::new(VecDeque::with_capacity(EVENT_HISTORY_LEN)),
+            events_tx,
+        }
+    }
+}
+
+impl EventHub {
+    pub fn publish(&self, event: DaemonEvent) {
+        // The lock is held while sending, so subscribers created in
+        // `history_and_subscribe` never miss or duplicate an event.
+        let mut history = self.history.lock().unwrap();
+        if history.len() == EVENT_HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(event.clone());
+        // Sending fails only if there are no subscribers.
+        let _ = self.events_tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DaemonEvent> {
+        self.events_tx.subscribe()
+    }
+
+    /// Returns the recent events, oldest first.
+    pub fn history(&self) -> Vec<DaemonEvent> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Returns the recent events and a receiver of the new ones.
+    pub fn history_and_subscribe(&self) -> (Vec<DaemonEvent>, broadcast::Receiver<DaemonEvent>) {
+        let history = self.history.lock().unwrap();
+        (
+            history.iter().cloned().collect(),
+            self.events_tx.subscribe(),
+        )
+    }
+}
+
+/// Starts reading denial and tracking failure events from all CPUs and
+/// sending them to the given channel, whose receiver enriches and publishes
+/// them.
+pub fn read_events(bpf: &mut Bpf, events_tx: mpsc::Sender<DaemonEvent>) -> Result<(), EventsError> {
+    read_perf_events(bpf, "EVENTS", events_tx.clone(), |event: &DenialEvent| {
+        DaemonEvent::Denial(Event::from(event))
+    })?;
+    read_perf_events(
+        bpf,
+        "TRACKING_FAILURE_EVENTS",
+        events_tx,
+        |event: &TrackingFailureEvent| DaemonEvent::TrackingFailure(TrackingFailure::from(event)),
+    )
+}
+
+/// Reads events of type `T` from the given perf event array on all CPUs and
+/// sends them converted to the given channel.
+fn read_perf_events<T, F>(
+    bpf: &mut Bpf,
+    map_name: &'static str,
+    events_tx: mpsc::Sender<DaemonEvent>,
+    convert: F,
+) -> Result<(), EventsError>
+where
+    T: Copy + Send + 'static,
+    F: Fn(&T) -> DaemonEvent + Copy + Send + 'static,
+{
+    let mut events: AsyncPerfEventArray<_> = bpf.map_mut(map_name)?.try_into()?;
+
+    for cpu_id in online_cpus()? {
+        let mut buf = events.open(cpu_id, None)?;
+        let events_tx = events_tx.clone();
+
+        tokio::spawn(async move {
+            let mut buffers = (0..PERF_BUFFERS)
+                .map(|_| BytesMut::with_capacity(1024))
+                .collect::<Vec<_>>();
+
+            loop {
+                let res = match buf.read_events(&mut buffers).await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        error!(
+                            map = map_name,
+                            error = e.to_string().as_str(),
+                            "could not read events"
+                        );
+                        return;
+                    }
+                };
+                if res.lost > 0 {
+                    warn!(map = map_name, cpu = cpu_id, lost = res.lost, "lost events");
+                }
+                for data in buffers.iter().take(res.read) {
+                    if data.len() < std::mem::size_of::<T>() {
+                        continue;
+                    }
+                    let event = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const T) };
+                    if events_tx.send(convert(&event)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Logs every event with its fields.
+pub async fn log_events(mut events_rx: broadcast::Receiver<DaemonEvent>) {
+    loop {
+        match events_rx.recv().await {
+            Ok(DaemonEvent::Denial(event)) => warn!(
+                container = event.container_id.as_str(),
+                name = event.metadata.display_name().unwrap_or_default().as_str(),
+                image = event.metadata.image.as_deref().unwrap_or_default(),
+                hook = event.hook.to_string().as_str(),
+                policy_level = event.policy_level.to_string().as_str(),
+                pid = event.pid,
+                tgid = event.tgid,
+                uid = event.uid,
+                comm = event.comm.as_str(),
+                "{}",
+                event.message()
+            ),
+            Ok(DaemonEvent::TrackingFailure(event)) => error!(
+                container = event.container_id.as_str(),
+                name = event.metadata.display_name().unwrap_or_default().as_str(),
+                policy_level = event.policy_level.to_string().as_str(),
+                pid = event.pid,
+                comm = event.comm.as_str(),
+                killed = event.killed,
+                "containerized process could not be registered, {}",
+                event.consequence()
+            ),
+            Ok(DaemonEvent::ContainerRegistered(event)) => info!(
+                container = event.container_id.as_str(),
+                name = event.metadata.display_name().unwrap_or_default().as_str(),
+                image = event.metadata.image.as_deref().unwrap_or_default(),
+                runtime = event
+                    .metadata
+                    .runtime
+                    .map(|runtime| runtime.to_string())
+                    .unwrap_or_default()
+                    .as_str(),
+                policy_level = event.policy_level.to_string().as_str(),
+                policy_source = event.policy_source.to_string().as_str(),
+                "registered container"
+            ),
+            Ok(DaemonEvent::ContainerDeleted(event)) => info!(
+                container = event.container_id.as_str(),
+                name = event.metadata.display_name().unwrap_or_default().as_str(),
+                "deleted container"
+            ),
+            Ok(DaemonEvent::PolicyChanged(event)) => info!(
+                container = event.container_id.as_str(),
+                name = event.metadata.display_name().unwrap_or_default().as_str(),
+                policy_level = event.policy_level.to_string().as_str(),
+                "changed policy level"
+            ),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!(skipped = skipped, "event log lagged behind")
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lockc_common::{
+        registry::{ContainerMetadata, ContainerRecord, PolicySource},
+        Action, ContainerID, ContainerPolicyLevel, Decision, Device, Hook, SockAddr, COMM_LEN,
+        PATH_LEN,
+    };
+
+    use super::*;
+
+    fn denial_event(action: Action) -> DenialEvent {
+        let mut comm = [0u8; COMM_LEN];
+        comm[..4].copy_from_slice(b"bash");
+        let mut data = [0u8; PATH_LEN];
+        data[..9].copy_from_slice(b"/dev/kmsg");
+        DenialEvent {
+            container_id: ContainerID::new("5833851e673d").unwrap(),
+            hook: Hook::FileOpen,
+            action,
+            decision: Decision::Deny,
+            policy_level: ContainerPolicyLevel::Baseline,
+            pid: 4242,
+            tgid: 4240,
+            uid: 1000,
+            comm,
+            device: Device::new(1, 11),
+            value: 0,
+            data,
+            address: SockAddr::none(),
+        }
+    }
+
+    #[test]
+    fn event_from_denial() {
+        let event = Event::from(&denial_event(Action::OpenPath));
+        assert_eq!(event.container_id, "5833851e673d");
+        assert_eq!(event.comm, "bash");
+        assert_eq!(event.path.as_deref(), Some("/dev/kmsg"));
+        assert_eq!(event.device, None);
+        assert_eq!(event.value, None);
+        assert_eq!(
+            event.message(),
+            "file_open: 5833851e673d: deny opening /dev/kmsg"
+        );
+
+        let event = Event::from(&denial_event(Action::OpenDevice));
+        assert_eq!(event.device.as_deref(), Some("1:11"));
+        assert_eq!(event.path, None);
+        assert_eq!(
+            event.message(),
+            "file_open: 5833851e673d: deny opening device 1:11"
+        );
+    }
+
+    #[test]
+    fn event_network_address() {
+        let mut denial = denial_event(Action::Network);
+        denial.hook = Hook::SocketSendmsg;
+        denial.address = SockAddr::ipv4([10, 0, 0, 1], 443);
+        let event = Event::from(&denial);
+        assert_eq!(event.address.as_deref(), Some("10.0.0.1:443"));
+        assert_eq!(event.path, None);
+        assert_eq!(
+            event.message(),
+            "socket_sendmsg: 5833851e673d: deny sending network messages to 10.0.0.1:443"
+        );
+    }
+
+    #[test]
+    fn event_json() {
+        let event = Event::from(&denial_event(Action::OpenDevice));
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["hook"], "file_open");
+        assert_eq!(value["action"], "open_device");
+        assert_eq!(value["decision"], "deny");
+        assert_eq!(value["policy_level"], "baseline");
+        assert_eq!(value["device"], "1:11");
+    }
+
+    #[test]
+    fn event_metadata() {
+        let mut event = Event::from(&denial_event(Action::OpenPath));
+        event.metadata = ContainerMetadata {
+            namespace: Some("default".to_string()),
+            pod: Some("nginx".to_string()),
+            container_name: Some("web".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            event.message(),
+            "file_open: default/nginx/web: deny opening /dev/kmsg"
+        );
+
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["namespace"], "default");
+        assert_eq!(value["pod"], "nginx");
+        assert_eq!(value["container_name"], "web");
+    }
+
+    #[test]
+    fn tracking_failure_event() {
+        let mut comm = [0u8; COMM_LEN];
+        comm[..4].copy_from_slice(b"bash");
+        let mut event =
+            DaemonEvent::TrackingFailure(TrackingFailure::from(&TrackingFailureEvent {
+                container_id: ContainerID::new("5833851e673d").unwrap(),
+                policy_level: ContainerPolicyLevel::Restricted,
+                pid: 4242,
+                comm,
+                killed: true,
+            }));
+        event.metadata_mut().container_name = Some("web".to_string());
+        assert_eq!(
+            event.message(),
+            "web: could not register process 4242 (bash), the process is killed"
+        );
+        assert_eq!(event.hook(), None);
+
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["type"], "tracking_failure");
+        assert_eq!(value["policy_level"], "restricted");
+        assert_eq!(value["killed"], true);
+    }
+
+    fn container_event(container_id: &str) -> DaemonEvent {
+        let record = ContainerRecord::new(
+            ContainerPolicyLevel::Baseline,
+            PolicySource::Default,
+            ContainerMetadata::default(),
+        );
+        DaemonEvent::ContainerRegistered(ContainerEvent::new(container_id.to_string(), &record))
+    }
+
+    #[test]
+    fn event_hub_history() {
+        let hub = EventHub::default();
+        for i in 0..EVENT_HISTORY_LEN + 2 {
+            hub.publish(container_event(&i.to_string()));
+        }
+        let (history, mut events_rx) = hub.history_and_subscribe();
+        // The oldest events were dropped.
+        assert_eq!(history.len(), EVENT_HISTORY_LEN);
+        assert_eq!(history[0].container_id(), "2");
+
+        hub.publish(DaemonEvent::Denial(Event::from(&denial_event(
+            Action::OpenPath,
+        ))));
+        let event = events_rx.try_recv().unwrap();
+        assert_eq!(event.hook(), Some(Hook::FileOpen));
+        assert_eq!(serde_json::to_value(&event).unwrap()["type"], "denial");
+    }
+
+    #[test]
+    fn event_filter() {
+        let mut denial = Event::from(&denial_event(Action::OpenPath));
+        denial.metadata.namespace = Some("default".to_string());
+        denial.metadata.container_name = Some("web".to_string());
+        let denial = DaemonEvent::Denial(denial);
+        let registered = container_event("5833851e673d");
+
+        assert!(EventFilter::default().matches(&denial));
+        let filter = EventFilter {
+            container: Some("5833".to_string()),
+            ..Default::default()
+        };
+        assert!(filter.matches(&denial));
+        assert!(filter.matches(&registered));
+        let filter = EventFilter {
+            container: Some("web".to_string()),
+            namespace: Some("default".to_string()),
+            ..Default::default()
+        };
+        assert!(filter.matches(&denial));
+        assert!(!filter.matches(&registered));
+        let filter = EventFilter {
+            hook: Some(Hook::FileOpen),
+            policy_level: Some(ContainerPolicyLevel::Baseline),
+            ..Default::default()
+        };
+        assert!(filter.matches(&denial));
+        // Lifecycle events have no hook.
+        assert!(!filter.matches(&registered));
+        let filter = EventFilter {
+            hook: Some(Hook::SbMount),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&denial));
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    collections::HashSet,
+    ops::AddAssign,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use aya::{
+    maps::{HashMap, PerCpuHashMap},
+    Bpf,
+};
+use tracing::debug;
+
+use lockc_common::{Container, ContainerGroup, ContainerID, HookStatsKey, Process};
+
+use crate::{
+    cgroups::cgroup_populated, maps::MapOperationError, registry::Registry,
+    runc::runc_container_exists,
+};
+
+/// Time (in seconds) after registering a container during which it's not
+/// removed, even though it has neither runc state nor registered processes.
+const REGISTRATION_GRACE_PERIOD: u64 = 120;
+
+/// Numbers of stale entries removed from eBPF maps.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GcStats {
+    pub processes: u64,
+    pub untracked_processes: u64,
+    pub containers: u64,
+    pub container_groups: u64,
+    pub cgroups: u64,
+    pub hook_stats: u64,
+}
+
+impl AddAssign for GcStats {
+    fn add_assign(&mut self, other: Self) {
+        self.processes += other.processes;
+        self.untracked_processes += other.untracked_processes;
+        self.containers += other.containers;
+        self.container_groups += other.container_groups;
+        self.cgroups += other.cgroups;
+        self.hook_stats += other.hook_stats;
+    }
+}
+
+/// Checks whether the process with the given PID and start time is still
+/// running. If the PID is used by another process, the registered one is
+/// considered to be gone.
+pub fn process_running(pid: i32, process: &Process) -> bool {
+    match procfs::process::Process::new(pid).and_then(|p| p.stat()) {
+        Ok(stat) => stat.starttime == process.start_time,
+        Err(_) => false,
+    }
+}
+
+/// Checks whether the container was registered during the grace period.
+fn registered_recently(registry: &Registry, container_id: &str, now: u64) -> bool {
+    registry
+        .get(container_id)
+        .map(|record| now.saturating_sub(record.registered_at) < REGISTRATION_GRACE_PERIOD)
+        .unwrap_or(false)
+}
+
+/// Checks whether any process runs in the cgroup of the container, recorded
+/// when registering it. Unlike runc state, the cgroup is found regardless of
+/// the runc root directory used by the container engine.
+fn cgroup_running(registry: &Registry, container_id: &str) -> bool {
+    registry
+        .get(container_id)
+        .and_then(|record| record.cgroup_path.as_ref())
+        .map(|cgroup_path| cgroup_populated(cgroup_path).unwrap_or(false))
+        .unwrap_or(false)
+}
+
+/// Removes processes which are not running anymore from the given map.
+/// Returns the number of removed processes.
+fn collect_processes(bpf: &mut Bpf, map_name: &str) -> Result<u64, MapOperationError> {
+    let mut processes: HashMap<_, i32, Process> = bpf.map_mut(map_name)?.try_into()?;
+    let mut to_remove = Vec::new();
+    for res in processes.iter() {
+        let (pid, process) = res?;
+        if !process_running(pid, &process) {
+            to_remove.push(pid);
+        }
+    }
+    for pid in to_remove.iter() {
+        debug!(pid = pid, map = map_name, "removing stale process");
+        processes.remove(pid)?;
+    }
+
+    Ok(to_remove.len() as u64)
+}
+
+/// Removes entries which are not valid anymore from eBPF maps:
+///
+/// * processes which are not running (i.e. when lockc missed their exit)
+/// * containers which have neither runc state, registered processes nor
+///   processes in their cgroup and were not registered recently (i.e. when
+///   lockc missed their deletion)
+/// * supplementary groups, cgroups and hook counters of removed containers
+pub fn collect_garbage(bpf: &mut Bpf, registry: &Registry) -> Result<GcStats, MapOperationError> {
+    let mut stats = GcStats {
+        processes: collect_processes(bpf, "PROCESSES")?,
+        untracked_processes: collect_processes(bpf, "UNTRACKED_PROCESSES")?,
+        ..Default::default()
+    };
+
+    // Containers which are being created might not have any runc state yet.
+    // With PID tracking, the runc process creating them is registered. With
+    // cgroup tracking, nothing points to them until they start, so the time
+    // of registration is checked as well.
+    let processes: HashMap<_, i32, Process> = bpf.map("PROCESSES")?.try_into()?;
+    let mut alive = HashSet::new();
+    for res in processes.iter() {
+        let (_, process) = res?;
+        alive.insert(process.container_id.id);
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut containers: HashMap<_, ContainerID, Container> =
+        bpf.map_mut("CONTAINERS")?.try_into()?;
+    let mut to_remove = Vec::new();
+    for res in containers.keys() {
+        let container_id = res?;
+        if alive.contains(&container_id.id) {
+            continue;
+        }
+        match container_id.as_str().map(|id| id.trim_end_matches('\0')) {
+            Ok(id)
+                if runc_container_exists(id)
+                    || registered_recently(registry, id, now)
+                    || cgroup_running(registry, id) =>
+            {
+                alive.insert(container_id.id);
+            }
+            _ => to_remove.push(container_id),
+        }
+    }
+    for container_id in to_remove.iter() {
+        debug!(
+            container = container_id.as_str().unwrap_or_default(),
+            "removing stale container"
+        );
+        containers.remove(container_id)?;
+    }
+    stats.containers = to_remove.len() as u64;
+
+    let mut container_groups: HashMap<_, ContainerGroup, u8> =
+        bpf.map_mut("CONTAINER_GROUPS")?.try_into()?;
+    let mut to_remove = Vec::new();
+    for res in container_groups.keys() {
+        let group = res?;
+        if !alive.contains(&group.container_id.id) {
+            to_remove.push(group);
+        }
+    }
+    for group in to_remove.iter() {
+        container_groups.remove(group)?;
+    }
+    stats.container_groups = to_remove.len() as u64;
+
+    let mut cgroups: HashMap<_, u64, ContainerID> = bpf.map_mut("CGROUPS")?.try_into()?;
+    let mut to_remove = Vec::new();
+    for res in cgroups.iter() {
+        let (cgroup_id, container_id) = res?;
+        if !alive.contains(&container_id.id) {
+            to_remove.push(cgroup_id);
+        }
+    }
+    for cgroup_id in to_remove.iter() {
+        cgroups.remove(cgroup_id)?;
+    }
+    stats.cgroups = to_remove.len() as u64;
+
+    let mut hook_stats: PerCpuHashMap<_, HookStatsKey, u64> =
+        bpf.map_mut("HOOK_STATS")?.try_into()?;
+    let mut to_remove = Vec::new();
+    for res in hook_stats.keys() {
+        let key = res?;
+        if !alive.contains(&key.container_id.id) {
+            to_remove.push(key);
+        }
+    }
+    for key in to_remove.iter() {
+        hook_stats.remove(key)?;
+    }
+    stats.hook_stats = to_remove.len() as u64;
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::{tempdir, Builder};
+
+    use lockc_common::{ContainerPolicyLevel, ProcessTracking};
+
+    use crate::{
+        load::load_bpf,
+        maps::add_container,
+        registry::{ContainerMetadata, ContainerRecord, PolicySource},
+        runc::ContainerCredentials,
+    };
+
+    use super::*;
+
+    #[test]
+    #[cfg_attr(not(feature = "tests_bpf"), ignore)]
+    fn test_collect_garbage() {
+        let path_base = Builder::new()
+            .prefix("lockc-temp")
+            .rand_bytes(5)
+            .tempdir_in("/sys/fs/bpf")
+            .expect("Creating temporary dir in BPFFS failed");
+        let mut bpf =
+            load_bpf(&path_base, ProcessTracking::Pid, false).expect("Loading BPF failed");
+
+        // Container with a process which doesn't exist.
+        add_container(
+            &mut bpf,
+            "5833851e673d45fab4d12105bf61c3f4892b2bbf9c12d811db509a4f22475ec9".to_string(),
+            i32::MAX,
+            0,
+            ContainerPolicyLevel::Baseline,
+            ContainerCredentials {
+                additional_gids: vec![2000],
+                ..Default::default()
+            },
+            ProcessTracking::Pid,
+        )
+        .expect("Adding container failed");
+
+        let state_dir = tempdir().unwrap();
+        let (registry, _) = Registry::load(&state_dir).unwrap();
+        let stats = collect_garbage(&mut bpf, &registry).expect("Collecting garbage failed");
+        assert_eq!(stats.processes, 1);
+        assert_eq!(stats.containers, 1);
+        assert_eq!(stats.container_groups, 1);
+
+        let containers: HashMap<_, ContainerID, Container> = bpf
+            .map("CONTAINERS")
+            .expect("Getting the map failed")
+            .try_into()
+            .expect("Converting the map failed");
+        assert_eq!(containers.keys().count(), 0);
+    }
+
+    #[test]
+    fn registered_recently_grace_period() {
+        let state_dir = tempdir().unwrap();
+        let (mut registry, _) = Registry::load(&state_dir).unwrap();
+        let record = ContainerRecord::new(
+            ContainerPolicyLevel::Baseline,
+            PolicySource::Default,
+            ContainerMetadata::default(),
+        );
+        let registered_at = record.registered_at;
+        registry.insert("5833851e673d".to_string(), record);
+
+        assert!(registered_recently(
+            &registry,
+            "5833851e673d",
+            registered_at
+        ));
+        assert!(!registered_recently(
+            &registry,
+            "5833851e673d",
+            registered_at + REGISTRATION_GRACE_PERIOD
+        ));
+        assert!(!registered_recently(
+            &registry,
+            "f1c4a2b9e0d7",
+            registered_at
+        ));
+    }
+
+    #[test]
+    fn cgroup_running_recorded_cgroup() {
+        let state_dir = tempdir().unwrap();
+        let cgroup_dir = tempdir().unwrap();
+        let (mut registry, _) = Registry::load(&state_dir).unwrap();
+        let record = ContainerRecord::new(
+            ContainerPolicyLevel::Baseline,
+            PolicySource::Default,
+            ContainerMetadata::default(),
+        );
+        registry.insert("f1c4a2b9e0d7".to_string(), record.clone());
+        registry.insert(
+            "5833851e673d".to_string(),
+            ContainerRecord {
+                cgroup_path: Some(cgroup_dir.path().to_path_buf()),
+                ..record
+            },
+        );
+
+        fs::write(cgroup_dir.path().join("cgroup.events"), "populated 1\n").unwrap();
+        assert!(cgroup_running(&registry, "5833851e673d"));
+        // Containers without a recorded cgroup are not checked.
+        assert!(!cgroup_running(&registry, "f1c4a2b9e0d7"));
+
+        fs::write(cgroup_dir.path().join("cgroup.events"), "populated 0\n").unwrap();
+        assert!(!cgroup_running(&registry, "5833851e673d"));
+    }
+}
@@ -0,0 +1,606 @@
+//! Kubernetes Events about denials and processes which could not be
+//! registered in pods, which make them visible to developers in
+//! `kubectl describe pod` and `kubectl get events`.
+//!
+//! Denials are aggregated by pod, hook and action, tracking failures by pod.
+//! Every aggregate is
+//! reported as a single Event, whose count and message (describing the
+//! latest denial) get updated on every flush. The number of API requests in
+//! one flush is limited, both per pod and in total, so neither a
+//! crash-looping pod nor many denying pods can flood the API server - their
+//! denials are only counted until the next flush.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    time::Duration,
+};
+
+use k8s_openapi::{
+    api::core::v1,
+    apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time},
+    chrono::{DateTime, Utc},
+};
+use kube::{
+    api::{Api, Patch, PatchParams, PostParams},
+    Client,
+};
+use serde_json::json;
+use tokio::{sync::mpsc, time};
+use tracing::{debug, error, warn};
+
+use lockc_common::{Action, Hook};
+
+use crate::{
+    events::{DaemonEvent, Event, TrackingFailure},
+    registry::ContainerMetadata,
+};
+
+/// Number of denials which can wait for the recorder before new ones get
+/// dropped.
+pub const KUBE_EVENTS_CHANNEL_CAPACITY: usize = 256;
+
+/// How often aggregated denials are reported.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Max number of API requests made for a single pod in one flush.
+const REQUESTS_PER_POD: usize = 5;
+
+/// Max number of API requests made in one flush.
+const REQUESTS_PER_FLUSH: usize = 50;
+
+/// Max number of tracked aggregates. When reached, denials which would need
+/// a new aggregate are dropped.
+const MAX_AGGREGATES: usize = 4096;
+
+/// How long aggregates without new denials are kept. Kubernetes removes
+/// Events after an hour by default.
+const AGGREGATE_TTL: Duration = Duration::from_secs(3600);
+
+static COMPONENT: &str = "lockc";
+static REASON_DENIED: &str = "PolicyDenied";
+static REASON_UNTRACKED: &str = "ProcessUntracked";
+
+/// Pod which the denied container belongs to.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PodRef {
+    pub namespace: String,
+    pub name: String,
+    /// Distinguishes the pod from earlier pods with the same name. Not known
+    /// for containers registered before lockc stored it.
+    pub uid: Option<String>,
+}
+
+impl PodRef {
+    /// Returns the pod of the container, if the container was created by
+    /// Kubernetes.
+    pub fn from_metadata(metadata: &ContainerMetadata) -> Option<Self> {
+        Some(PodRef {
+            namespace: metadata.namespace.clone()?,
+            name: metadata.pod.clone()?,
+            uid: metadata.pod_uid.clone(),
+        })
+    }
+}
+
+/// What happened in the pod.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PodEventKind {
+    /// The hook denied the action.
+    Denial(Hook, Action),
+    /// A containerized process could not be registered.
+    TrackingFailure,
+}
+
+impl PodEventKind {
+    fn reason(&self) -> &'static str {
+        match self {
+            PodEventKind::Denial(..) => REASON_DENIED,
+            PodEventKind::TrackingFailure => REASON_UNTRACKED,
+        }
+    }
+}
+
+/// Denial or tracking failure in a pod, sent to the recorder.
+#[derive(Debug)]
+pub struct PodEvent {
+    pub pod: PodRef,
+    pub kind: PodEventKind,
+    pub message: String,
+}
+
+impl PodEvent {
+    /// Returns the denial or tracking failure, if the container was created
+    /// by Kubernetes. Lifecycle events are not reported.
+    pub fn from_event(event: &DaemonEvent) -> Option<Self> {
+        let pod = PodRef::from_metadata(event.metadata())?;
+        let (kind, message) = match event {
+            DaemonEvent::Denial(event) => (
+                PodEventKind::Denial(event.hook, event.action),
+                denial_message(event),
+            ),
+            DaemonEvent::TrackingFailure(event) => (
+                PodEventKind::TrackingFailure,
+                tracking_failure_message(event),
+            ),
+            _ => return None,
+        };
+        Some(PodEvent { pod, kind, message })
+    }
+}
+
+/// Key of the aggregate. Paths and values of denials are not part of it, so
+/// the number of aggregates of a pod is bounded.
+type AggregateKey = (PodRef, PodEventKind);
+
+/// Returns the message of the Kubernetes Event about the denial.
+fn denial_message(event: &Event) -> String {
+    match &event.metadata.container_name {
+        Some(container_name) => format!(
+            "lockc denied {} in container {} ({})",
+            event.action_description(),
+            container_name,
+            event.policy_level
+        ),
+        None => format!(
+            "lockc denied {} ({})",
+            event.action_description(),
+            event.policy_level
+        ),
+    }
+}
+
+/// Returns the message of the Kubernetes Event about the tracking failure.
+fn tracking_failure_message(event: &TrackingFailure) -> String {
+    match &event.metadata.container_name {
+        Some(container_name) => format!(
+            "lockc could not register process {} ({}) in container {}, {}",
+            event.pid,
+            event.comm,
+            container_name,
+            event.consequence()
+        ),
+        None => format!(
+            "lockc could not register process {} ({}), {}",
+            event.pid,
+            event.comm,
+            event.consequence()
+        ),
+    }
+}
+
+#[derive(Debug)]
+struct Aggregate {
+    /// Message of the latest event.
+    message: String,
+    /// Name of the Event reporting the aggregate, once it's created.
+    event_name: Option<String>,
+    /// Number of events already reported.
+    reported: i32,
+    /// Number of events waiting for the next flush.
+    pending: i32,
+    first_seen: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+}
+
+/// Aggregates denials and tracking failures and reports them as Kubernetes
+/// Events.
+pub struct KubeEventRecorder {
+    client: Client,
+    aggregates: BTreeMap<AggregateKey, Aggregate>,
+    /// The last aggregate reported in the previous flush. The next flush
+    /// starts after it, so aggregates at the end are not starved when the
+    /// request budget runs out.
+    last_reported: Option<AggregateKey>,
+    /// Number of events dropped because of the aggregates limit since the
+    /// last flush.
+    dropped: u64,
+}
+
+impl KubeEventRecorder {
+    pub fn new(client: Client) -> Self {
+        KubeEventRecorder {
+            client,
+            aggregates: BTreeMap::new(),
+            last_reported: None,
+            dropped: 0,
+        }
+    }
+
+    /// Counts the event, which is going to be reported on the next flush.
+    pub fn record(&mut self, event: PodEvent) {
+        let now = Utc::now();
+        let key = (event.pod, event.kind);
+        if let Some(aggregate) = self.aggregates.get_mut(&key) {
+            aggregate.message = event.message;
+            aggregate.pending += 1;
+            aggregate.last_seen = now;
+            return;
+        }
+        if self.aggregates.len() >= MAX_AGGREGATES {
+            self.dropped += 1;
+            return;
+        }
+        self.aggregates.insert(
+            key,
+            Aggregate {
+                message: event.message,
+                event_name: None,
+                reported: 0,
+                pending: 1,
+                first_seen: now,
+                last_seen: now,
+            },
+        );
+    }
+
+    /// Reports pending events and forgets aggregates which didn't get new
+    /// events for a long time. Returns the number of API requests made.
+    pub async fn flush(&mut self) -> usize {
+        if self.dropped > 0 {
+            warn!(
+                dropped = self.dropped,
+                "too many denials in pods, some of them were not reported as Kubernetes Events"
+            );
+            self.dropped = 0;
+        }
+
+        let pending: Vec<AggregateKey> = match &self.last_reported {
+            Some(last) => {
+                let after = self
+                    .aggregates
+                    .range((Bound::Excluded(last), Bound::Unbounded));
+                let before = self.aggregates.range(..=last);
+                after
+                    .chain(before)
+                    .filter(|(_, aggregate)| aggregate.pending > 0)
+                    .map(|(key, _)| key.clone())
+                    .collect()
+            }
+            None => self
+                .aggregates
+                .iter()
+                .filter(|(_, aggregate)| aggregate.pending > 0)
+                .map(|(key, _)| key.clone())
+                .collect(),
+        };
+
+        let mut pod_requests: HashMap<PodRef, usize> = HashMap::new();
+        let mut requests = 0;
+        for key in pending {
+            if requests >= REQUESTS_PER_FLUSH {
+                break;
+            }
+            let pod_requests = pod_requests.entry(key.0.clone()).or_default();
+            if *pod_requests >= REQUESTS_PER_POD {
+                continue;
+            }
+            *pod_requests += 1;
+            requests += 1;
+
+            let (pod, kind) = &key;
+            if let Some(aggregate) = self.aggregates.get_mut(&key) {
+                if let Err(e) = report(&self.client, pod, kind.reason(), aggregate).await {
+                    error!(
+                        namespace = pod.namespace.as_str(),
+                        pod = pod.name.as_str(),
+                        error = e.to_string().as_str(),
+                        "could not report Kubernetes Event"
+                    );
+                }
+            }
+            self.last_reported = Some(key);
+        }
+
+        let now = Utc::now();
+        self.aggregates.retain(|_, aggregate| {
+            aggregate.pending > 0
+                || (now - aggregate.last_seen)
+                    .to_std()
+                    .map(|age| age < AGGREGATE_TTL)
+                    .unwrap_or(true)
+        });
+
+        requests
+    }
+}
+
+/// Creates the Event for the aggregate or updates its count.
+async fn report(
+    client: &Client,
+    pod: &PodRef,
+    reason: &str,
+    aggregate: &mut Aggregate,
+) -> Result<(), kube::Error> {
+    let api: Api<v1::Event> = Api::namespaced(client.clone(), &pod.namespace);
+    let count = aggregate.reported + aggregate.pending;
+
+    match &aggregate.event_name {
+        Some(name) => {
+            let patch = json!({
+                "count": count,
+                "message": aggregate.message,
+                "lastTimestamp": Time(aggregate.last_seen),
+            });
+            match api
+                .patch(name, &PatchParams::default(), &Patch::Merge(&patch))
+                .await
+            {
+                Ok(_) => {}
+                // The Event expired. Pending denials are going to be reported
+                // as a new one on the next flush.
+                Err(kube::Error::Api(e)) if e.code == 404 => {
+                    aggregate.event_name = None;
+                    aggregate.reported = 0;
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        None => {
+            let name = format!("{}.{:x}", pod.name, aggregate.first_seen.timestamp_nanos());
+            let event = v1::Event {
+                metadata: ObjectMeta {
+                    name: Some(name.clone()),
+                    namespace: Some(pod.namespace.clone()),
+                    ..Default::default()
+                },
+                involved_object: v1::ObjectReference {
+                    api_version: Some("v1".to_string()),
+                    kind: Some("Pod".to_string()),
+                    name: Some(pod.name.clone()),
+                    namespace: Some(pod.namespace.clone()),
+                    uid: pod.uid.clone(),
+                    ..Default::default()
+                },
+                type_: Some("Warning".to_string()),
+                reason: Some(reason.to_string()),
+                message: Some(aggregate.message.clone()),
+                count: Some(count),
+                first_timestamp: Some(Time(aggregate.first_seen)),
+                last_timestamp: Some(Time(aggregate.last_seen)),
+                source: Some(v1::EventSource {
+                    component: Some(COMPONENT.to_string()),
+                    host: None,
+                }),
+                reporting_component: Some(COMPONENT.to_string()),
+                ..Default::default()
+            };
+            api.create(&PostParams::default(), &event).await?;
+            aggregate.event_name = Some(name);
+        }
+    }
+
+    aggregate.reported = count;
+    aggregate.pending = 0;
+    Ok(())
+}
+
+/// Receives denials and tracking failures in pods and periodically reports
+/// them as Kubernetes Events.
+pub async fn record_kube_events(
+    mut recorder: KubeEventRecorder,
+    mut kube_events_rx: mpsc::Receiver<PodEvent>,
+) {
+    let mut flush_interval = time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            event = kube_events_rx.recv() => match event {
+                Some(event) => recorder.record(event),
+                None => break,
+            },
+            _ = flush_interval.tick() => {
+                let requests = recorder.flush().await;
+                if requests > 0 {
+                    debug!(requests = requests, "reported Kubernetes Events");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::{Arc, Mutex},
+    };
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Method, Request, Response, Server,
+    };
+    use serde_json::Value;
+
+    use lockc_common::ContainerPolicyLevel;
+
+    use super::*;
+
+    type Requests = Arc<Mutex<Vec<(Method, String, Value)>>>;
+
+    /// Starts a fake API server, which records requests and responds with
+    /// a minimal Event, and returns a client connected to it.
+    fn fake_api_server() -> (Client, Requests) {
+        let requests = Requests::default();
+        let requests_svc = requests.clone();
+        let make_svc = make_service_fn(move |_conn| {
+            let requests = requests_svc.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let requests = requests.clone();
+                    async move {
+                        let method = req.method().clone();
+                        let path = req.uri().path().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let body: Value = serde_json::from_slice(&body).unwrap();
+                        requests.lock().unwrap().push((method, path, body));
+
+                        let event = json!({
+                            "apiVersion": "v1",
+                            "kind": "Event",
+                            "metadata": {"name": "fake"},
+                            "involvedObject": {},
+                        });
+                        Ok::<_, Infallible>(Response::new(Body::from(event.to_string())))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let config = kube::Config::new(format!("http://{}", addr).parse().unwrap());
+        (Client::try_from(config).unwrap(), requests)
+    }
+
+    fn denial(pod: &str, action: Action, message: &str) -> PodEvent {
+        PodEvent {
+            pod: PodRef {
+                namespace: "default".to_string(),
+                name: pod.to_string(),
+                uid: Some(format!("uid-{}", pod)),
+            },
+            kind: PodEventKind::Denial(Hook::FileOpen, action),
+            message: message.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn aggregate_denials() {
+        let (client, requests) = fake_api_server();
+        let mut recorder = KubeEventRecorder::new(client);
+        let message = "lockc denied opening /sys/kernel/debug (baseline)";
+        let new_message = "lockc denied opening /proc/kcore (baseline)";
+
+        for _ in 0..3 {
+            recorder.record(denial("nginx", Action::OpenPath, message));
+        }
+        assert_eq!(recorder.flush().await, 1);
+        // Nothing new to report.
+        assert_eq!(recorder.flush().await, 0);
+        // Denials of other paths end up in the same aggregate.
+        for _ in 0..2 {
+            recorder.record(denial("nginx", Action::OpenPath, new_message));
+        }
+        assert_eq!(recorder.flush().await, 1);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+
+        let (method, path, event) = &requests[0];
+        assert_eq!(method, Method::POST);
+        assert_eq!(path, "/api/v1/namespaces/default/events");
+        assert_eq!(event["type"], "Warning");
+        assert_eq!(event["reason"], "PolicyDenied");
+        assert_eq!(event["message"], message);
+        assert_eq!(event["count"], 3);
+        assert_eq!(event["involvedObject"]["kind"], "Pod");
+        assert_eq!(event["involvedObject"]["name"], "nginx");
+        assert_eq!(event["involvedObject"]["uid"], "uid-nginx");
+        let name = event["metadata"]["name"].as_str().unwrap();
+        assert!(name.starts_with("nginx."));
+
+        let (method, path, patch) = &requests[1];
+        assert_eq!(method, Method::PATCH);
+        assert_eq!(path, &format!("/api/v1/namespaces/default/events/{}", name));
+        assert_eq!(patch["count"], 5);
+        assert_eq!(patch["message"], new_message);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_pod() {
+        let (client, requests) = fake_api_server();
+        let mut recorder = KubeEventRecorder::new(client);
+
+        for action in [
+            Action::Syslog,
+            Action::BindMount,
+            Action::SetuidRoot,
+            Action::Setuid,
+            Action::Setgid,
+            Action::Setgroups,
+            Action::OpenDevice,
+            Action::OpenPath,
+        ] {
+            recorder.record(denial("crashloop", action, "lockc denied it"));
+        }
+        recorder.record(denial(
+            "nginx",
+            Action::Syslog,
+            "lockc denied accessing syslog",
+        ));
+
+        // Requests for the crash-looping pod are limited, the other pod is
+        // not affected.
+        assert_eq!(recorder.flush().await, REQUESTS_PER_POD + 1);
+        assert_eq!(recorder.flush().await, 8 - REQUESTS_PER_POD);
+        assert_eq!(recorder.flush().await, 0);
+        assert_eq!(requests.lock().unwrap().len(), 9);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_flush() {
+        let (client, requests) = fake_api_server();
+        let mut recorder = KubeEventRecorder::new(client);
+
+        let pods = REQUESTS_PER_FLUSH + 10;
+        for i in 0..pods {
+            recorder.record(denial(&format!("pod-{:03}", i), Action::OpenPath, "denied"));
+        }
+
+        // Pods which didn't fit into the budget are reported first in the
+        // next flush.
+        assert_eq!(recorder.flush().await, REQUESTS_PER_FLUSH);
+        recorder.record(denial("pod-000", Action::OpenPath, "denied"));
+        assert_eq!(recorder.flush().await, 11);
+        assert_eq!(recorder.flush().await, 0);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), pods + 1);
+        let (_, _, event) = &requests[REQUESTS_PER_FLUSH];
+        assert_eq!(
+            event["involvedObject"]["name"],
+            format!("pod-{:03}", REQUESTS_PER_FLUSH)
+        );
+    }
+
+    #[tokio::test]
+    async fn report_tracking_failure() {
+        let (client, requests) = fake_api_server();
+        let mut recorder = KubeEventRecorder::new(client);
+
+        let event = DaemonEvent::TrackingFailure(TrackingFailure {
+            timestamp: 1646128800,
+            container_id: "5833851e673d".to_string(),
+            policy_level: ContainerPolicyLevel::Restricted,
+            pid: 4242,
+            comm: "bash".to_string(),
+            killed: false,
+            metadata: ContainerMetadata {
+                namespace: Some("default".to_string()),
+                pod: Some("nginx".to_string()),
+                pod_uid: Some("uid-nginx".to_string()),
+                container_name: Some("web".to_string()),
+                ..Default::default()
+            },
+        });
+        recorder.record(PodEvent::from_event(&event).unwrap());
+        recorder.record(denial("nginx", Action::OpenPath, "lockc denied it"));
+        // Tracking failures and denials are reported as separate Events.
+        assert_eq!(recorder.flush().await, 2);
+
+        let requests = requests.lock().unwrap();
+        let reasons: Vec<_> = requests
+            .iter()
+            .map(|(_, _, event)| event["reason"].as_str().unwrap())
+            .collect();
+        assert_eq!(reasons, ["PolicyDenied", "ProcessUntracked"]);
+        let (_, _, event) = &requests[1];
+        assert_eq!(
+            event["message"],
+            "lockc could not register process 4242 (bash) in container web, \
+             the process is not restricted by the policy"
+        );
+    }
+}
@@ -1,12 +1,31 @@
-use std::{io, path::Path};
+use std::{
+    collections::HashSet,
+    fs, io, mem,
+    path::{Path, PathBuf},
+};
 
 use aya::{
     include_bytes_aligned,
-    programs::{BtfTracePoint, Lsm, ProgramError},
+    programs::{BtfTracePoint, Lsm, ProgramError, ProgramFd},
     Bpf, BpfError, BpfLoader, Btf, BtfError,
 };
 use thiserror::Error;
-use tracing::warn;
+use tracing::{debug, info, warn};
+
+use lockc_common::{
+    Container, ContainerGroup, ContainerID, HookStatsKey, Process, ProcessTracking, HOOKS_COUNT,
+};
+
+use crate::{
+    bpf_sys::{
+        map_create, map_info, obj_get, obj_pin, raw_tracepoint_open, MapDef, BPF_F_NO_PREALLOC,
+        BPF_MAP_TYPE_HASH, BPF_MAP_TYPE_PERCPU_HASH,
+    },
+    migrate::{resize_map, ReplacedMap},
+};
+
+/// Max number of supplementary groups of a container.
+const GROUPS_PER_CONTAINER: u32 = 32;
 
 #[derive(Error, Debug)]
 pub enum LoadError {
@@ -17,20 +36,156 @@ pub enum LoadError {
     Bpf(#[from] BpfError),
 }
 
+/// Sizes of eBPF maps, which depend on the system and configuration.
+#[derive(Debug, Clone, Copy)]
+pub struct MapSizes {
+    /// Size of maps keyed by PIDs.
+    pub processes: u32,
+    /// Size of maps keyed by containers.
+    pub containers: u32,
+}
+
+impl MapSizes {
+    /// Returns definitions of pinned maps.
+    pub fn defs(&self) -> [MapDef<'static>; 6] {
+        [
+            MapDef {
+                name: "CONTAINERS",
+                map_type: BPF_MAP_TYPE_HASH,
+                key_size: mem::size_of::<ContainerID>() as u32,
+                value_size: mem::size_of::<Container>() as u32,
+                max_entries: self.containers,
+                map_flags: 0,
+            },
+            MapDef {
+                name: "PROCESSES",
+                map_type: BPF_MAP_TYPE_HASH,
+                key_size: mem::size_of::<i32>() as u32,
+                value_size: mem::size_of::<Process>() as u32,
+                max_entries: self.processes,
+                // pid_max might be huge (up to 4194304), preallocating the
+                // whole map would waste a lot of memory.
+                map_flags: BPF_F_NO_PREALLOC,
+            },
+            MapDef {
+                name: "UNTRACKED_PROCESSES",
+                map_type: BPF_MAP_TYPE_HASH,
+                key_size: mem::size_of::<i32>() as u32,
+                value_size: mem::size_of::<Process>() as u32,
+                max_entries: self.processes,
+                map_flags: BPF_F_NO_PREALLOC,
+            },
+            MapDef {
+                name: "CONTAINER_GROUPS",
+                map_type: BPF_MAP_TYPE_HASH,
+                key_size: mem::size_of::<ContainerGroup>() as u32,
+                value_size: mem::size_of::<u8>() as u32,
+                max_entries: self.containers * GROUPS_PER_CONTAINER,
+                map_flags: BPF_F_NO_PREALLOC,
+            },
+            MapDef {
+                name: "CGROUPS",
+                map_type: BPF_MAP_TYPE_HASH,
+                key_size: mem::size_of::<u64>() as u32,
+                value_size: mem::size_of::<ContainerID>() as u32,
+                max_entries: self.containers,
+                map_flags: 0,
+            },
+            MapDef {
+                name: "HOOK_STATS",
+                map_type: BPF_MAP_TYPE_PERCPU_HASH,
+                key_size: mem::size_of::<HookStatsKey>() as u32,
+                value_size: mem::size_of::<u64>() as u32,
+                // Every hook can both allow and deny.
+                max_entries: self.containers * HOOKS_COUNT as u32 * 2,
+                // Per-CPU values of all entries would take a lot of memory.
+                map_flags: BPF_F_NO_PREALLOC,
+            },
+        ]
+    }
+}
+
+/// Creates pinned eBPF maps with the given sizes. aya reuses already pinned
+/// maps when loading the programs instead of creating them with sizes
+/// hardcoded in the eBPF object, which is the only way to size the maps at
+/// runtime. Maps pinned by the previous instance of lockc are kept, with
+/// their entries copied to a new map if the size changed. Returns the resized
+/// maps, which should be passed to
+/// [`copy_late_entries`](crate::migrate::copy_late_entries) after attaching
+/// the programs.
+pub fn create_pinned_maps<P: AsRef<Path>>(
+    path_base_r: P,
+    sizes: &MapSizes,
+) -> Result<Vec<ReplacedMap>, io::Error> {
+    let path_base = path_base_r.as_ref();
+    std::fs::create_dir_all(path_base)?;
+
+    let mut replaced = Vec::new();
+    for def in sizes.defs() {
+        let path = path_base.join(def.name);
+        if path.exists() {
+            let fd = obj_get(&path)?;
+            let info = map_info(&fd)?;
+            if info.max_entries != def.max_entries {
+                match resize_map(path_base, &def, fd) {
+                    Ok(map) => {
+                        info!(
+                            map = def.name,
+                            max_entries = info.max_entries,
+                            requested_max_entries = def.max_entries,
+                            "resized eBPF map"
+                        );
+                        replaced.push(map);
+                    }
+                    Err(e) => warn!(
+                        map = def.name,
+                        max_entries = info.max_entries,
+                        requested_max_entries = def.max_entries,
+                        error = e.to_string().as_str(),
+                        "could not resize eBPF map, keeping the old size"
+                    ),
+                }
+            }
+            continue;
+        }
+
+        let fd = map_create(&def)?;
+        obj_pin(&fd, &path)?;
+        info!(
+            map = def.name,
+            max_entries = def.max_entries,
+            "created eBPF map"
+        );
+    }
+
+    Ok(replaced)
+}
+
 /// Loads BPF programs from the object file built with clang.
-pub fn load_bpf<P: AsRef<Path>>(path_base_r: P) -> Result<Bpf, LoadError> {
+pub fn load_bpf<P: AsRef<Path>>(
+    path_base_r: P,
+    process_tracking: ProcessTracking,
+    fail_closed: bool,
+) -> Result<Bpf, LoadError> {
     let path_base = path_base_r.as_ref();
     std::fs::create_dir_all(path_base)?;
 
+    let process_tracking = process_tracking as u8;
+    let fail_closed = fail_closed as u8;
+
     #[cfg(debug_assertions)]
     let bpf = BpfLoader::new()
         .map_pin_path(path_base)
+        .set_global("PROCESS_TRACKING", &process_tracking)
+        .set_global("FAIL_CLOSED", &fail_closed)
         .load(include_bytes_aligned!(
             "../../target/bpfel-unknown-none/debug/lockc"
         ))?;
     #[cfg(not(debug_assertions))]
     let bpf = BpfLoader::new()
         .map_pin_path(path_base)
+        .set_global("PROCESS_TRACKING", &process_tracking)
+        .set_global("FAIL_CLOSED", &fail_closed)
         .load(include_bytes_aligned!(
             "../../target/bpfel-unknown-none/release/lockc"
         ))?;
@@ -40,6 +195,9 @@ pub fn load_bpf<P: AsRef<Path>>(path_base_r: P) -> Result<Bpf, LoadError> {
 
 #[derive(Error, Debug)]
 pub enum AttachError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
     #[error(transparent)]
     Btf(#[from] BtfError),
 
@@ -50,6 +208,70 @@ pub enum AttachError {
     ProgLoad,
 }
 
+/// Links of attached programs, pinned in BPFFS.
+///
+/// Programs attached by aya are detached as soon as lockc exits, which would
+/// leave the system unprotected during restarts. Pinned links keep them
+/// attached until lockc starts again and replaces them.
+struct PinnedLinks {
+    path: PathBuf,
+    attached: HashSet<String>,
+}
+
+impl PinnedLinks {
+    fn new<P: AsRef<Path>>(path_base: P) -> Result<Self, io::Error> {
+        let path = path_base.as_ref().join("links");
+        fs::create_dir_all(&path)?;
+        Ok(PinnedLinks {
+            path,
+            attached: HashSet::new(),
+        })
+    }
+
+    /// Attaches the loaded program and pins its link. If the program is
+    /// already attached by the previous instance of lockc, the old link is
+    /// replaced only after attaching the new one, so the hook is never left
+    /// without a program.
+    ///
+    /// Old programs are never reused, even when they didn't change, because
+    /// they refer to maps and global variables of the previous instance.
+    fn attach<T: ProgramFd>(&mut self, name: &str, program: &T) -> Result<(), AttachError> {
+        let prog_fd = program.fd().ok_or(AttachError::ProgLoad)?;
+        let path = self.path.join(name);
+        let tmp_path = self.path.join(format!("{}.new", name));
+
+        // Leftover after a crash during the previous replacement.
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+
+        let link = raw_tracepoint_open(prog_fd)?;
+        obj_pin(&link, &tmp_path)?;
+        let replaced = path.exists();
+        // Renaming over the old pin detaches the old program.
+        fs::rename(&tmp_path, &path)?;
+        debug!(program = name, replaced = replaced, "attached program");
+
+        self.attached.insert(name.to_string());
+        Ok(())
+    }
+
+    /// Removes links of programs which were attached by the previous
+    /// instance of lockc, but are not attached anymore (i.e. optional
+    /// programs which can't be loaded).
+    fn remove_stale(&self) -> Result<(), io::Error> {
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !self.attached.contains(&name) {
+                info!(program = name.as_str(), "detaching stale program");
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
 fn is_root_btrfs() -> bool {
     let mountinfo = std::fs::read_to_string("/proc/1/mountinfo");
     if let Ok(mountinfo) = mountinfo {
@@ -64,36 +286,50 @@ fn is_root_btrfs() -> bool {
     }
 }
 
-pub fn attach_programs(bpf: &mut Bpf) -> Result<(), AttachError> {
-    let btf = Btf::from_sys_fs()?;
-
+/// Attaches tracepoint programs which keep track of containerized processes.
+fn attach_tracepoints(
+    bpf: &mut Bpf,
+    btf: &Btf,
+    links: &mut PinnedLinks,
+) -> Result<(), AttachError> {
     let program: &mut BtfTracePoint = bpf
         .program_mut("sched_process_fork")
         .ok_or(AttachError::ProgLoad)?
         .try_into()?;
-    program.load("sched_process_fork", &btf)?;
-    program.attach()?;
+    program.load("sched_process_fork", btf)?;
+    links.attach("sched_process_fork", program)?;
 
     let program: &mut BtfTracePoint = bpf
         .program_mut("sched_process_exec")
         .ok_or(AttachError::ProgLoad)?
         .try_into()?;
-    program.load("sched_process_exec", &btf)?;
-    program.attach()?;
+    program.load("sched_process_exec", btf)?;
+    links.attach("sched_process_exec", program)?;
 
     let program: &mut BtfTracePoint = bpf
         .program_mut("sched_process_exit")
         .ok_or(AttachError::ProgLoad)?
         .try_into()?;
-    program.load("sched_process_exit", &btf)?;
-    program.attach()?;
+    program.load("sched_process_exit", btf)?;
+    links.attach("sched_process_exit", program)?;
+
+    Ok(())
+}
+
+/// Attaches all programs and pins their links in the `links` directory under
+/// the given path.
+pub fn attach_programs<P: AsRef<Path>>(bpf: &mut Bpf, path_base: P) -> Result<(), AttachError> {
+    let btf = Btf::from_sys_fs()?;
+    let mut links = PinnedLinks::new(path_base)?;
+
+    attach_tracepoints(bpf, &btf, &mut links)?;
 
     let program: &mut Lsm = bpf
         .program_mut("syslog")
         .ok_or(AttachError::ProgLoad)?
         .try_into()?;
     program.load("syslog", &btf)?;
-    program.attach()?;
+    links.attach("syslog", program)?;
 
     // NOTE(vadorovsky): Mount policies work only with BTRFS for now.
     // TODO(vadorovsky): Add support for overlayfs.
@@ -103,7 +339,7 @@ pub fn attach_programs(bpf: &mut Bpf) -> Result<(), AttachError> {
             .ok_or(AttachError::ProgLoad)?
             .try_into()?;
         program.load("sb_mount", &btf)?;
-        program.attach()?;
+        links.attach("sb_mount", program)?;
     } else {
         warn!("Root filesystem is not BTRFS, skipping mount policies");
     }
@@ -113,40 +349,212 @@ pub fn attach_programs(bpf: &mut Bpf) -> Result<(), AttachError> {
         .ok_or(AttachError::ProgLoad)?
         .try_into()?;
     program.load("task_fix_setuid", &btf)?;
-    program.attach()?;
+    links.attach("task_fix_setuid", program)?;
+
+    let program: &mut Lsm = bpf
+        .program_mut("task_fix_setgid")
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    program.load("task_fix_setgid", &btf)?;
+    links.attach("task_fix_setgid", program)?;
+
+    // The task_fix_setgroups hook is not available in older kernels.
+    let program: &mut Lsm = bpf
+        .program_mut("task_fix_setgroups")
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    match program.load("task_fix_setgroups", &btf) {
+        Ok(_) => {
+            links.attach("task_fix_setgroups", program)?;
+        }
+        Err(e) => {
+            warn!(
+                error = e.to_string().as_str(),
+                "could not load task_fix_setgroups, skipping supplementary groups policies"
+            );
+        }
+    }
+
+    let program: &mut Lsm = bpf
+        .program_mut("bprm_creds_from_file")
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    program.load("bprm_creds_from_file", &btf)?;
+    links.attach("bprm_creds_from_file", program)?;
 
     let program: &mut Lsm = bpf
         .program_mut("file_open")
         .ok_or(AttachError::ProgLoad)?
         .try_into()?;
     program.load("file_open", &btf)?;
-    program.attach()?;
+    links.attach("file_open", program)?;
+
+    let program: &mut Lsm = bpf
+        .program_mut("path_mknod")
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    program.load("path_mknod", &btf)?;
+    links.attach("path_mknod", program)?;
+
+    let program: &mut Lsm = bpf
+        .program_mut("path_chmod")
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    program.load("path_chmod", &btf)?;
+    links.attach("path_chmod", program)?;
+
+    let program: &mut Lsm = bpf
+        .program_mut("inode_setattr")
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    program.load("inode_setattr", &btf)?;
+    links.attach("inode_setattr", program)?;
+
+    let program: &mut Lsm = bpf
+        .program_mut("inode_setxattr")
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    program.load("inode_setxattr", &btf)?;
+    links.attach("inode_setxattr", program)?;
+
+    let program: &mut Lsm = bpf
+        .program_mut("unix_stream_connect")
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    program.load("unix_stream_connect", &btf)?;
+    links.attach("unix_stream_connect", program)?;
 
     let program: &mut Lsm = bpf
         .program_mut("socket_sendmsg")
         .ok_or(AttachError::ProgLoad)?
         .try_into()?;
     program.load("socket_sendmsg", &btf)?;
-    program.attach()?;
+    links.attach("socket_sendmsg", program)?;
 
     let program: &mut Lsm = bpf
         .program_mut("socket_recvmsg")
         .ok_or(AttachError::ProgLoad)?
         .try_into()?;
     program.load("socket_recvmsg", &btf)?;
-    program.attach()?;
+    links.attach("socket_recvmsg", program)?;
+
+    links.remove_stale()?;
 
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{process, sync::mpsc, thread, time::Duration};
+
+    use aya::maps::HashMap;
+    use nix::unistd::gettid;
+    use tempfile::{Builder, TempDir};
+
+    use lockc_common::{ContainerPolicyLevel, Process};
+
+    use crate::{maps::add_container, runc::ContainerCredentials};
+
     use super::*;
 
+    fn tmp_path_base() -> TempDir {
+        Builder::new()
+            .prefix("lockc-temp")
+            .rand_bytes(5)
+            .tempdir_in("/sys/fs/bpf")
+            .expect("Creating temporary dir in BPFFS failed")
+    }
+
     #[test]
     #[cfg_attr(not(feature = "tests_bpf"), ignore)]
     fn load_and_attach_bpf() {
-        let mut bpf = load_bpf("/sys/fs/bpf/lockc-test").expect("Loading BPF failed");
-        attach_programs(&mut bpf).expect("Attaching BPF programs failed");
+        let path_base = tmp_path_base();
+        let mut bpf =
+            load_bpf(&path_base, ProcessTracking::Pid, false).expect("Loading BPF failed");
+        attach_programs(&mut bpf, &path_base).expect("Attaching BPF programs failed");
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tests_bpf"), ignore)]
+    fn reattach_pinned_links() {
+        let path_base = tmp_path_base();
+        let links_path = path_base.path().join("links");
+
+        let mut bpf =
+            load_bpf(&path_base, ProcessTracking::Pid, false).expect("Loading BPF failed");
+        attach_programs(&mut bpf, &path_base).expect("Attaching BPF programs failed");
+        let links: HashSet<_> = fs::read_dir(&links_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert!(links.contains(std::ffi::OsStr::new("file_open")));
+
+        // Links of the old instance are replaced, programs stay attached
+        // after dropping the old instance.
+        let mut new_bpf =
+            load_bpf(&path_base, ProcessTracking::Pid, false).expect("Loading BPF failed");
+        attach_programs(&mut new_bpf, &path_base).expect("Reattaching BPF programs failed");
+        drop(bpf);
+        let new_links: HashSet<_> = fs::read_dir(&links_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(links, new_links);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tests_bpf"), ignore)]
+    fn threads_tracked_by_tgid() {
+        let path_base = tmp_path_base();
+        let mut bpf =
+            load_bpf(&path_base, ProcessTracking::Pid, false).expect("Loading BPF failed");
+        let btf = Btf::from_sys_fs().expect("Loading BTF failed");
+        let mut links = PinnedLinks::new(&path_base).expect("Creating links dir failed");
+        attach_tracepoints(&mut bpf, &btf, &mut links).expect("Attaching tracepoints failed");
+
+        let tgid = process::id() as i32;
+        add_container(
+            &mut bpf,
+            "5833851e673d45fab4d12105bf61c3f4892b2bbf9c12d811db509a4f22475ec9".to_string(),
+            tgid,
+            procfs::process::Process::myself()
+                .expect("Getting the current process failed")
+                .stat()
+                .expect("Reading the process stat failed")
+                .starttime,
+            ContainerPolicyLevel::Privileged,
+            ContainerCredentials::default(),
+            ProcessTracking::Pid,
+        )
+        .expect("Adding container failed");
+
+        // Spawn a lot of threads and keep them alive until all of them are
+        // checked.
+        let (tid_tx, tid_rx) = mpsc::channel();
+        let threads: Vec<_> = (0..256)
+            .map(|_| {
+                let tid_tx = tid_tx.clone();
+                thread::spawn(move || {
+                    tid_tx.send(gettid().as_raw()).unwrap();
+                    thread::sleep(Duration::from_millis(100));
+                })
+            })
+            .collect();
+        let tids: Vec<i32> = tid_rx.iter().take(threads.len()).collect();
+
+        let processes: HashMap<_, i32, Process> = bpf
+            .map("PROCESSES")
+            .expect("Getting the map failed")
+            .try_into()
+            .expect("Converting the map failed");
+        for tid in tids {
+            assert!(processes.get(&tid, 0).is_err());
+        }
+
+        // Exiting threads should not unregister the process.
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(processes.get(&tgid, 0).is_ok());
     }
 }
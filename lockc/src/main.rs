@@ -1,30 +1,64 @@
-use std::{env, fs, path, thread};
+use std::{env, fs, net::SocketAddr, path, path::PathBuf, sync::Arc, thread, time::Duration};
 
+use aya::Bpf;
 use aya_log::BpfLogger;
 use clap::{Parser, ValueEnum};
 use thiserror::Error;
 use tokio::{
     runtime::Runtime,
     sync::{mpsc, oneshot},
+    task, time,
 };
-use tracing::{debug, error, Level};
+use tracing::{debug, error, warn, Level};
 use tracing_log::LogTracer;
 use tracing_subscriber::FmtSubscriber;
 
-use lockc_common::ContainerPolicyLevel;
+use lockc_common::{
+    api::DEFAULT_SOCKET_PATH, ContainerPolicyLevel, Device, ProcessTracking, DEVICE_MINOR_ANY,
+};
 
+mod api;
+mod bpf_sys;
+mod cgroups;
 mod communication;
+mod events;
+mod gc;
+mod kube_events;
 mod load;
 mod maps;
+mod metrics;
+mod migrate;
+mod registry;
 mod runc;
 mod sysutils;
 
+use api::{handle_request, serve_api, API_CHANNEL_CAPACITY};
 use communication::EbpfCommand;
-use load::{attach_programs, load_bpf};
-use maps::{add_container, add_process, delete_container};
+use events::{
+    log_events, read_events, ContainerEvent, DaemonEvent, EventHub, EVENT_CHANNEL_CAPACITY,
+};
+use gc::{collect_garbage, GcStats};
+use kube_events::{record_kube_events, KubeEventRecorder, PodEvent, KUBE_EVENTS_CHANNEL_CAPACITY};
+use load::{attach_programs, create_pinned_maps, load_bpf, MapSizes};
+use maps::{
+    add_cgroup, add_container, add_process, delete_container, init_allowed_devices,
+    tracking_failures, update_runtime_sockets,
+};
+use metrics::{refresh_map_metrics, serve_metrics, Metrics};
+use migrate::{copy_late_entries, migrate_pinned_maps, store_schema_version};
+use registry::Registry;
 // use runc::{attach_runc_nsexec, handle_events, mark_runc_binaries};
 use runc::RuncWatcher;
-use sysutils::check_bpf_lsm_enabled;
+use sysutils::{check_bpf_lsm_enabled, pid_max, runtime_socket_inodes};
+
+/// How often the inodes of container runtime sockets are refreshed.
+const RUNTIME_SOCKETS_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often stale entries are removed from eBPF maps.
+const GC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often metrics read from eBPF maps are refreshed.
+const METRICS_REFRESH_INTERVAL: Duration = Duration::from_secs(15);
 
 #[derive(Error, Debug)]
 enum FanotifyError {
@@ -38,15 +72,26 @@ fn fanotify(
     fanotify_bootstrap_rx: oneshot::Receiver<()>,
     ebpf_tx: mpsc::Sender<EbpfCommand>,
     default_policy_level: ContainerPolicyLevel,
+    process_tracking: ProcessTracking,
+    metrics: Arc<Metrics>,
 ) -> Result<(), anyhow::Error> {
-    RuncWatcher::new(fanotify_bootstrap_rx, ebpf_tx, default_policy_level)?.work_loop()?;
+    RuncWatcher::new(
+        fanotify_bootstrap_rx,
+        ebpf_tx,
+        default_policy_level,
+        process_tracking,
+        metrics,
+    )?
+    .work_loop()?;
     Ok(())
 }
 
 /// Loads and attaches eBPF programs, then fetches logs and events from them.
 async fn ebpf(
+    opt: &Opt,
     fanotify_bootstrap_tx: oneshot::Sender<()>,
     mut ebpf_rx: mpsc::Receiver<EbpfCommand>,
+    metrics: Arc<Metrics>,
 ) -> Result<(), anyhow::Error> {
     // Check whether BPF LSM is enabled in the kernel. That check should be
     // omitted in Kubernetes (where lockc runs in a container) or nested
@@ -68,69 +113,330 @@ async fn ebpf(
         .join("lockc");
     fs::create_dir_all(&path_base)?;
 
-    let mut bpf = load_bpf(&path_base)?;
+    let pid_max_path = path::Path::new("/proc")
+        .join("sys")
+        .join("kernel")
+        .join("pid_max");
+    let map_sizes = MapSizes {
+        processes: pid_max(pid_max_path)?,
+        containers: opt.max_containers,
+    };
+    debug!(
+        processes = map_sizes.processes,
+        containers = map_sizes.containers,
+        "eBPF map sizes"
+    );
+    // Pinned maps might have been created by an older version of lockc.
+    let mut replaced_maps = migrate_pinned_maps(&path_base, &map_sizes)?;
+    replaced_maps.extend(create_pinned_maps(&path_base, &map_sizes)?);
+    store_schema_version(&path_base)?;
+
+    let mut bpf = load_bpf(&path_base, opt.process_tracking, opt.fail_closed)?;
     BpfLogger::init(&mut bpf)?;
 
+    // Containers might have been deleted while lockc was not running.
+    fs::create_dir_all(&opt.state_dir)?;
+    let (mut registry, registry_writer) = Registry::load(&opt.state_dir)?;
+    let registry_writer = tokio::spawn(registry_writer.run());
+    let removed = registry.reconcile(&bpf)?;
+    debug!(removed = removed, "container registry reconciled");
+
     // init_allowed_paths(&mut bpf, &config)?;
     debug!("allowed paths initialized");
-    attach_programs(&mut bpf)?;
+    init_allowed_devices(
+        &mut bpf,
+        &opt.allowed_devices_restricted,
+        &opt.allowed_devices_baseline,
+    )?;
+    debug!("allowed devices initialized");
+    update_runtime_sockets(&mut bpf, &runtime_socket_inodes(&opt.runtime_sockets))?;
+    debug!("runtime sockets initialized");
+    // Attaching replaces programs of the previous instance of lockc, so all
+    // maps which are not pinned have to be filled before, otherwise
+    // containers would be unrestricted until they are.
+    attach_programs(&mut bpf, &path_base)?;
     debug!("attached programs");
+    // Programs of the previous instance kept writing to the maps replaced
+    // above until now.
+    copy_late_entries(replaced_maps)?;
+
+    // Events are enriched with container metadata from the registry before
+    // being published.
+    let (raw_events_tx, mut raw_events_rx) = mpsc::channel(EVENT_CHANNEL_CAPACITY);
+    read_events(&mut bpf, raw_events_tx)?;
+    let hub = Arc::new(EventHub::default());
+    tokio::spawn(log_events(hub.subscribe()));
+    debug!("reading denial events");
+
+    let (api_tx, mut api_rx) = mpsc::channel(API_CHANNEL_CAPACITY);
+    tokio::spawn(serve_api(
+        &opt.socket,
+        opt.socket_group.as_deref(),
+        hub.clone(),
+        api_tx,
+    )?);
+    debug!(socket = ?opt.socket, "serving the local API");
+
+    if let Some(addr) = opt.metrics_addr {
+        let server = serve_metrics(&addr, metrics.clone())?;
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!(error = e.to_string().as_str(), "metrics endpoint failed");
+            }
+        });
+        tokio::spawn(refresh_map_metrics(
+            metrics.clone(),
+            path_base.clone(),
+            map_sizes,
+            METRICS_REFRESH_INTERVAL,
+        ));
+        debug!(addr = addr.to_string().as_str(), "serving metrics");
+    }
+
+    let kube_events_tx = if opt.kube_events {
+        let client = kube::Client::try_default().await?;
+        let (kube_events_tx, kube_events_rx) = mpsc::channel(KUBE_EVENTS_CHANNEL_CAPACITY);
+        tokio::spawn(record_kube_events(
+            KubeEventRecorder::new(client),
+            kube_events_rx,
+        ));
+        debug!("reporting denials as Kubernetes Events");
+        Some(kube_events_tx)
+    } else {
+        None
+    };
 
     // Bootstrap the fanotify thread.
     fanotify_bootstrap_tx
         .send(())
         .map_err(|_| FanotifyError::Send)?;
 
-    while let Some(cmd) = ebpf_rx.recv().await {
-        match cmd {
-            EbpfCommand::AddContainer {
-                container_id,
-                pid,
-                policy_level,
-                responder_tx,
-            } => {
-                let res = add_container(&mut bpf, container_id, pid, policy_level);
-                match responder_tx.send(res) {
-                    Ok(_) => {}
-                    Err(_) => error!(
-                        command = "add_container",
-                        "could not send eBPF command result although the operation was succeessful"
-                    ),
+    // Runtime sockets might be recreated (i.e. after a restart of container
+    // engine), which results in new inodes. Refresh them periodically.
+    // Walking the directories happens in a blocking task, so it doesn't hold
+    // up the loop. Only one walk runs at a time.
+    let mut runtime_sockets_interval = time::interval(RUNTIME_SOCKETS_REFRESH_INTERVAL);
+    let (runtime_sockets_tx, mut runtime_sockets_rx) = mpsc::channel(1);
+    let mut runtime_sockets_refreshing = false;
+
+    // Entries of containers and processes might be left in eBPF maps if
+    // lockc misses their deletion or exit.
+    let mut gc_interval = time::interval_at(time::Instant::now() + GC_INTERVAL, GC_INTERVAL);
+    let mut gc_stats = GcStats::default();
+    let mut tracking_failures_total = 0;
+
+    let mut metrics_interval = time::interval(METRICS_REFRESH_INTERVAL);
+
+    loop {
+        tokio::select! {
+            cmd = ebpf_rx.recv() => match cmd {
+                Some(cmd) => handle_ebpf_command(
+                    &mut bpf,
+                    &mut registry,
+                    &hub,
+                    opt.process_tracking,
+                    cmd,
+                ),
+                None => break,
+            },
+            Some(cmd) = api_rx.recv() => {
+                let reply = handle_request(
+                    &mut bpf,
+                    &mut registry,
+                    &hub,
+                    opt.process_tracking,
+                    cmd.request,
+                );
+                // The client might have disconnected.
+                let _ = cmd.responder_tx.send(reply);
+            }
+            // The branch gets disabled if all event readers fail.
+            Some(mut event) = raw_events_rx.recv() => {
+                if let Some(record) = registry.get(event.container_id()) {
+                    *event.metadata_mut() = record.metadata.clone();
+                }
+                if let DaemonEvent::Denial(denial) = &event {
+                    metrics.record_denial(denial);
+                }
+
+                let pod_event = PodEvent::from_event(&event);
+                if let (Some(kube_events_tx), Some(pod_event)) = (&kube_events_tx, pod_event) {
+                    if kube_events_tx.try_send(pod_event).is_err() {
+                        debug!("Kubernetes Event recorder is busy, dropping the event");
+                    }
                 }
+
+                hub.publish(event);
+            },
+            _ = runtime_sockets_interval.tick(), if !runtime_sockets_refreshing => {
+                runtime_sockets_refreshing = true;
+                let paths = opt.runtime_sockets.clone();
+                let runtime_sockets_tx = runtime_sockets_tx.clone();
+                task::spawn_blocking(move || {
+                    // Sending fails only if the loop has ended.
+                    let _ = runtime_sockets_tx.blocking_send(runtime_socket_inodes(&paths));
+                });
             }
-            EbpfCommand::DeleteContainer {
-                container_id,
-                responder_tx,
-            } => {
-                let res = delete_container(&mut bpf, container_id);
-                match responder_tx.send(res) {
-                    Ok(_) => {}
-                    Err(_) => error!(
-                        command = "delete_container",
-                        "could not send eBPF command result although the operation was succeessful"
-                    ),
+            Some(inodes) = runtime_sockets_rx.recv() => {
+                runtime_sockets_refreshing = false;
+                if let Err(e) = update_runtime_sockets(&mut bpf, &inodes) {
+                    error!(error = e.to_string().as_str(), "could not update runtime sockets");
                 }
             }
-            EbpfCommand::AddProcess {
-                container_id,
-                pid,
-                responder_tx,
-            } => {
-                let res = add_process(&mut bpf, container_id, pid);
-                match responder_tx.send(res) {
-                    Ok(_) => {}
-                    Err(_) => error!(
-                        command = "add_proceess",
-                        "could not send eBPF command result although the operation was succeessful"
+            _ = gc_interval.tick() => {
+                match collect_garbage(&mut bpf, &registry) {
+                    Ok(stats) => {
+                        if stats.containers > 0 {
+                            if let Err(e) = registry.reconcile(&bpf) {
+                                error!(
+                                    error = e.to_string().as_str(),
+                                    "could not reconcile the container registry"
+                                );
+                            }
+                        }
+                        gc_stats += stats;
+                        metrics.set_gc_stats(gc_stats);
+                        debug!(
+                            processes = gc_stats.processes,
+                            untracked_processes = gc_stats.untracked_processes,
+                            containers = gc_stats.containers,
+                            container_groups = gc_stats.container_groups,
+                            cgroups = gc_stats.cgroups,
+                            hook_stats = gc_stats.hook_stats,
+                            "removed stale eBPF map entries (total)"
+                        );
+                    }
+                    Err(e) => error!(
+                        error = e.to_string().as_str(),
+                        "could not remove stale eBPF map entries"
+                    ),
+                }
+                match tracking_failures(&bpf) {
+                    Ok(failures) => {
+                        if failures > tracking_failures_total {
+                            warn!(
+                                new = failures - tracking_failures_total,
+                                total = failures,
+                                "containerized processes could not be registered"
+                            );
+                        }
+                        tracking_failures_total = failures;
+                        metrics.set_tracking_failures(failures);
+                    }
+                    Err(e) => error!(
+                        error = e.to_string().as_str(),
+                        "could not read process tracking failures"
                     ),
                 }
             }
+            _ = metrics_interval.tick(), if opt.metrics_addr.is_some() => {
+                if let Err(e) = metrics.update_program_runs(&bpf) {
+                    error!(error = e.to_string().as_str(), "could not read program run counts");
+                }
+            }
         }
     }
 
+    // Let the writer store the last modifications.
+    drop(registry);
+    let _ = registry_writer.await;
+
     Ok(())
 }
 
+/// Handles the command sent to the eBPF thread.
+fn handle_ebpf_command(
+    bpf: &mut Bpf,
+    registry: &mut Registry,
+    hub: &EventHub,
+    process_tracking: ProcessTracking,
+    cmd: EbpfCommand,
+) {
+    match cmd {
+        EbpfCommand::AddContainer {
+            container_id,
+            pid,
+            start_time,
+            record,
+            credentials,
+            responder_tx,
+        } => {
+            let res = add_container(
+                bpf,
+                container_id.clone(),
+                pid,
+                start_time,
+                record.policy_level,
+                credentials,
+                process_tracking,
+            );
+            if res.is_ok() {
+                hub.publish(DaemonEvent::ContainerRegistered(ContainerEvent::new(
+                    container_id.clone(),
+                    &record,
+                )));
+                registry.insert(container_id, record);
+            }
+            match responder_tx.send(res) {
+                Ok(_) => {}
+                Err(_) => error!(
+                    command = "add_container",
+                    "could not send eBPF command result although the operation was succeessful"
+                ),
+            }
+        }
+        EbpfCommand::DeleteContainer {
+            container_id,
+            responder_tx,
+        } => {
+            let res = delete_container(bpf, container_id.clone());
+            if let Some(record) = registry.get(&container_id) {
+                hub.publish(DaemonEvent::ContainerDeleted(ContainerEvent::new(
+                    container_id.clone(),
+                    record,
+                )));
+            }
+            registry.remove(&container_id);
+            match responder_tx.send(res) {
+                Ok(_) => {}
+                Err(_) => error!(
+                    command = "delete_container",
+                    "could not send eBPF command result although the operation was succeessful"
+                ),
+            }
+        }
+        EbpfCommand::AddProcess {
+            container_id,
+            pid,
+            start_time,
+            responder_tx,
+        } => {
+            let res = add_process(bpf, container_id, pid, start_time);
+            match responder_tx.send(res) {
+                Ok(_) => {}
+                Err(_) => error!(
+                    command = "add_proceess",
+                    "could not send eBPF command result although the operation was succeessful"
+                ),
+            }
+        }
+        EbpfCommand::AddCgroup {
+            container_id,
+            cgroup_id,
+            responder_tx,
+        } => {
+            let res = add_cgroup(bpf, container_id, cgroup_id);
+            match responder_tx.send(res) {
+                Ok(_) => {}
+                Err(_) => error!(
+                    command = "add_cgroup",
+                    "could not send eBPF command result although the operation was succeessful"
+                ),
+            }
+        }
+    }
+}
+
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Opt {
@@ -149,8 +455,86 @@ struct Opt {
 
     #[clap(value_enum, long, env="LOCKC_DEFAULT_POLICY_LEVEL", default_value_t = ContainerPolicyLevel::Baseline)]
     default_policy_level: ContainerPolicyLevel,
+
+    /// Method of finding the container of a process. `pid` tracks PIDs of
+    /// containerized processes, `cgroup` uses cgroups (v2) of containers.
+    #[clap(value_enum, long, env="LOCKC_PROCESS_TRACKING", default_value_t = ProcessTracking::Pid)]
+    process_tracking: ProcessTracking,
+
+    /// Kill containerized processes which could not be registered (i.e.
+    /// because of a full eBPF map) instead of letting them escape the
+    /// policy. Applies only to `pid` process tracking.
+    #[clap(long, env = "LOCKC_FAIL_CLOSED")]
+    fail_closed: bool,
+
+    /// Directory where lockc keeps its state (i.e. the registry of
+    /// containers), which has to survive restarts.
+    #[clap(long, env = "LOCKC_STATE_DIR", default_value = "/var/lib/lockc")]
+    state_dir: PathBuf,
+
+    /// Path of the Unix socket serving the local API, used by lockctl.
+    #[clap(long, env = "LOCKC_SOCKET", default_value = DEFAULT_SOCKET_PATH)]
+    socket: PathBuf,
+
+    /// Group whose members can read from the local API (i.e. list
+    /// containers and follow events). Only root can apply changes.
+    #[clap(long, env = "LOCKC_SOCKET_GROUP")]
+    socket_group: Option<String>,
+
+    /// Address (i.e. `0.0.0.0:9090`) of the HTTP endpoint serving Prometheus
+    /// metrics on `/metrics`. Metrics are not served if not set.
+    #[clap(long, env = "LOCKC_METRICS_ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Report denials in Kubernetes pods as Warning Events on these pods.
+    #[clap(long, env = "LOCKC_KUBE_EVENTS")]
+    kube_events: bool,
+
+    /// Max number of containers which can be registered at the same time.
+    #[clap(long, env = "LOCKC_MAX_CONTAINERS", default_value_t = 8192)]
+    max_containers: u32,
+
+    /// Devices (in major:minor format, where minor can be `*`) which
+    /// restricted containers are allowed to open and create.
+    #[clap(long, env = "LOCKC_ALLOWED_DEVICES_RESTRICTED", value_delimiter = ',', default_values_t = default_allowed_devices())]
+    allowed_devices_restricted: Vec<Device>,
+
+    /// Devices (in major:minor format, where minor can be `*`) which baseline
+    /// containers are allowed to open and create.
+    #[clap(long, env = "LOCKC_ALLOWED_DEVICES_BASELINE", value_delimiter = ',', default_values_t = default_allowed_devices())]
+    allowed_devices_baseline: Vec<Device>,
+
+    /// Paths of container runtime sockets (or directories containing them)
+    /// which containers are not allowed to connect to.
+    #[clap(long, env = "LOCKC_RUNTIME_SOCKETS", value_delimiter = ',', default_values = DEFAULT_RUNTIME_SOCKETS)]
+    runtime_sockets: Vec<PathBuf>,
+}
+
+/// Returns the devices which containers need for basic functionality: null,
+/// zero, full, random, urandom, tty and pseudo-terminals.
+fn default_allowed_devices() -> Vec<Device> {
+    let mut devices = vec![
+        Device::new(1, 3), // /dev/null
+        Device::new(1, 5), // /dev/zero
+        Device::new(1, 7), // /dev/full
+        Device::new(1, 8), // /dev/random
+        Device::new(1, 9), // /dev/urandom
+        Device::new(5, 0), // /dev/tty
+        Device::new(5, 2), // /dev/ptmx
+    ];
+    // Pseudo-terminals (/dev/pts/*) use majors from 136 to 143.
+    devices.extend((136..=143).map(|major| Device::new(major, DEVICE_MINOR_ANY)));
+    devices
 }
 
+/// Default paths of container runtime sockets.
+const DEFAULT_RUNTIME_SOCKETS: [&str; 4] = [
+    "/run/containerd/containerd.sock",
+    "/var/run/docker.sock",
+    "/run/crio/crio.sock",
+    "/run/k3s",
+];
+
 #[derive(ValueEnum, Clone)]
 enum LogLevel {
     Trace,
@@ -227,9 +611,20 @@ fn main() -> Result<(), anyhow::Error> {
     // from the async eBPF thread.
     let (ebpf_tx, ebpf_rx) = mpsc::channel::<EbpfCommand>(100);
 
+    // Metrics are collected by both threads.
+    let metrics = Arc::new(Metrics::default());
+    let fanotify_metrics = metrics.clone();
+
     // Start the thread (but it's going to wait for bootstrap).
-    let fanotify_thread =
-        thread::spawn(move || fanotify(fanotify_bootstrap_rx, ebpf_tx, opt.default_policy_level));
+    let fanotify_thread = thread::spawn(move || {
+        fanotify(
+            fanotify_bootstrap_rx,
+            ebpf_tx,
+            opt.default_policy_level,
+            opt.process_tracking,
+            fanotify_metrics,
+        )
+    });
 
     // Step 2: Setup a Tokio runtime for asynchronous part of lockc, which
     // takes care of:
@@ -240,7 +635,7 @@ fn main() -> Result<(), anyhow::Error> {
 
     let rt = Runtime::new()?;
 
-    rt.block_on(ebpf(fanotify_bootstrap_tx, ebpf_rx))?;
+    rt.block_on(ebpf(&opt, fanotify_bootstrap_tx, ebpf_rx, metrics))?;
 
     if let Err(e) = fanotify_thread.join() {
         error!("failed to join the fanotify thread: {:?}", e);
@@ -1,12 +1,19 @@
+use std::collections::HashSet;
+
 use aya::{
-    maps::{HashMap, MapError},
+    maps::{HashMap, MapError, PerCpuArray, PerCpuHashMap},
     Bpf,
 };
 use config::ConfigError;
 use thiserror::Error;
 use tracing::{debug, warn};
 
-use lockc_common::{Container, ContainerID, ContainerPolicyLevel, NewContainerIDError, Process};
+use lockc_common::{
+    Container, ContainerGroup, ContainerID, ContainerPolicyLevel, Device, HookStatsKey, InodeID,
+    NewContainerIDError, Process, ProcessTracking,
+};
+
+use crate::runc::ContainerCredentials;
 
 #[derive(Error, Debug)]
 pub enum MapOperationError {
@@ -20,11 +27,74 @@ pub enum MapOperationError {
     NewContainerID(#[from] NewContainerIDError),
 }
 
+/// Fills the eBPF maps with devices allowed for restricted and baseline
+/// containers.
+pub fn init_allowed_devices(
+    bpf: &mut Bpf,
+    devices_restricted: &[Device],
+    devices_baseline: &[Device],
+) -> Result<(), MapOperationError> {
+    for (map_name, devices) in [
+        ("ALLOWED_DEVICES_RESTRICTED", devices_restricted),
+        ("ALLOWED_DEVICES_BASELINE", devices_baseline),
+    ] {
+        let mut allowed_devices: HashMap<_, Device, u8> = bpf.map_mut(map_name)?.try_into()?;
+        for device in devices {
+            debug!(
+                device = device.to_string().as_str(),
+                map = map_name,
+                "adding device to eBPF map"
+            );
+            allowed_devices.insert(*device, 0, 0)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the content of the eBPF map of container runtime socket inodes
+/// with the given inodes.
+pub fn update_runtime_sockets(
+    bpf: &mut Bpf,
+    inodes: &HashSet<InodeID>,
+) -> Result<(), MapOperationError> {
+    let mut runtime_sockets: HashMap<_, InodeID, u8> =
+        bpf.map_mut("RUNTIME_SOCKETS")?.try_into()?;
+
+    let mut to_remove = Vec::new();
+    for res in runtime_sockets.keys() {
+        let inode = res?;
+        if !inodes.contains(&inode) {
+            to_remove.push(inode);
+        }
+    }
+    for inode in to_remove {
+        runtime_sockets.remove(&inode)?;
+    }
+
+    for inode in inodes {
+        if runtime_sockets.get(inode, 0).is_err() {
+            debug!(
+                device = inode.device.to_string().as_str(),
+                ino = inode.ino,
+                map = "RUNTIME_SOCKETS",
+                "adding runtime socket to eBPF map"
+            );
+            runtime_sockets.insert(*inode, 0, 0)?;
+        }
+    }
+
+    Ok(())
+}
+
 pub fn add_container(
     bpf: &mut Bpf,
     container_id: String,
     pid: i32,
+    start_time: u64,
     policy_level: ContainerPolicyLevel,
+    credentials: ContainerCredentials,
+    process_tracking: ProcessTracking,
 ) -> Result<(), MapOperationError> {
     debug!(
         container = container_id.as_str(),
@@ -37,14 +107,33 @@ pub fn add_container(
     let mut containers: HashMap<_, ContainerID, Container> =
         bpf.map_mut("CONTAINERS")?.try_into()?;
     let container_key = ContainerID::new(&container_id)?;
-    let container = Container { policy_level };
+    let container = Container {
+        policy_level,
+        uid_range: credentials.uid_range,
+        gid_range: credentials.gid_range,
+    };
     containers.insert(container_key, container, 0)?;
 
-    let mut processes: HashMap<_, i32, Process> = bpf.map_mut("PROCESSES")?.try_into()?;
-    let process = Process {
-        container_id: container_key,
-    };
-    processes.insert(pid, process, 0)?;
+    let mut container_groups: HashMap<_, ContainerGroup, u8> =
+        bpf.map_mut("CONTAINER_GROUPS")?.try_into()?;
+    for gid in credentials.additional_gids {
+        let group = ContainerGroup {
+            container_id: container_key,
+            gid,
+        };
+        container_groups.insert(group, 0, 0)?;
+    }
+
+    // With cgroup tracking, eBPF programs find the container by its cgroup,
+    // which is registered once the container starts.
+    if let ProcessTracking::Pid = process_tracking {
+        let mut processes: HashMap<_, i32, Process> = bpf.map_mut("PROCESSES")?.try_into()?;
+        let process = Process {
+            container_id: container_key,
+            start_time,
+        };
+        processes.insert(pid, process, 0)?;
+    }
 
     Ok(())
 }
@@ -73,6 +162,44 @@ pub fn delete_container(bpf: &mut Bpf, container_id: String) -> Result<(), MapOp
         }
     }
 
+    let mut container_groups: HashMap<_, ContainerGroup, u8> =
+        bpf.map_mut("CONTAINER_GROUPS")?.try_into()?;
+    let mut to_remove = Vec::new();
+    for res in container_groups.keys() {
+        let group = res?;
+        if group.container_id.id == container_key.id {
+            to_remove.push(group);
+        }
+    }
+    for group in to_remove {
+        container_groups.remove(&group)?;
+    }
+
+    let mut cgroups: HashMap<_, u64, ContainerID> = bpf.map_mut("CGROUPS")?.try_into()?;
+    let mut to_remove = Vec::new();
+    for res in cgroups.iter() {
+        let (cgroup_id, cgroup_container_id) = res?;
+        if cgroup_container_id.id == container_key.id {
+            to_remove.push(cgroup_id);
+        }
+    }
+    for cgroup_id in to_remove {
+        cgroups.remove(&cgroup_id)?;
+    }
+
+    let mut hook_stats: PerCpuHashMap<_, HookStatsKey, u64> =
+        bpf.map_mut("HOOK_STATS")?.try_into()?;
+    let mut to_remove = Vec::new();
+    for res in hook_stats.keys() {
+        let key = res?;
+        if key.container_id.id == container_key.id {
+            to_remove.push(key);
+        }
+    }
+    for key in to_remove {
+        hook_stats.remove(&key)?;
+    }
+
     // TODO(vadorovsky): Add iter_mut() to HashMap in aya. Due to lack of it,
     // we cannot remove elements immediately when iterating, because iter()
     // borrows the HashMap immutably.
@@ -92,7 +219,12 @@ pub fn delete_container(bpf: &mut Bpf, container_id: String) -> Result<(), MapOp
     Ok(())
 }
 
-pub fn add_process(bpf: &mut Bpf, container_id: String, pid: i32) -> Result<(), MapOperationError> {
+pub fn add_process(
+    bpf: &mut Bpf,
+    container_id: String,
+    pid: i32,
+    start_time: u64,
+) -> Result<(), MapOperationError> {
     debug!(
         pid = pid,
         container = container_id.as_str(),
@@ -104,16 +236,102 @@ pub fn add_process(bpf: &mut Bpf, container_id: String, pid: i32) -> Result<(),
     let container_key = ContainerID::new(&container_id)?;
     let process = Process {
         container_id: container_key,
+        start_time,
     };
     processes.insert(pid, process, 0)?;
 
     Ok(())
 }
 
+pub fn add_cgroup(
+    bpf: &mut Bpf,
+    container_id: String,
+    cgroup_id: u64,
+) -> Result<(), MapOperationError> {
+    debug!(
+        cgroup_id = cgroup_id,
+        container = container_id.as_str(),
+        map = "CGROUPS",
+        "adding cgroup to eBPF map",
+    );
+
+    let mut cgroups: HashMap<_, u64, ContainerID> = bpf.map_mut("CGROUPS")?.try_into()?;
+    let container_key = ContainerID::new(&container_id)?;
+    cgroups.insert(cgroup_id, container_key, 0)?;
+
+    Ok(())
+}
+
+/// Changes the policy level of the registered container.
+pub fn set_policy_level(
+    bpf: &mut Bpf,
+    container_id: &str,
+    policy_level: ContainerPolicyLevel,
+) -> Result<(), MapOperationError> {
+    debug!(
+        container = container_id,
+        policy_level = policy_level.to_string().as_str(),
+        map = "CONTAINERS",
+        "changing policy level in eBPF map",
+    );
+
+    let mut containers: HashMap<_, ContainerID, Container> =
+        bpf.map_mut("CONTAINERS")?.try_into()?;
+    let container_key = ContainerID::new(container_id)?;
+    let mut container = containers.get(&container_key, 0)?;
+    container.policy_level = policy_level;
+    containers.insert(container_key, container, 0)?;
+
+    Ok(())
+}
+
+/// Returns IDs of all registered containers with their entries.
+pub fn containers(bpf: &Bpf) -> Result<Vec<(String, Container)>, MapOperationError> {
+    let containers: HashMap<_, ContainerID, Container> = bpf.map("CONTAINERS")?.try_into()?;
+    let mut res = Vec::new();
+    for entry in containers.iter() {
+        let (container_id, container) = entry?;
+        if let Ok(id) = container_id.as_str() {
+            res.push((id.trim_end_matches('\0').to_string(), container));
+        }
+    }
+    Ok(res)
+}
+
+/// Returns PIDs of all registered processes with their entries.
+pub fn processes(bpf: &Bpf) -> Result<Vec<(i32, Process)>, MapOperationError> {
+    let processes: HashMap<_, i32, Process> = bpf.map("PROCESSES")?.try_into()?;
+    let mut res = Vec::new();
+    for entry in processes.iter() {
+        res.push(entry?);
+    }
+    Ok(res)
+}
+
+/// Returns counts of hook decisions, summed across CPUs.
+pub fn hook_stats(bpf: &Bpf) -> Result<Vec<(HookStatsKey, u64)>, MapOperationError> {
+    let hook_stats: PerCpuHashMap<_, HookStatsKey, u64> = bpf.map("HOOK_STATS")?.try_into()?;
+    let mut res = Vec::new();
+    for entry in hook_stats.iter() {
+        let (key, values) = entry?;
+        res.push((key, values.iter().sum()));
+    }
+    Ok(res)
+}
+
+/// Returns the number of containerized processes which eBPF programs could
+/// not register, summed across CPUs.
+pub fn tracking_failures(bpf: &Bpf) -> Result<u64, MapOperationError> {
+    let failures: PerCpuArray<_, u64> = bpf.map("TRACKING_FAILURES")?.try_into()?;
+    Ok(failures.get(&0, 0)?.iter().sum())
+}
+
 #[cfg(test)]
 mod tests {
     use tempfile::{Builder, TempDir};
 
+    use lockc_common::DEVICE_MINOR_ANY;
+
     use crate::load::load_bpf;
 
     use super::*;
@@ -130,13 +348,35 @@ mod tests {
     #[cfg_attr(not(feature = "tests_bpf"), ignore)]
     fn test_add_container() {
         let path_base = tmp_path_base();
-        let mut bpf = load_bpf(path_base).expect("Loading BPF failed");
+        let mut bpf = load_bpf(path_base, ProcessTracking::Pid, false).expect("Loading BPF failed");
         add_container(
             &mut bpf,
             "5833851e673d45fab4d12105bf61c3f4892b2bbf9c12d811db509a4f22475ec9".to_string(),
             42069,
+            0,
             ContainerPolicyLevel::Baseline,
+            ContainerCredentials::default(),
+            ProcessTracking::Pid,
         )
         .expect("Adding container failed");
     }
+
+    #[test]
+    #[cfg_attr(not(feature = "tests_bpf"), ignore)]
+    fn test_init_allowed_devices() {
+        let path_base = tmp_path_base();
+        let mut bpf = load_bpf(path_base, ProcessTracking::Pid, false).expect("Loading BPF failed");
+        let devices = [Device::new(1, 3), Device::new(136, DEVICE_MINOR_ANY)];
+        init_allowed_devices(&mut bpf, &devices, &devices)
+            .expect("Initializing allowed devices failed");
+
+        let allowed_devices: HashMap<_, Device, u8> = bpf
+            .map("ALLOWED_DEVICES_RESTRICTED")
+            .expect("Getting the map failed")
+            .try_into()
+            .expect("Converting the map failed");
+        for device in devices {
+            assert!(allowed_devices.get(&device, 0).is_ok());
+        }
+    }
 }
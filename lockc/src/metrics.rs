@@ -0,0 +1,500 @@
+//! Prometheus metrics of the daemon, served over HTTP in the text exposition
+//! format.
+//!
+//! Metrics are collected from the command loop (denial events, container
+//! registry, garbage collection), the runc watcher and eBPF maps.
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Write,
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use aya::{
+    maps::{MapError, PerCpuArray},
+    Bpf,
+};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use tokio::{task, time};
+use tracing::error;
+
+use lockc_common::Hook;
+
+use crate::{
+    bpf_sys::{map_get_next_key, obj_get},
+    events::Event,
+    gc::GcStats,
+    load::MapSizes,
+};
+
+/// Upper bounds (in seconds) of buckets of the runc event handling latency
+/// histogram.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Histogram with cumulative buckets, as expected by Prometheus.
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Number of entries and size of the eBPF map.
+#[derive(Debug, Default, Clone, Copy)]
+struct MapFill {
+    entries: u64,
+    max_entries: u32,
+}
+
+#[derive(Debug, Default)]
+struct MetricsData {
+    /// Denials by hook, policy level and namespace.
+    denials: BTreeMap<(String, String, String), u64>,
+    maps: BTreeMap<&'static str, MapFill>,
+    program_runs: BTreeMap<String, u64>,
+    runc_event_duration: Histogram,
+    kubernetes_lookup_errors: u64,
+    tracking_failures: u64,
+    gc: GcStats,
+}
+
+/// Metrics shared between the command loop, the runc watcher and the HTTP
+/// endpoint.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    data: Mutex<MetricsData>,
+}
+
+/// Escapes the label value according to the text exposition format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Writes the HELP and TYPE lines of the metric.
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Counts entries of the pinned eBPF map with the given key size.
+fn map_len<P: AsRef<Path>>(path: P, key_size: u32) -> Result<u64, io::Error> {
+    let fd = obj_get(path)?;
+    let mut len = 0;
+    let mut key: Option<Vec<u8>> = None;
+    loop {
+        let mut next_key = vec![0u8; key_size as usize];
+        if !map_get_next_key(&fd, key.as_deref(), &mut next_key)? {
+            break;
+        }
+        len += 1;
+        key = Some(next_key);
+    }
+    Ok(len)
+}
+
+impl Metrics {
+    /// Counts the denial event. Namespace is known only for Kubernetes
+    /// containers.
+    pub fn record_denial(&self, event: &Event) {
+        let key = (
+            event.hook.to_string(),
+            event.policy_level.to_string(),
+            event.metadata.namespace.clone().unwrap_or_default(),
+        );
+        let mut data = self.data.lock().unwrap();
+        *data.denials.entry(key).or_default() += 1;
+    }
+
+    /// Records the time of handling a runc (fanotify) event.
+    pub fn observe_runc_event(&self, duration: Duration) {
+        let mut data = self.data.lock().unwrap();
+        data.runc_event_duration.observe(duration.as_secs_f64());
+    }
+
+    pub fn inc_kubernetes_lookup_errors(&self) {
+        let mut data = self.data.lock().unwrap();
+        data.kubernetes_lookup_errors += 1;
+    }
+
+    /// Sets the number of containerized processes which eBPF programs could
+    /// not register.
+    pub fn set_tracking_failures(&self, failures: u64) {
+        let mut data = self.data.lock().unwrap();
+        data.tracking_failures = failures;
+    }
+
+    /// Sets the total numbers of entries removed by the garbage collector.
+    pub fn set_gc_stats(&self, stats: GcStats) {
+        let mut data = self.data.lock().unwrap();
+        data.gc = stats;
+    }
+
+    /// Refreshes the number of entries of pinned eBPF maps.
+    pub fn update_maps<P: AsRef<Path>>(
+        &self,
+        path_base: P,
+        sizes: &MapSizes,
+    ) -> Result<(), io::Error> {
+        let mut maps = BTreeMap::new();
+        for def in sizes.defs() {
+            let entries = map_len(path_base.as_ref().join(def.name), def.key_size)?;
+            maps.insert(
+                def.name,
+                MapFill {
+                    entries,
+                    max_entries: def.max_entries,
+                },
+            );
+        }
+        let mut data = self.data.lock().unwrap();
+        data.maps = maps;
+        Ok(())
+    }
+
+    /// Refreshes the run counts of eBPF programs, summed across CPUs.
+    pub fn update_program_runs(&self, bpf: &Bpf) -> Result<(), MapError> {
+        let runs: PerCpuArray<_, u64> = bpf.map("PROGRAM_RUNS")?.try_into()?;
+        let mut program_runs = BTreeMap::new();
+        for hook in Hook::ALL {
+            let values = runs.get(&(hook as u32), 0)?;
+            program_runs.insert(hook.to_string(), values.iter().sum());
+        }
+        let mut data = self.data.lock().unwrap();
+        data.program_runs = program_runs;
+        Ok(())
+    }
+
+    /// Renders all metrics in the text exposition format.
+    pub fn render(&self) -> String {
+        let data = self.data.lock().unwrap();
+        let mut out = String::new();
+
+        // Writing to a String never fails, results of `writeln!` are ignored.
+        header(
+            &mut out,
+            "lockc_denials_total",
+            "counter",
+            "Number of actions denied by LSM programs.",
+        );
+        for ((hook, policy_level, namespace), count) in data.denials.iter() {
+            let _ = writeln!(
+                out,
+                "lockc_denials_total{{hook=\"{}\",policy_level=\"{}\",namespace=\"{}\"}} {}",
+                escape_label(hook),
+                escape_label(policy_level),
+                escape_label(namespace),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "lockc_containers",
+            "gauge",
+            "Number of registered containers.",
+        );
+        let containers = data.maps.get("CONTAINERS").copied().unwrap_or_default();
+        let _ = writeln!(out, "lockc_containers {}", containers.entries);
+
+        header(
+            &mut out,
+            "lockc_processes",
+            "gauge",
+            "Number of registered processes.",
+        );
+        let processes = data.maps.get("PROCESSES").copied().unwrap_or_default();
+        let _ = writeln!(out, "lockc_processes {}", processes.entries);
+
+        header(
+            &mut out,
+            "lockc_map_entries",
+            "gauge",
+            "Number of entries in pinned eBPF maps.",
+        );
+        for (map, fill) in data.maps.iter() {
+            let _ = writeln!(out, "lockc_map_entries{{map=\"{}\"}} {}", map, fill.entries);
+        }
+        header(
+            &mut out,
+            "lockc_map_max_entries",
+            "gauge",
+            "Max number of entries in pinned eBPF maps.",
+        );
+        for (map, fill) in data.maps.iter() {
+            let _ = writeln!(
+                out,
+                "lockc_map_max_entries{{map=\"{}\"}} {}",
+                map, fill.max_entries
+            );
+        }
+        header(
+            &mut out,
+            "lockc_map_fill_ratio",
+            "gauge",
+            "Fill ratio of pinned eBPF maps.",
+        );
+        for (map, fill) in data.maps.iter() {
+            let ratio = match fill.max_entries {
+                0 => 0.0,
+                max_entries => fill.entries as f64 / max_entries as f64,
+            };
+            let _ = writeln!(out, "lockc_map_fill_ratio{{map=\"{}\"}} {}", map, ratio);
+        }
+
+        header(
+            &mut out,
+            "lockc_runc_event_duration_seconds",
+            "histogram",
+            "Time of handling runc executions.",
+        );
+        let histogram = &data.runc_event_duration;
+        for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "lockc_runc_event_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, bucket
+            );
+        }
+        let _ = writeln!(
+            out,
+            "lockc_runc_event_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "lockc_runc_event_duration_seconds_sum {}",
+            histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "lockc_runc_event_duration_seconds_count {}",
+            histogram.count
+        );
+
+        header(
+            &mut out,
+            "lockc_kubernetes_lookup_errors_total",
+            "counter",
+            "Number of failed lookups of Kubernetes namespaces.",
+        );
+        let _ = writeln!(
+            out,
+            "lockc_kubernetes_lookup_errors_total {}",
+            data.kubernetes_lookup_errors
+        );
+
+        header(
+            &mut out,
+            "lockc_program_runs_total",
+            "counter",
+            "Number of runs of LSM programs.",
+        );
+        for (hook, runs) in data.program_runs.iter() {
+            let _ = writeln!(
+                out,
+                "lockc_program_runs_total{{hook=\"{}\"}} {}",
+                hook, runs
+            );
+        }
+
+        header(
+            &mut out,
+            "lockc_process_tracking_failures_total",
+            "counter",
+            "Number of containerized processes which could not be registered.",
+        );
+        let _ = writeln!(
+            out,
+            "lockc_process_tracking_failures_total {}",
+            data.tracking_failures
+        );
+
+        header(
+            &mut out,
+            "lockc_gc_removed_total",
+            "counter",
+            "Number of stale eBPF map entries removed by the garbage collector.",
+        );
+        for (kind, removed) in [
+            ("processes", data.gc.processes),
+            ("untracked_processes", data.gc.untracked_processes),
+            ("containers", data.gc.containers),
+            ("container_groups", data.gc.container_groups),
+            ("cgroups", data.gc.cgroups),
+            ("hook_stats", data.gc.hook_stats),
+        ] {
+            let _ = writeln!(
+                out,
+                "lockc_gc_removed_total{{kind=\"{}\"}} {}",
+                kind, removed
+            );
+        }
+
+        out
+    }
+}
+
+/// Refreshes the number of entries of pinned eBPF maps periodically.
+/// Counting walks over all keys of every map, so it's done in a blocking
+/// task, away from the command loop.
+pub async fn refresh_map_metrics(
+    metrics: Arc<Metrics>,
+    path_base: PathBuf,
+    sizes: MapSizes,
+    period: Duration,
+) {
+    let mut interval = time::interval(period);
+    loop {
+        interval.tick().await;
+        let metrics = metrics.clone();
+        let path_base = path_base.clone();
+        match task::spawn_blocking(move || metrics.update_maps(&path_base, &sizes)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!(
+                error = e.to_string().as_str(),
+                "could not count eBPF map entries"
+            ),
+            Err(e) => error!(
+                error = e.to_string().as_str(),
+                "counting eBPF map entries failed"
+            ),
+        }
+    }
+}
+
+fn handle_request(req: Request<Body>, metrics: &Metrics) -> Response<Body> {
+    let mut response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let mut response = Response::new(Body::from(metrics.render()));
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+            return response;
+        }
+        _ => Response::new(Body::empty()),
+    };
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
+
+/// Binds the HTTP endpoint serving metrics on `/metrics`. Returns the server
+/// future, which has to be spawned.
+pub fn serve_metrics(
+    addr: &SocketAddr,
+    metrics: Arc<Metrics>,
+) -> Result<impl Future<Output = Result<(), hyper::Error>>, hyper::Error> {
+    let make_svc = make_service_fn(move |_conn| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(handle_request(req, &metrics)) }
+            }))
+        }
+    });
+    Ok(Server::try_bind(addr)?.serve(make_svc))
+}
+
+#[cfg(test)]
+mod tests {
+    use lockc_common::{Action, ContainerPolicyLevel, Decision};
+
+    use crate::registry::ContainerMetadata;
+
+    use super::*;
+
+    fn event(hook: Hook, namespace: Option<&str>) -> Event {
+        Event {
+            timestamp: 0,
+            container_id: "5833851e673d".to_string(),
+            hook,
+            action: Action::OpenPath,
+            decision: Decision::Deny,
+            policy_level: ContainerPolicyLevel::Baseline,
+            pid: 4242,
+            tgid: 4242,
+            uid: 0,
+            comm: "cat".to_string(),
+            device: None,
+            value: None,
+            path: Some("/proc/acpi".to_string()),
+            address: None,
+            metadata: ContainerMetadata {
+                namespace: namespace.map(String::from),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn render_denials() {
+        let metrics = Metrics::default();
+        metrics.record_denial(&event(Hook::FileOpen, Some("default")));
+        metrics.record_denial(&event(Hook::FileOpen, Some("default")));
+        metrics.record_denial(&event(Hook::SbMount, None));
+
+        let out = metrics.render();
+        assert!(out.contains(
+            "lockc_denials_total{hook=\"file_open\",policy_level=\"baseline\",namespace=\"default\"} 2\n"
+        ));
+        assert!(out.contains(
+            "lockc_denials_total{hook=\"sb_mount\",policy_level=\"baseline\",namespace=\"\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn render_histogram() {
+        let metrics = Metrics::default();
+        metrics.observe_runc_event(Duration::from_millis(20));
+        metrics.observe_runc_event(Duration::from_secs(20));
+
+        let out = metrics.render();
+        assert!(out.contains("lockc_runc_event_duration_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(out.contains("lockc_runc_event_duration_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(out.contains("lockc_runc_event_duration_seconds_bucket{le=\"10\"} 1\n"));
+        assert!(out.contains("lockc_runc_event_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(out.contains("lockc_runc_event_duration_seconds_count 2\n"));
+    }
+
+    #[test]
+    fn render_tracking_failures() {
+        let metrics = Metrics::default();
+        metrics.set_tracking_failures(3);
+
+        let out = metrics.render();
+        assert!(out.contains("# TYPE lockc_process_tracking_failures_total counter\n"));
+        assert!(out.contains("lockc_process_tracking_failures_total 3\n"));
+    }
+
+    #[test]
+    fn label_escaping() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
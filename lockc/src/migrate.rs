@@ -0,0 +1,562 @@
+//! Versioning of the layout of pinned eBPF maps and migration of their
+//! entries after upgrading lockc.
+//!
+//! Pinned maps are reused by new versions of lockc. If the layout of types
+//! stored in them changes, the entries have to be converted and moved to new
+//! maps before loading the eBPF programs. The version of the layout is
+//! stored in a separate pinned map.
+//!
+//! The eBPF programs of the previous instance of lockc stay attached until
+//! the new ones are, so they keep writing to the replaced maps in the
+//! meantime. Entries they add are copied by [`copy_late_entries`] once the
+//! new programs are attached.
+
+use std::{fs, io, mem, os::unix::io::OwnedFd, path::Path, slice};
+
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use lockc_common::{
+    Container, ContainerID, ContainerPolicyLevel, IdRange, Process, MAPS_SCHEMA_VERSION,
+};
+
+use crate::{
+    bpf_sys::{
+        map_create, map_get_next_key, map_info, map_insert_elem, map_lookup_elem, map_update_elem,
+        obj_get, obj_pin, MapDef, MapInfo, BPF_MAP_TYPE_ARRAY, BPF_MAP_TYPE_PERCPU_HASH,
+    },
+    load::MapSizes,
+    sysutils::possible_cpus,
+};
+
+/// Name of the pinned map which stores the schema version.
+static SCHEMA_MAP: &str = "LOCKC_SCHEMA";
+
+/// Key of the schema version in the schema map.
+const SCHEMA_VERSION_KEY: u32 = 0;
+
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
+    #[error("pinned maps have schema version {version}, which is newer than supported by this lockc version ({supported}), remove them to downgrade")]
+    NewerSchema { version: u32, supported: u32 },
+
+    #[error("could not migrate pinned map {map} with key size {key_size} and value size {value_size}, remove it to start from scratch")]
+    Unsupported {
+        map: &'static str,
+        key_size: u32,
+        value_size: u32,
+    },
+}
+
+/// Raw key and value of a map entry.
+type Entry = (Vec<u8>, Vec<u8>);
+
+/// Conversion of entries of a pinned map with an older layout.
+struct Migration {
+    map: &'static str,
+    /// Schema version of the older layout. Version 0 stands for maps pinned
+    /// before introducing schema versions.
+    from_version: u32,
+    /// Key size of the older layout.
+    key_size: u32,
+    /// Value size of the older layout.
+    value_size: u32,
+    /// Converts the old key and value into the current ones. Returns `None`
+    /// if the entry should be dropped.
+    convert: fn(&[u8], &[u8]) -> Option<Entry>,
+}
+
+/// Migrations of pinned maps, identified by the map and the schema version
+/// they were pinned with. Every migration converts entries straight into the
+/// current layout.
+static MIGRATIONS: &[Migration] = &[
+    // Before introducing schema versions, `Container` contained only the
+    // policy level and `Process` contained only the container ID.
+    Migration {
+        map: "CONTAINERS",
+        from_version: 0,
+        key_size: mem::size_of::<ContainerID>() as u32,
+        value_size: mem::size_of::<i32>() as u32,
+        convert: convert_container_v0,
+    },
+    Migration {
+        map: "PROCESSES",
+        from_version: 0,
+        key_size: mem::size_of::<i32>() as u32,
+        value_size: mem::size_of::<ContainerID>() as u32,
+        convert: convert_process_v0,
+    },
+];
+
+fn as_bytes<T>(value: &T) -> Vec<u8> {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }.to_vec()
+}
+
+fn policy_level_from_raw(raw: i32) -> Option<ContainerPolicyLevel> {
+    match raw {
+        -1 => Some(ContainerPolicyLevel::NotFound),
+        0 => Some(ContainerPolicyLevel::Lockc),
+        1 => Some(ContainerPolicyLevel::Restricted),
+        2 => Some(ContainerPolicyLevel::Offline),
+        3 => Some(ContainerPolicyLevel::Baseline),
+        4 => Some(ContainerPolicyLevel::Privileged),
+        _ => None,
+    }
+}
+
+/// Converts the container with only the policy level. Credentials were not
+/// enforced, so the container is allowed to use all IDs.
+fn convert_container_v0(key: &[u8], value: &[u8]) -> Option<Entry> {
+    let policy_level = policy_level_from_raw(i32::from_ne_bytes(value.try_into().ok()?))?;
+    let container = Container {
+        policy_level,
+        uid_range: IdRange::ANY,
+        gid_range: IdRange::ANY,
+    };
+    Some((key.to_vec(), as_bytes(&container)))
+}
+
+/// Converts the process without the start time. The start time is read from
+/// procfs, processes which are not running anymore are dropped.
+fn convert_process_v0(key: &[u8], value: &[u8]) -> Option<Entry> {
+    let pid = i32::from_ne_bytes(key.try_into().ok()?);
+    let stat = procfs::process::Process::new(pid)
+        .and_then(|p| p.stat())
+        .ok()?;
+    let process = Process {
+        container_id: ContainerID {
+            id: value.try_into().ok()?,
+        },
+        start_time: stat.starttime,
+    };
+    Some((key.to_vec(), as_bytes(&process)))
+}
+
+fn schema_map_def() -> MapDef<'static> {
+    MapDef {
+        name: SCHEMA_MAP,
+        map_type: BPF_MAP_TYPE_ARRAY,
+        key_size: mem::size_of::<u32>() as u32,
+        value_size: mem::size_of::<u32>() as u32,
+        max_entries: 1,
+        map_flags: 0,
+    }
+}
+
+/// Reads the schema version of pinned maps. Returns `None` if maps were
+/// pinned by lockc without schema versioning (or not pinned at all).
+fn schema_version<P: AsRef<Path>>(path_base: P) -> Result<Option<u32>, io::Error> {
+    let path = path_base.as_ref().join(SCHEMA_MAP);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let fd = obj_get(path)?;
+    let mut version = [0u8; mem::size_of::<u32>()];
+    map_lookup_elem(&fd, &SCHEMA_VERSION_KEY.to_ne_bytes(), &mut version)?;
+    Ok(Some(u32::from_ne_bytes(version)))
+}
+
+/// Stores the current schema version in the pinned schema map. Should be
+/// called after creating all pinned maps.
+pub fn store_schema_version<P: AsRef<Path>>(path_base: P) -> Result<(), io::Error> {
+    let path = path_base.as_ref().join(SCHEMA_MAP);
+    let fd = if path.exists() {
+        obj_get(&path)?
+    } else {
+        let fd = map_create(&schema_map_def())?;
+        obj_pin(&fd, &path)?;
+        fd
+    };
+    map_update_elem(
+        &fd,
+        &SCHEMA_VERSION_KEY.to_ne_bytes(),
+        &MAPS_SCHEMA_VERSION.to_ne_bytes(),
+    )
+}
+
+/// Returns all entries of the given map.
+fn map_entries(fd: &OwnedFd, key_size: u32, value_size: u32) -> Result<Vec<Entry>, io::Error> {
+    let mut entries = Vec::new();
+    let mut key: Option<Vec<u8>> = None;
+    loop {
+        let mut next_key = vec![0u8; key_size as usize];
+        if !map_get_next_key(fd, key.as_deref(), &mut next_key)? {
+            break;
+        }
+        let mut value = vec![0u8; value_size as usize];
+        // The entry might have been removed in the meantime.
+        if map_lookup_elem(fd, &next_key, &mut value)? {
+            entries.push((next_key.clone(), value));
+        }
+        key = Some(next_key);
+    }
+    Ok(entries)
+}
+
+/// Returns the size of the buffer for values of the map with the given
+/// definition. Values of per-CPU maps are read and written for all possible
+/// CPUs at once, each of them padded to 8 bytes.
+fn value_buf_size(def: &MapDef, value_size: u32) -> Result<u32, io::Error> {
+    match def.map_type {
+        BPF_MAP_TYPE_PERCPU_HASH => {
+            let cpus = possible_cpus("/sys/devices/system/cpu/possible")?;
+            Ok(((value_size + 7) & !7) * cpus)
+        }
+        _ => Ok(value_size),
+    }
+}
+
+/// Pinned map replaced by a new one, which might still be written by eBPF
+/// programs of the previous instance of lockc.
+pub struct ReplacedMap {
+    name: &'static str,
+    old_fd: OwnedFd,
+    new_fd: OwnedFd,
+    key_size: u32,
+    /// Size of the buffer for values of the old map.
+    value_size: u32,
+    convert: fn(&[u8], &[u8]) -> Option<Entry>,
+}
+
+/// Moves entries of the pinned map to a new map with the current layout and
+/// replaces the pin.
+fn migrate_map(
+    path_base: &Path,
+    def: &MapDef<'static>,
+    old_fd: OwnedFd,
+    migration: &Migration,
+) -> Result<ReplacedMap, io::Error> {
+    let new_fd = map_create(def)?;
+    let mut migrated = 0;
+    let mut dropped = 0;
+    let value_size = value_buf_size(def, migration.value_size)?;
+    for (key, value) in map_entries(&old_fd, migration.key_size, value_size)? {
+        match (migration.convert)(&key, &value) {
+            Some((key, value)) => {
+                map_update_elem(&new_fd, &key, &value)?;
+                migrated += 1;
+            }
+            None => dropped += 1,
+        }
+    }
+
+    let path = path_base.join(def.name);
+    let tmp_path = path_base.join(format!("{}.new", def.name));
+    if tmp_path.exists() {
+        fs::remove_file(&tmp_path)?;
+    }
+    obj_pin(&new_fd, &tmp_path)?;
+    fs::rename(&tmp_path, &path)?;
+
+    info!(
+        map = def.name,
+        migrated = migrated,
+        dropped = dropped,
+        "migrated eBPF map"
+    );
+    Ok(ReplacedMap {
+        name: def.name,
+        old_fd,
+        new_fd,
+        key_size: migration.key_size,
+        value_size,
+        convert: migration.convert,
+    })
+}
+
+/// Copies entries added to the replaced maps by eBPF programs of the previous
+/// instance of lockc after their entries were moved. Has to be called after
+/// attaching the new programs, which detaches the old ones. Entries already
+/// present in the new maps are kept, since the new programs might have
+/// updated them. Entries removed from the old maps in the meantime are left
+/// to the start time checks and the garbage collection.
+pub fn copy_late_entries(maps: Vec<ReplacedMap>) -> Result<(), io::Error> {
+    for map in maps {
+        let mut copied = 0;
+        let mut dropped = 0;
+        for (key, value) in map_entries(&map.old_fd, map.key_size, map.value_size)? {
+            let (key, value) = match (map.convert)(&key, &value) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            match map_insert_elem(&map.new_fd, &key, &value) {
+                Ok(true) => copied += 1,
+                Ok(false) => {}
+                // The new map might be smaller and full already.
+                Err(e) if e.raw_os_error() == Some(libc::E2BIG) => dropped += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        if copied > 0 {
+            info!(
+                map = map.name,
+                copied = copied,
+                "copied eBPF map entries added during migration"
+            );
+        }
+        if dropped > 0 {
+            warn!(
+                map = map.name,
+                dropped = dropped,
+                "eBPF map is full, dropped entries added during migration"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Keeps the entry as it is. Used for resizing maps.
+fn copy_entry(key: &[u8], value: &[u8]) -> Option<Entry> {
+    Some((key.to_vec(), value.to_vec()))
+}
+
+/// Moves entries of the pinned map with the current layout to a new map with
+/// the size from the given definition and replaces the pin. Fails without
+/// replacing the map if the entries don't fit.
+pub fn resize_map(
+    path_base: &Path,
+    def: &MapDef<'static>,
+    old_fd: OwnedFd,
+) -> Result<ReplacedMap, io::Error> {
+    let migration = Migration {
+        map: def.name,
+        from_version: MAPS_SCHEMA_VERSION,
+        key_size: def.key_size,
+        value_size: def.value_size,
+        convert: copy_entry,
+    };
+    migrate_map(path_base, def, old_fd, &migration)
+}
+
+/// Finds the migration of the pinned map with the given layout, pinned with
+/// the given schema version. Returns `None` if the map already has the
+/// current layout.
+fn find_migration(
+    version: u32,
+    def: &MapDef<'static>,
+    info: &MapInfo,
+) -> Result<Option<&'static Migration>, MigrationError> {
+    let sizes_match = info.key_size == def.key_size && info.value_size == def.value_size;
+    // Maps pinned before introducing schema versions might already have
+    // the current layout, only their sizes can tell.
+    if version == 0 && sizes_match {
+        return Ok(None);
+    }
+    match MIGRATIONS
+        .iter()
+        .find(|m| m.map == def.name && m.from_version == version)
+    {
+        Some(migration)
+            if migration.key_size == info.key_size && migration.value_size == info.value_size =>
+        {
+            Ok(Some(migration))
+        }
+        // The layout of the map didn't change since that version.
+        None if sizes_match => Ok(None),
+        _ => Err(MigrationError::Unsupported {
+            map: def.name,
+            key_size: info.key_size,
+            value_size: info.value_size,
+        }),
+    }
+}
+
+/// Checks the schema version of pinned maps and migrates their entries if
+/// the layout changed. Fails without modifying any map if some of them can't
+/// be migrated. Should be called before creating pinned maps and loading
+/// eBPF programs. Returns the replaced maps, which should be passed to
+/// [`copy_late_entries`] after attaching the programs.
+pub fn migrate_pinned_maps<P: AsRef<Path>>(
+    path_base_r: P,
+    sizes: &MapSizes,
+) -> Result<Vec<ReplacedMap>, MigrationError> {
+    let path_base = path_base_r.as_ref();
+
+    let version = schema_version(path_base)?;
+    match version {
+        Some(version) if version == MAPS_SCHEMA_VERSION => {
+            debug!(version = version, "eBPF map schema is up to date");
+            return Ok(Vec::new());
+        }
+        Some(version) if version > MAPS_SCHEMA_VERSION => {
+            return Err(MigrationError::NewerSchema {
+                version,
+                supported: MAPS_SCHEMA_VERSION,
+            });
+        }
+        version => debug!(
+            version = ?version,
+            current_version = MAPS_SCHEMA_VERSION,
+            "checking eBPF maps for migration"
+        ),
+    }
+
+    // Find the migrations of all maps first, so nothing gets modified if
+    // some of them can't be migrated.
+    let version = version.unwrap_or(0);
+    let mut pending = Vec::new();
+    for def in sizes.defs() {
+        let path = path_base.join(def.name);
+        if !path.exists() {
+            continue;
+        }
+        let fd = obj_get(&path)?;
+        let info = map_info(&fd)?;
+        if let Some(migration) = find_migration(version, &def, &info)? {
+            pending.push((def, fd, migration));
+        }
+    }
+
+    let mut replaced = Vec::with_capacity(pending.len());
+    for (def, fd, migration) in pending {
+        replaced.push(migrate_map(path_base, &def, fd, migration)?);
+    }
+
+    Ok(replaced)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::Builder;
+
+    use super::*;
+
+    fn containers_def() -> MapDef<'static> {
+        MapSizes {
+            processes: 1024,
+            containers: 1024,
+        }
+        .defs()
+        .into_iter()
+        .find(|def| def.name == "CONTAINERS")
+        .unwrap()
+    }
+
+    fn info(key_size: u32, value_size: u32) -> MapInfo {
+        MapInfo {
+            key_size,
+            value_size,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn find_migration_by_version() {
+        let def = containers_def();
+        let key_size = mem::size_of::<ContainerID>() as u32;
+        let old_value_size = mem::size_of::<i32>() as u32;
+
+        let migration = find_migration(0, &def, &info(key_size, old_value_size))
+            .unwrap()
+            .unwrap();
+        assert_eq!(migration.map, "CONTAINERS");
+        assert_eq!(migration.from_version, 0);
+        // Maps without a version, but with the current layout.
+        assert!(find_migration(0, &def, &info(key_size, def.value_size))
+            .unwrap()
+            .is_none());
+
+        // Versioned maps are not matched by sizes of older layouts.
+        assert!(find_migration(1, &def, &info(key_size, def.value_size))
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            find_migration(1, &def, &info(key_size, old_value_size)),
+            Err(MigrationError::Unsupported { .. })
+        ));
+    }
+
+    #[test]
+    fn convert_container() {
+        let container_id = ContainerID::new("5833851e673d").unwrap();
+        let (key, value) = convert_container_v0(&container_id.id, &3i32.to_ne_bytes()).unwrap();
+        assert_eq!(key, container_id.id.to_vec());
+        assert_eq!(value.len(), mem::size_of::<Container>());
+        let container: Container = unsafe { std::ptr::read_unaligned(value.as_ptr() as *const _) };
+        assert_eq!(container.policy_level, ContainerPolicyLevel::Baseline);
+        assert_eq!(container.uid_range, IdRange::ANY);
+
+        assert!(convert_container_v0(&container_id.id, &42i32.to_ne_bytes()).is_none());
+    }
+
+    #[test]
+    fn convert_process() {
+        let container_id = ContainerID::new("5833851e673d").unwrap();
+        let pid = std::process::id() as i32;
+        let (_, value) = convert_process_v0(&pid.to_ne_bytes(), &container_id.id).unwrap();
+        let process: Process = unsafe { std::ptr::read_unaligned(value.as_ptr() as *const _) };
+        assert_eq!(process.container_id.id, container_id.id);
+        assert_eq!(
+            process.start_time,
+            procfs::process::Process::myself()
+                .unwrap()
+                .stat()
+                .unwrap()
+                .starttime
+        );
+
+        // Processes which are not running are dropped.
+        assert!(convert_process_v0(&i32::MAX.to_ne_bytes(), &container_id.id).is_none());
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tests_bpf"), ignore)]
+    fn copy_entries_added_after_resize() {
+        let path_base = Builder::new()
+            .prefix("lockc-temp")
+            .rand_bytes(5)
+            .tempdir_in("/sys/fs/bpf")
+            .expect("Creating temporary dir in BPFFS failed");
+        let def = containers_def();
+        let old_fd = map_create(&def).unwrap();
+        obj_pin(&old_fd, path_base.path().join(def.name)).unwrap();
+
+        let container = |policy_level| {
+            as_bytes(&Container {
+                policy_level,
+                uid_range: IdRange::ANY,
+                gid_range: IdRange::ANY,
+            })
+        };
+        let key = |c: u8| [c; mem::size_of::<ContainerID>()];
+        map_update_elem(
+            &old_fd,
+            &key(b'a'),
+            &container(ContainerPolicyLevel::Baseline),
+        )
+        .unwrap();
+
+        let def = MapDef {
+            max_entries: def.max_entries * 2,
+            ..def
+        };
+        let replaced = resize_map(path_base.path(), &def, old_fd).unwrap();
+
+        // Added by programs of the previous instance after resizing.
+        map_update_elem(
+            &replaced.old_fd,
+            &key(b'b'),
+            &container(ContainerPolicyLevel::Restricted),
+        )
+        .unwrap();
+        // Updated by the new programs.
+        map_update_elem(
+            &replaced.new_fd,
+            &key(b'a'),
+            &container(ContainerPolicyLevel::Privileged),
+        )
+        .unwrap();
+        copy_late_entries(vec![replaced]).unwrap();
+
+        let fd = obj_get(path_base.path().join(def.name)).unwrap();
+        assert_eq!(map_info(&fd).unwrap().max_entries, def.max_entries);
+        let mut value = vec![0u8; def.value_size as usize];
+        assert!(map_lookup_elem(&fd, &key(b'a'), &mut value).unwrap());
+        assert_eq!(value, container(ContainerPolicyLevel::Privileged));
+        assert!(map_lookup_elem(&fd, &key(b'b'), &mut value).unwrap());
+        assert_eq!(value, container(ContainerPolicyLevel::Restricted));
+    }
+}
@@ -0,0 +1,352 @@
+//! Registry of containers kept on disk, which stores the information about
+//! containers that doesn't fit into eBPF maps (i.e. why the container got
+//! its policy level). eBPF maps are pinned, so they survive restarts of
+//! lockc - the registry makes sure that this information survives as well.
+//!
+//! The registry is modified by the eBPF thread, while runc waits for
+//! containers to be registered. Writing to disk is left to
+//! [`RegistryWriter`], which runs in its own task.
+
+use std::{
+    collections::{BTreeMap, HashMap as StdHashMap},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use aya::{
+    maps::{HashMap, MapError},
+    Bpf,
+};
+use thiserror::Error;
+use tokio::{sync::watch, task};
+use tracing::{debug, error, warn};
+
+use lockc_common::{Container, ContainerID, ContainerPolicyLevel};
+
+pub use lockc_common::registry::{
+    ContainerMetadata, ContainerRecord, ContainerRuntime, PolicySource, REGISTRY_FILE,
+};
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Map(#[from] MapError),
+}
+
+type Containers = BTreeMap<String, ContainerRecord>;
+
+/// Registry of containers stored as a JSON file. Every modification is
+/// handed over to the [`RegistryWriter`] returned by [`Registry::load`].
+#[derive(Debug)]
+pub struct Registry {
+    containers: Containers,
+    snapshots: watch::Sender<Containers>,
+}
+
+/// Writes snapshots of the [`Registry`] to disk. Modifications made while a
+/// snapshot is being written are coalesced, only the latest state is
+/// written afterwards.
+#[derive(Debug)]
+pub struct RegistryWriter {
+    path: PathBuf,
+    snapshots: watch::Receiver<Containers>,
+}
+
+impl Registry {
+    /// Loads the registry from the given state directory. If the registry
+    /// file doesn't exist yet, the registry is empty.
+    pub fn load<P: AsRef<Path>>(state_dir: P) -> Result<(Self, RegistryWriter), RegistryError> {
+        let path = state_dir.as_ref().join(REGISTRY_FILE);
+        let containers = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(RegistryError::from(e)),
+        };
+        let (snapshots_tx, snapshots_rx) = watch::channel(containers.clone());
+        Ok((
+            Registry {
+                containers,
+                snapshots: snapshots_tx,
+            },
+            RegistryWriter {
+                path,
+                snapshots: snapshots_rx,
+            },
+        ))
+    }
+
+    /// Passes the current state to the writer.
+    fn save(&self) {
+        // Fails only if the writer is gone, which is logged by its task.
+        let _ = self.snapshots.send(self.containers.clone());
+    }
+
+    pub fn get(&self, container_id: &str) -> Option<&ContainerRecord> {
+        self.containers.get(container_id)
+    }
+
+    pub fn insert(&mut self, container_id: String, record: ContainerRecord) {
+        self.containers.insert(container_id, record);
+        self.save();
+    }
+
+    /// Changes the policy level of the container, applied manually. If the
+    /// container has no record yet, a new one is created.
+    pub fn set_policy_level(&mut self, container_id: &str, policy_level: ContainerPolicyLevel) {
+        let record = self
+            .containers
+            .entry(container_id.to_string())
+            .or_insert_with(|| {
+                ContainerRecord::new(
+                    policy_level,
+                    PolicySource::Manual,
+                    ContainerMetadata::default(),
+                )
+            });
+        record.policy_level = policy_level;
+        record.policy_source = PolicySource::Manual;
+        self.save();
+    }
+
+    pub fn remove(&mut self, container_id: &str) {
+        if self.containers.remove(container_id).is_some() {
+            self.save();
+        }
+    }
+
+    /// Brings the registry in sync with the CONTAINERS eBPF map, which is
+    /// the source of truth about registered containers:
+    ///
+    /// * records of containers missing in the map (i.e. deleted when lockc
+    ///   was not running, or after a reboot) are removed
+    /// * policy levels changed directly in the map are updated
+    ///
+    /// Returns the number of removed records.
+    pub fn reconcile(&mut self, bpf: &Bpf) -> Result<u64, RegistryError> {
+        let containers: HashMap<_, ContainerID, Container> = bpf.map("CONTAINERS")?.try_into()?;
+        let mut registered = StdHashMap::new();
+        for res in containers.iter() {
+            let (container_id, container) = res?;
+            if let Ok(id) = container_id.as_str() {
+                registered.insert(id.trim_end_matches('\0').to_string(), container);
+            }
+        }
+
+        let mut removed = 0;
+        let mut modified = false;
+        self.containers
+            .retain(|id, record| match registered.get(id) {
+                Some(container) => {
+                    if container.policy_level != record.policy_level {
+                        warn!(
+                            container = id.as_str(),
+                            policy_level = container.policy_level.to_string().as_str(),
+                            "policy level was changed outside of lockc"
+                        );
+                        record.policy_level = container.policy_level;
+                        modified = true;
+                    }
+                    true
+                }
+                None => {
+                    debug!(container = id.as_str(), "removing stale registry record");
+                    removed += 1;
+                    false
+                }
+            });
+        for id in registered.keys() {
+            if !self.containers.contains_key(id) {
+                debug!(container = id.as_str(), "container has no registry record");
+            }
+        }
+
+        if removed > 0 || modified {
+            self.save();
+        }
+
+        Ok(removed)
+    }
+}
+
+impl RegistryWriter {
+    /// Writes every new snapshot of the registry in a blocking task. Returns
+    /// once the [`Registry`] is dropped and its last snapshot is written.
+    pub async fn run(mut self) {
+        while self.snapshots.changed().await.is_ok() {
+            let containers = self.snapshots.borrow().clone();
+            let path = self.path.clone();
+            match task::spawn_blocking(move || save(&path, &containers)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!(
+                    error = e.to_string().as_str(),
+                    "could not write the container registry"
+                ),
+                Err(e) => error!(
+                    error = e.to_string().as_str(),
+                    "writing the container registry failed"
+                ),
+            }
+        }
+    }
+}
+
+/// Writes the registry to disk. The temporary file is synced and renamed
+/// over the old one, then the directory is synced, so a crash never leaves a
+/// partially written registry.
+fn save(path: &Path, containers: &Containers) -> Result<(), RegistryError> {
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&serde_json::to_vec_pretty(containers)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::{tempdir, Builder};
+
+    use lockc_common::ProcessTracking;
+
+    use crate::{load::load_bpf, maps::add_container, runc::ContainerCredentials};
+
+    use super::*;
+
+    fn record() -> ContainerRecord {
+        ContainerRecord::new(
+            ContainerPolicyLevel::Restricted,
+            PolicySource::NamespaceLabel,
+            ContainerMetadata {
+                namespace: Some("default".to_string()),
+                pod: Some("nginx".to_string()),
+                pod_uid: Some("6f1b8d5e-2c3a-4e7f-9b0d-1a2c3e4f5a6b".to_string()),
+                container_name: Some("nginx".to_string()),
+                image: Some("docker.io/library/nginx:latest".to_string()),
+                runtime: Some(ContainerRuntime::Containerd),
+            },
+        )
+    }
+
+    /// Writes all modifications of the registry and loads it again.
+    async fn reload(registry: Registry, writer: RegistryWriter, dir: &Path) -> Registry {
+        drop(registry);
+        writer.run().await;
+        Registry::load(dir).unwrap().0
+    }
+
+    #[test]
+    fn registry_empty() {
+        let dir = tempdir().unwrap();
+        let (registry, _) = Registry::load(dir.path()).unwrap();
+        assert!(registry.get("5833851e673d").is_none());
+    }
+
+    #[tokio::test]
+    async fn registry_persists() {
+        let dir = tempdir().unwrap();
+        let (mut registry, writer) = Registry::load(dir.path()).unwrap();
+        let record = record();
+        registry.insert("5833851e673d".to_string(), record.clone());
+        registry.insert("f4fb5ea0ec56".to_string(), record.clone());
+        registry.remove("f4fb5ea0ec56");
+
+        let registry = reload(registry, writer, dir.path()).await;
+        assert_eq!(registry.get("5833851e673d"), Some(&record));
+        assert!(registry.get("f4fb5ea0ec56").is_none());
+    }
+
+    #[tokio::test]
+    async fn registry_set_policy_level() {
+        let dir = tempdir().unwrap();
+        let (mut registry, writer) = Registry::load(dir.path()).unwrap();
+        registry.insert("5833851e673d".to_string(), record());
+        registry.set_policy_level("5833851e673d", ContainerPolicyLevel::Privileged);
+        // Containers without a record get a new one.
+        registry.set_policy_level("f4fb5ea0ec56", ContainerPolicyLevel::Baseline);
+
+        let registry = reload(registry, writer, dir.path()).await;
+        let record = registry.get("5833851e673d").unwrap();
+        assert_eq!(record.policy_level, ContainerPolicyLevel::Privileged);
+        assert_eq!(record.policy_source, PolicySource::Manual);
+        assert_eq!(record.metadata.pod.as_deref(), Some("nginx"));
+        let record = registry.get("f4fb5ea0ec56").unwrap();
+        assert_eq!(record.policy_level, ContainerPolicyLevel::Baseline);
+        assert_eq!(record.policy_source, PolicySource::Manual);
+    }
+
+    #[test]
+    fn registry_format() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join(REGISTRY_FILE),
+            r#"{
+                "5833851e673d": {
+                    "policy_level": "baseline",
+                    "policy_source": "docker-label",
+                    "namespace": null,
+                    "pod": null,
+                    "image": "nginx",
+                    "registered_at": 1646128800
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let (registry, _) = Registry::load(dir.path()).unwrap();
+        let record = registry.get("5833851e673d").unwrap();
+        assert_eq!(record.policy_level, ContainerPolicyLevel::Baseline);
+        assert_eq!(record.policy_source, PolicySource::DockerLabel);
+        assert_eq!(record.metadata.image.as_deref(), Some("nginx"));
+        // Records written before container names and runtimes were stored.
+        assert_eq!(record.metadata.container_name, None);
+        assert_eq!(record.metadata.runtime, None);
+        assert_eq!(record.registered_at, 1646128800);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tests_bpf"), ignore)]
+    fn registry_reconcile() {
+        let path_base = Builder::new()
+            .prefix("lockc-temp")
+            .rand_bytes(5)
+            .tempdir_in("/sys/fs/bpf")
+            .expect("Creating temporary dir in BPFFS failed");
+        let mut bpf =
+            load_bpf(&path_base, ProcessTracking::Pid, false).expect("Loading BPF failed");
+        let container_id =
+            "5833851e673d45fab4d12105bf61c3f4892b2bbf9c12d811db509a4f22475ec9".to_string();
+        add_container(
+            &mut bpf,
+            container_id.clone(),
+            42069,
+            0,
+            ContainerPolicyLevel::Baseline,
+            ContainerCredentials::default(),
+            ProcessTracking::Pid,
+        )
+        .expect("Adding container failed");
+
+        let dir = tempdir().unwrap();
+        let (mut registry, _) = Registry::load(dir.path()).unwrap();
+        registry.insert(container_id.clone(), record());
+        registry.insert("f4fb5ea0ec56".to_string(), record());
+
+        let removed = registry.reconcile(&bpf).expect("Reconciling failed");
+        assert_eq!(removed, 1);
+        assert!(registry.get("f4fb5ea0ec56").is_none());
+        assert_eq!(
+            registry.get(&container_id).unwrap().policy_level,
+            ContainerPolicyLevel::Baseline
+        );
+    }
+}
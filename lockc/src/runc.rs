@@ -1,11 +1,19 @@
-use std::{collections, fs, io, os::unix::fs::PermissionsExt, path::Path, string::String};
+use std::{
+    cell::RefCell,
+    collections, fs, io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    string::String,
+    sync::Arc,
+    time::Instant,
+};
 
 use fanotify::{
     high_level::{Event, Fanotify, FanotifyMode, FanotifyResponse},
     low_level::FAN_OPEN_EXEC_PERM,
 };
 use k8s_openapi::api::core::v1;
-use lockc_common::ContainerPolicyLevel;
+use lockc_common::{ContainerPolicyLevel, IdRange, ProcessTracking};
 use nix::poll::{poll, PollFd, PollFlags};
 use procfs::{process::Process, ProcError};
 use scopeguard::defer;
@@ -19,7 +27,13 @@ use tokio::{
 use tracing::{debug, error, warn};
 use walkdir::WalkDir;
 
-use crate::{communication::EbpfCommand, maps::MapOperationError};
+use crate::{
+    cgroups::{cgroup_id, cgroup_path, cgroup_pids, CgroupError},
+    communication::EbpfCommand,
+    maps::MapOperationError,
+    metrics::Metrics,
+    registry::{ContainerMetadata, ContainerRecord, ContainerRuntime, PolicySource},
+};
 
 // static LABEL_NAMESPACE: &str = "io.kubernetes.pod.namespace";
 static LABEL_POLICY_ENFORCE: &str = "pod-security.kubernetes.io/enforce";
@@ -28,6 +42,21 @@ static LABEL_POLICY_ENFORCE: &str = "pod-security.kubernetes.io/enforce";
 
 static ANNOTATION_CONTAINERD_LOG_DIRECTORY: &str = "io.kubernetes.cri.sandbox-log-directory";
 static ANNOTATION_CONTAINERD_SANDBOX_ID: &str = "io.kubernetes.cri.sandbox-id";
+static ANNOTATION_CONTAINERD_SANDBOX_NAME: &str = "io.kubernetes.cri.sandbox-name";
+static ANNOTATION_CONTAINERD_SANDBOX_UID: &str = "io.kubernetes.cri.sandbox-uid";
+static ANNOTATION_CONTAINERD_IMAGE_NAME: &str = "io.kubernetes.cri.image-name";
+static ANNOTATION_CONTAINERD_CONTAINER_NAME: &str = "io.kubernetes.cri.container-name";
+/// Set only by containerd 1.5 and newer.
+static ANNOTATION_CONTAINERD_SANDBOX_NAMESPACE: &str = "io.kubernetes.cri.sandbox-namespace";
+
+/// Directories where runc keeps the state of containers, depending on the
+/// engine which runs it.
+static RUNC_STATE_DIRS: &[&str] = &[
+    "/run/runc",
+    "/run/containerd/runc/k8s.io",
+    "/run/containerd/runc/default",
+    "/run/docker/runtime-runc/moby",
+];
 
 /// Type of Kubernetes container determined by annotations.
 enum KubernetesContainerType {
@@ -62,11 +91,184 @@ struct Mount {
     source: String,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct User {
+    uid: u32,
+    gid: u32,
+    additional_gids: Option<Vec<u32>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ContainerProcess {
+    user: User,
+}
+
+/// Mapping of IDs in the user namespace of the container to IDs on the
+/// host.
+#[derive(Debug, Deserialize)]
+struct IdMapping {
+    #[serde(rename = "containerID")]
+    container_id: u32,
+    #[serde(rename = "hostID")]
+    host_id: u32,
+    size: u32,
+}
+
+/// Converts the ID in the user namespace of the container to the ID on the
+/// host, which is what eBPF programs see. Without mappings, the container
+/// shares the user namespace with the host.
+fn host_id(mappings: &[IdMapping], id: u32) -> u32 {
+    mappings
+        .iter()
+        .find(|mapping| id >= mapping.container_id && id - mapping.container_id < mapping.size)
+        .map(|mapping| mapping.host_id + (id - mapping.container_id))
+        .unwrap_or(id)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ContainerLinux {
+    cgroups_path: Option<String>,
+    uid_mappings: Option<Vec<IdMapping>>,
+    gid_mappings: Option<Vec<IdMapping>>,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ContainerConfig {
     mounts: Vec<Mount>,
     annotations: Option<collections::HashMap<String, String>>,
+    process: Option<ContainerProcess>,
+    linux: Option<ContainerLinux>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RuncStateConfig {
+    labels: Option<Vec<String>>,
+}
+
+/// State of a container kept by runc in `<root>/<container_id>/state.json`.
+#[derive(Debug, Deserialize)]
+struct RuncState {
+    id: String,
+    init_process_pid: i32,
+    /// Start time of the init process in clock ticks since boot.
+    init_process_start: u64,
+    config: RuncStateConfig,
+    cgroup_paths: Option<collections::HashMap<String, String>>,
+}
+
+impl RuncState {
+    /// Returns the bundle directory of the container, stored by runc as a
+    /// `bundle=<path>` label.
+    fn bundle(&self) -> Option<PathBuf> {
+        self.config
+            .labels
+            .as_ref()?
+            .iter()
+            .find_map(|label| label.strip_prefix("bundle="))
+            .map(PathBuf::from)
+    }
+
+    /// Returns the absolute path of the container cgroup. In cgroup v1,
+    /// the path in the pids hierarchy is used.
+    fn cgroup_path(&self) -> Option<PathBuf> {
+        let cgroup_paths = self.cgroup_paths.as_ref()?;
+        cgroup_paths
+            .get("")
+            .or_else(|| cgroup_paths.get("pids"))
+            .map(PathBuf::from)
+    }
+}
+
+fn runc_state<P: AsRef<Path>>(state_path: P) -> Result<RuncState, ContainerError> {
+    let f = fs::File::open(state_path)?;
+    let r = io::BufReader::new(f);
+
+    Ok(serde_json::from_reader(r)?)
+}
+
+/// Checks whether runc keeps the state of the given container in any of the
+/// known state directories.
+pub fn runc_container_exists(container_id: &str) -> bool {
+    RUNC_STATE_DIRS
+        .iter()
+        .any(|state_dir| Path::new(state_dir).join(container_id).exists())
+}
+
+/// Returns the absolute path of the container cgroup, stored by runc in the
+/// state of the container.
+pub fn runc_container_cgroup(container_id: &str) -> Option<PathBuf> {
+    RUNC_STATE_DIRS.iter().find_map(|state_dir| {
+        runc_state(Path::new(state_dir).join(container_id).join("state.json"))
+            .ok()?
+            .cgroup_path()
+    })
+}
+
+/// Reads states of all containers in the given runc state directory.
+fn runc_states<P: AsRef<Path>>(state_dir: P) -> Vec<RuncState> {
+    let mut states = Vec::new();
+    let entries = match fs::read_dir(state_dir) {
+        Ok(entries) => entries,
+        Err(_) => return states,
+    };
+    for entry in entries.flatten() {
+        let state_path = entry.path().join("state.json");
+        match runc_state(&state_path) {
+            Ok(state) => states.push(state),
+            Err(e) => warn!(
+                path = ?state_path,
+                error = e.to_string().as_str(),
+                "could not read runc state"
+            ),
+        }
+    }
+    states
+}
+
+/// User and group IDs which processes in the container are allowed to use.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ContainerCredentials {
+    pub uid_range: IdRange,
+    pub gid_range: IdRange,
+    pub additional_gids: Vec<u32>,
+}
+
+impl Default for ContainerCredentials {
+    /// Credentials which allow to use all IDs.
+    fn default() -> Self {
+        ContainerCredentials {
+            uid_range: IdRange::ANY,
+            gid_range: IdRange::ANY,
+            additional_gids: Vec::new(),
+        }
+    }
+}
+
+impl ContainerCredentials {
+    /// Credentials of the container process, with IDs as seen on the host.
+    /// Containers running as root are limited to root as well.
+    fn new(user: User, linux: Option<&ContainerLinux>) -> Self {
+        let uid_mappings = linux
+            .and_then(|linux| linux.uid_mappings.as_deref())
+            .unwrap_or_default();
+        let gid_mappings = linux
+            .and_then(|linux| linux.gid_mappings.as_deref())
+            .unwrap_or_default();
+        ContainerCredentials {
+            uid_range: IdRange::single(host_id(uid_mappings, user.uid)),
+            gid_range: IdRange::single(host_id(gid_mappings, user.gid)),
+            additional_gids: user
+                .additional_gids
+                .unwrap_or_default()
+                .into_iter()
+                .map(|gid| host_id(gid_mappings, gid))
+                .collect(),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -82,6 +284,45 @@ pub enum ContainerError {
 
     #[error("could not parse k8s namespace")]
     K8sNamespace,
+
+    #[error(transparent)]
+    Cgroup(#[from] CgroupError),
+}
+
+fn container_config<P: AsRef<std::path::Path>>(
+    config_path: P,
+) -> Result<ContainerConfig, ContainerError> {
+    let f = fs::File::open(config_path)?;
+    let r = io::BufReader::new(f);
+
+    Ok(serde_json::from_reader(r)?)
+}
+
+/// Reads the user and groups of the container process from the OCI config.
+fn container_credentials<P: AsRef<std::path::Path>>(
+    container_bundle: P,
+) -> Result<ContainerCredentials, ContainerError> {
+    let config = container_config(container_bundle.as_ref().join("config.json"))?;
+
+    Ok(match config.process {
+        Some(process) => ContainerCredentials::new(process.user, config.linux.as_ref()),
+        None => ContainerCredentials::default(),
+    })
+}
+
+/// Reads the cgroup path (relative to the cgroup root) of the container from
+/// the OCI config. If the config doesn't specify any, runc uses the
+/// container ID.
+fn container_cgroup_path<P: AsRef<std::path::Path>>(
+    container_bundle: P,
+    container_id: &str,
+) -> Result<PathBuf, ContainerError> {
+    let config = container_config(container_bundle.as_ref().join("config.json"))?;
+
+    match config.linux.and_then(|linux| linux.cgroups_path) {
+        Some(cgroups_path) => Ok(cgroup_path(&cgroups_path)?),
+        None => Ok(PathBuf::from(container_id)),
+    }
 }
 
 fn container_type_data<P: AsRef<std::path::Path>>(
@@ -89,10 +330,7 @@ fn container_type_data<P: AsRef<std::path::Path>>(
 ) -> Result<(ContainerType, Option<std::string::String>), ContainerError> {
     let bundle_path = container_bundle.as_ref();
     let config_path = bundle_path.join("config.json");
-    let f = fs::File::open(&config_path)?;
-    let r = io::BufReader::new(f);
-
-    let config: ContainerConfig = serde_json::from_reader(r)?;
+    let config = container_config(&config_path)?;
 
     // Kubernetes
     if let Some(annotations) = config.annotations {
@@ -167,18 +405,28 @@ fn container_type_data<P: AsRef<std::path::Path>>(
     Ok((ContainerType::Unknown, None))
 }
 
+/// Parses the value of a label defining the policy level.
+fn policy_level_from_label(value: &str) -> Option<ContainerPolicyLevel> {
+    match value {
+        "restricted" => Some(ContainerPolicyLevel::Restricted),
+        "baseline" => Some(ContainerPolicyLevel::Baseline),
+        "privileged" => Some(ContainerPolicyLevel::Privileged),
+        _ => None,
+    }
+}
+
 /// Finds the policy for the given Kubernetes namespace. If none, the baseline
 /// policy is returned. Otherwise checks the Kubernetes namespace labels.
 async fn policy_kubernetes(
     default_policy_level: ContainerPolicyLevel,
     namespace: String,
-) -> Result<ContainerPolicyLevel, kube::Error> {
+) -> Result<(ContainerPolicyLevel, PolicySource), kube::Error> {
     // Apply the privileged policy for kube-system containers immediately.
     // Otherwise the core k8s components (apiserver, scheduler) won't be able
     // to run.
     // If container has no k8s namespace, apply the baseline policy.
     if namespace.as_str() == "kube-system" {
-        return Ok(ContainerPolicyLevel::Privileged);
+        return Ok((ContainerPolicyLevel::Privileged, PolicySource::KubeSystem));
     }
 
     let client = kube::Client::try_default().await?;
@@ -186,17 +434,16 @@ async fn policy_kubernetes(
     let namespaces: kube::api::Api<v1::Namespace> = kube::api::Api::all(client);
     let namespace = namespaces.get(&namespace).await?;
 
-    match namespace.metadata.labels {
-        Some(v) => match v.get(LABEL_POLICY_ENFORCE) {
-            Some(v) => match v.as_str() {
-                "restricted" => Ok(ContainerPolicyLevel::Restricted),
-                "baseline" => Ok(ContainerPolicyLevel::Baseline),
-                "privileged" => Ok(ContainerPolicyLevel::Privileged),
-                _ => Ok(default_policy_level),
-            },
-            None => Ok(default_policy_level),
-        },
-        None => Ok(default_policy_level),
+    let label_policy_level = namespace
+        .metadata
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(LABEL_POLICY_ENFORCE))
+        .and_then(|v| policy_level_from_label(v));
+
+    match label_policy_level {
+        Some(policy_level) => Ok((policy_level, PolicySource::NamespaceLabel)),
+        None => Ok((default_policy_level, PolicySource::Default)),
     }
 }
 
@@ -214,7 +461,7 @@ pub enum PolicyKubernetesSyncError {
 fn policy_kubernetes_sync(
     default_policy_level: ContainerPolicyLevel,
     namespace: String,
-) -> Result<ContainerPolicyLevel, PolicyKubernetesSyncError> {
+) -> Result<(ContainerPolicyLevel, PolicySource), PolicyKubernetesSyncError> {
     match Builder::new_current_thread()
         .enable_all()
         .build()?
@@ -225,27 +472,92 @@ fn policy_kubernetes_sync(
     }
 }
 
-fn policy_docker<P: AsRef<Path>>(
-    default_policy_level: ContainerPolicyLevel,
-    docker_bundle: P,
-) -> Result<ContainerPolicyLevel, ContainerError> {
-    let config_path = docker_bundle.as_ref();
+/// Reads the Docker container config (`config.v2.json`).
+fn docker_config<P: AsRef<Path>>(config_path: P) -> Result<Value, ContainerError> {
     let f = std::fs::File::open(config_path)?;
     let r = std::io::BufReader::new(f);
 
-    let l: Value = serde_json::from_reader(r)?;
+    Ok(serde_json::from_reader(r)?)
+}
+
+fn policy_docker<P: AsRef<Path>>(
+    default_policy_level: ContainerPolicyLevel,
+    docker_bundle: P,
+) -> Result<(ContainerPolicyLevel, PolicySource), ContainerError> {
+    let l = docker_config(docker_bundle)?;
 
     let x = l["Config"]["Labels"]["org.lockc.policy"].as_str();
 
-    match x {
-        Some(x) => match x {
-            "restricted" => Ok(ContainerPolicyLevel::Restricted),
-            "baseline" => Ok(ContainerPolicyLevel::Baseline),
-            "privileged" => Ok(ContainerPolicyLevel::Privileged),
-            _ => Ok(default_policy_level),
-        },
-        None => Ok(default_policy_level),
+    match x.and_then(policy_level_from_label) {
+        Some(policy_level) => Ok((policy_level, PolicySource::DockerLabel)),
+        None => Ok((default_policy_level, PolicySource::Default)),
+    }
+}
+
+/// Finds the policy level of the container based on the engine which
+/// created it.
+fn container_policy_level<P: AsRef<Path>>(
+    default_policy_level: ContainerPolicyLevel,
+    container_bundle: P,
+) -> Result<(ContainerPolicyLevel, PolicySource), HandleRuncEventError> {
+    let (container_type, container_data) = container_type_data(container_bundle)?;
+    let policy = match container_type {
+        ContainerType::Docker => policy_docker(
+            default_policy_level,
+            container_data.ok_or(HandleRuncEventError::ContainerData)?,
+        )?,
+        ContainerType::KubernetesContainerd => policy_kubernetes_sync(
+            default_policy_level,
+            container_data.ok_or(HandleRuncEventError::ContainerData)?,
+        )?,
+        ContainerType::Unknown => (ContainerPolicyLevel::Baseline, PolicySource::Fallback),
+    };
+
+    Ok(policy)
+}
+
+/// Reads the Kubernetes namespace, pod, name and image of the container from
+/// the engine which created it.
+fn container_metadata<P: AsRef<Path>>(
+    container_bundle: P,
+) -> Result<ContainerMetadata, ContainerError> {
+    let bundle_path = container_bundle.as_ref();
+    let (container_type, container_data) = container_type_data(bundle_path)?;
+
+    let mut metadata = ContainerMetadata::default();
+    match container_type {
+        ContainerType::Docker => {
+            metadata.runtime = Some(ContainerRuntime::Docker);
+            if let Some(config_v2) = container_data {
+                let config = docker_config(config_v2)?;
+                // Docker stores names with a leading slash.
+                metadata.container_name = config["Name"]
+                    .as_str()
+                    .map(|name| name.trim_start_matches('/').to_string());
+                metadata.image = config["Config"]["Image"].as_str().map(String::from);
+            }
+        }
+        ContainerType::KubernetesContainerd => {
+            metadata.runtime = Some(ContainerRuntime::Containerd);
+            metadata.namespace = container_data;
+            let config = container_config(bundle_path.join("config.json"))?;
+            if let Some(annotations) = config.annotations {
+                if let Some(namespace) = annotations.get(ANNOTATION_CONTAINERD_SANDBOX_NAMESPACE) {
+                    metadata.namespace = Some(namespace.clone());
+                }
+                metadata.pod = annotations.get(ANNOTATION_CONTAINERD_SANDBOX_NAME).cloned();
+                metadata.pod_uid = annotations.get(ANNOTATION_CONTAINERD_SANDBOX_UID).cloned();
+                // Sandbox (pause) containers have no name.
+                metadata.container_name = annotations
+                    .get(ANNOTATION_CONTAINERD_CONTAINER_NAME)
+                    .cloned();
+                metadata.image = annotations.get(ANNOTATION_CONTAINERD_IMAGE_NAME).cloned();
+            }
+        }
+        ContainerType::Unknown => {}
     }
+
+    Ok(metadata)
 }
 
 enum ShimOptParsingAction {
@@ -285,6 +597,10 @@ enum ContainerAction {
     /// Action of creating the container, when we want to register the new
     /// container.
     Create,
+    /// Action of starting the container, when we want to register the
+    /// cgroup of the container (which doesn't exist before `runc create`
+    /// finishes).
+    Start,
     /// Action of deleting the container, when we want to remove the registered
     /// container.
     Delete,
@@ -295,6 +611,10 @@ pub struct RuncWatcher {
     ebpf_tx: mpsc::Sender<EbpfCommand>,
     fd: Fanotify,
     default_policy_level: ContainerPolicyLevel,
+    process_tracking: ProcessTracking,
+    /// Cgroup paths of created containers, which are waiting to be started.
+    cgroup_paths: RefCell<collections::HashMap<String, PathBuf>>,
+    metrics: Arc<Metrics>,
 }
 
 #[derive(Error, Debug)]
@@ -326,6 +646,9 @@ pub enum HandleRuncEventError {
     #[error(transparent)]
     MapOperation(#[from] MapOperationError),
 
+    #[error(transparent)]
+    Cgroup(#[from] CgroupError),
+
     #[error("container data missing")]
     ContainerData,
 
@@ -338,6 +661,8 @@ impl RuncWatcher {
         bootstrap_rx: oneshot::Receiver<()>,
         ebpf_tx: mpsc::Sender<EbpfCommand>,
         default_policy_level: ContainerPolicyLevel,
+        process_tracking: ProcessTracking,
+        metrics: Arc<Metrics>,
     ) -> Result<Self, io::Error> {
         let runc_paths = vec![
             "/usr/bin/runc",
@@ -407,6 +732,9 @@ impl RuncWatcher {
             ebpf_tx,
             fd,
             default_policy_level,
+            process_tracking,
+            cgroup_paths: RefCell::new(collections::HashMap::new()),
+            metrics,
         })
     }
 
@@ -414,7 +742,9 @@ impl RuncWatcher {
         &self,
         container_id: String,
         pid: i32,
-        policy_level: ContainerPolicyLevel,
+        start_time: u64,
+        record: ContainerRecord,
+        credentials: ContainerCredentials,
     ) -> Result<(), HandleRuncEventError> {
         let (responder_tx, responder_rx) = oneshot::channel();
 
@@ -422,7 +752,9 @@ impl RuncWatcher {
             .send(EbpfCommand::AddContainer {
                 container_id,
                 pid,
-                policy_level,
+                start_time,
+                record,
+                credentials,
                 responder_tx,
             })
             .await?;
@@ -435,13 +767,15 @@ impl RuncWatcher {
         &self,
         container_id: String,
         pid: i32,
-        policy_level: ContainerPolicyLevel,
+        start_time: u64,
+        record: ContainerRecord,
+        credentials: ContainerCredentials,
     ) -> Result<(), HandleRuncEventError> {
         debug!(container_id = container_id.as_str(), "adding container");
 
         Builder::new_current_thread()
             .build()?
-            .block_on(self.add_container(container_id, pid, policy_level))
+            .block_on(self.add_container(container_id, pid, start_time, record, credentials))
     }
 
     async fn delete_container(&self, container_id: String) -> Result<(), HandleRuncEventError> {
@@ -470,6 +804,7 @@ impl RuncWatcher {
         &self,
         container_id: String,
         pid: i32,
+        start_time: u64,
     ) -> Result<(), HandleRuncEventError> {
         let (responder_tx, responder_rx) = oneshot::channel();
 
@@ -477,6 +812,7 @@ impl RuncWatcher {
             .send(EbpfCommand::AddProcess {
                 container_id,
                 pid,
+                start_time,
                 responder_tx,
             })
             .await?;
@@ -485,7 +821,12 @@ impl RuncWatcher {
         Ok(())
     }
 
-    fn add_process_sync(&self, container_id: String, pid: i32) -> Result<(), HandleRuncEventError> {
+    fn add_process_sync(
+        &self,
+        container_id: String,
+        pid: i32,
+        start_time: u64,
+    ) -> Result<(), HandleRuncEventError> {
         debug!(
             container = container_id.as_str(),
             pid = pid,
@@ -494,7 +835,128 @@ impl RuncWatcher {
 
         Builder::new_current_thread()
             .build()?
-            .block_on(self.add_process(container_id, pid))
+            .block_on(self.add_process(container_id, pid, start_time))
+    }
+
+    async fn add_cgroup(
+        &self,
+        container_id: String,
+        cgroup_id: u64,
+    ) -> Result<(), HandleRuncEventError> {
+        let (responder_tx, responder_rx) = oneshot::channel();
+
+        self.ebpf_tx
+            .send(EbpfCommand::AddCgroup {
+                container_id,
+                cgroup_id,
+                responder_tx,
+            })
+            .await?;
+        responder_rx.await??;
+
+        Ok(())
+    }
+
+    fn add_cgroup_sync(
+        &self,
+        container_id: String,
+        cgroup_id: u64,
+    ) -> Result<(), HandleRuncEventError> {
+        debug!(
+            container = container_id.as_str(),
+            cgroup_id = cgroup_id,
+            "adding cgroup"
+        );
+
+        Builder::new_current_thread()
+            .build()?
+            .block_on(self.add_cgroup(container_id, cgroup_id))
+    }
+
+    /// Registers the container which was created before lockc started,
+    /// together with all its processes.
+    fn register_existing_container(&self, state: RuncState) -> Result<(), HandleRuncEventError> {
+        // Skip containers which are not running anymore.
+        match Process::new(state.init_process_pid) {
+            Ok(p) if matches!(p.stat(), Ok(stat) if stat.starttime == state.init_process_start) => {
+            }
+            _ => {
+                debug!(container = state.id.as_str(), "container is not running");
+                return Ok(());
+            }
+        }
+
+        let container_bundle = state.bundle().ok_or(HandleRuncEventError::ContainerData)?;
+        let cgroup_path = state
+            .cgroup_path()
+            .ok_or(HandleRuncEventError::ContainerData)?;
+
+        let credentials = container_credentials(&container_bundle)?;
+        let (policy, policy_source) =
+            container_policy_level(self.default_policy_level, &container_bundle)?;
+        let metadata = container_metadata(&container_bundle)?;
+        self.add_container_sync(
+            state.id.clone(),
+            state.init_process_pid,
+            state.init_process_start,
+            ContainerRecord {
+                cgroup_path: Some(cgroup_path.clone()),
+                ..ContainerRecord::new(policy, policy_source, metadata)
+            },
+            credentials,
+        )?;
+
+        match self.process_tracking {
+            ProcessTracking::Pid => {
+                // Processes forked before their parent got registered are
+                // not going to be tracked by eBPF programs. Do the second
+                // pass to catch them.
+                let mut registered = collections::HashSet::from([state.init_process_pid]);
+                for _ in 0..2 {
+                    for pid in cgroup_pids(&cgroup_path)? {
+                        if registered.contains(&pid) {
+                            continue;
+                        }
+                        // The process might have already exited.
+                        let start_time = match Process::new(pid).and_then(|p| p.stat()) {
+                            Ok(stat) => stat.starttime,
+                            Err(_) => continue,
+                        };
+                        self.add_process_sync(state.id.clone(), pid, start_time)?;
+                        registered.insert(pid);
+                    }
+                }
+            }
+            ProcessTracking::Cgroup => {
+                self.add_cgroup_sync(state.id, cgroup_id(cgroup_path)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds containers which were created before lockc started (i.e. on
+    /// Kubernetes, where lockc runs as a DaemonSet) in runc state directories
+    /// and registers them.
+    fn register_existing_containers(&self) {
+        for state_dir in RUNC_STATE_DIRS {
+            for state in runc_states(state_dir) {
+                let container_id = state.id.clone();
+                debug!(
+                    container = container_id.as_str(),
+                    state_dir = *state_dir,
+                    "found existing container"
+                );
+                if let Err(e) = self.register_existing_container(state) {
+                    self.record_error(&e);
+                    error!(
+                        container = container_id.as_str(),
+                        error = e.to_string().as_str(),
+                        "could not register existing container"
+                    );
+                }
+            }
+        }
     }
 
     fn handle_containerd_shim_event(
@@ -622,6 +1084,7 @@ impl RuncWatcher {
                 "run" => arg_parsing_action = ArgParsingAction::ContainerId,
                 "start" => {
                     arg_parsing_action = ArgParsingAction::ContainerId;
+                    container_action = ContainerAction::Start;
                 }
                 "state" => arg_parsing_action = ArgParsingAction::ContainerId,
                 "update" => arg_parsing_action = ArgParsingAction::ContainerId,
@@ -629,11 +1092,16 @@ impl RuncWatcher {
             }
         }
 
+        // Used to distinguish runc from later processes reusing its PID.
+        let start_time = runc_process.stat()?.starttime;
+
         match container_action {
             ContainerAction::Other => {
                 debug!("other container action");
-                if let Some(container_id) = container_id_o {
-                    self.add_process_sync(container_id, runc_process.pid)?;
+                if let ProcessTracking::Pid = self.process_tracking {
+                    if let Some(container_id) = container_id_o {
+                        self.add_process_sync(container_id, runc_process.pid, start_time)?;
+                    }
                 }
             }
             ContainerAction::Create => {
@@ -643,24 +1111,53 @@ impl RuncWatcher {
                     None => std::env::current_dir()?,
                 };
 
-                // let policy;
-                let (container_type, container_data) = container_type_data(container_bundle)?;
-                let policy: ContainerPolicyLevel = match container_type {
-                    ContainerType::Docker => policy_docker(
-                        self.default_policy_level,
-                        container_data.ok_or(HandleRuncEventError::ContainerData)?,
-                    )?,
-                    ContainerType::KubernetesContainerd => policy_kubernetes_sync(
-                        self.default_policy_level,
-                        container_data.ok_or(HandleRuncEventError::ContainerData)?,
-                    )?,
-                    ContainerType::Unknown => ContainerPolicyLevel::Baseline,
-                };
+                let credentials = container_credentials(&container_bundle)?;
+                let cgroup_path = container_cgroup_path(&container_bundle, &container_id)?;
+                if let ProcessTracking::Cgroup = self.process_tracking {
+                    self.cgroup_paths
+                        .borrow_mut()
+                        .insert(container_id.clone(), cgroup_path.clone());
+                }
 
-                self.add_container_sync(container_id, runc_process.pid, policy)?;
+                let (policy, policy_source) =
+                    container_policy_level(self.default_policy_level, &container_bundle)?;
+                let metadata = container_metadata(&container_bundle)?;
+
+                self.add_container_sync(
+                    container_id,
+                    runc_process.pid,
+                    start_time,
+                    ContainerRecord {
+                        cgroup_path: Some(cgroup_path),
+                        ..ContainerRecord::new(policy, policy_source, metadata)
+                    },
+                    credentials,
+                )?;
+            }
+            ContainerAction::Start => {
+                let container_id = container_id_o.ok_or(HandleRuncEventError::ContainerID)?;
+                match self.process_tracking {
+                    ProcessTracking::Pid => {
+                        self.add_process_sync(container_id, runc_process.pid, start_time)?;
+                    }
+                    ProcessTracking::Cgroup => {
+                        let cgroup_path_o = self.cgroup_paths.borrow_mut().remove(&container_id);
+                        match cgroup_path_o {
+                            Some(cgroup_path) => {
+                                let cgroup_id = cgroup_id(cgroup_path)?;
+                                self.add_cgroup_sync(container_id, cgroup_id)?;
+                            }
+                            None => warn!(
+                                container = container_id.as_str(),
+                                "unknown cgroup, container was created before starting lockc"
+                            ),
+                        }
+                    }
+                }
             }
             ContainerAction::Delete => {
                 let container_id = container_id_o.ok_or(HandleRuncEventError::ContainerID)?;
+                self.cgroup_paths.borrow_mut().remove(&container_id);
                 self.delete_container_sync(container_id)?;
             }
         }
@@ -699,6 +1196,13 @@ impl RuncWatcher {
         Ok(())
     }
 
+    /// Counts errors which are exposed as metrics.
+    fn record_error(&self, e: &HandleRuncEventError) {
+        if let HandleRuncEventError::PolicyKubernetes(_) = e {
+            self.metrics.inc_kubernetes_lookup_errors();
+        }
+    }
+
     pub fn work_loop(&mut self) -> Result<(), HandleRuncEventError> {
         // Wait for the bootstrap request from the main, asynchronous part of
         // lockc.
@@ -714,6 +1218,9 @@ impl RuncWatcher {
             }
         }
 
+        debug!("registering existing containers");
+        self.register_existing_containers();
+
         debug!("starting work loop");
 
         let mut fds = [PollFd::new(self.fd.as_raw_fd(), PollFlags::POLLIN)];
@@ -721,10 +1228,13 @@ impl RuncWatcher {
             let poll_num = poll(&mut fds, -1)?;
             if poll_num > 0 {
                 for event in self.fd.read_event() {
-                    match self.handle_event(event) {
-                        Ok(_) => {}
-                        Err(e) => error!(error = e.to_string().as_str(), "failed to handle event"),
-                    };
+                    let start = Instant::now();
+                    let res = self.handle_event(event);
+                    self.metrics.observe_runc_event(start.elapsed());
+                    if let Err(e) = res {
+                        self.record_error(&e);
+                        error!(error = e.to_string().as_str(), "failed to handle event");
+                    }
                 }
             } else {
                 debug!("poll_num <= 0!");
@@ -735,3 +1245,204 @@ impl RuncWatcher {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use tempfile::tempdir;
+
+    fn write_config(config: &str) -> tempfile::TempDir {
+        let dir = tempdir().unwrap();
+        let mut f = fs::File::create(dir.path().join("config.json")).unwrap();
+        f.write_all(config.as_bytes()).unwrap();
+        dir
+    }
+
+    #[test]
+    fn container_credentials_non_root() {
+        let bundle = write_config(
+            r#"{
+                "process": {
+                    "user": {"uid": 101, "gid": 0, "additionalGids": [0, 2000]}
+                },
+                "mounts": []
+            }"#,
+        );
+        let credentials = container_credentials(bundle.path()).unwrap();
+        assert_eq!(
+            credentials,
+            ContainerCredentials {
+                uid_range: IdRange::single(101),
+                gid_range: IdRange::single(0),
+                additional_gids: vec![0, 2000],
+            }
+        );
+    }
+
+    #[test]
+    fn container_credentials_root() {
+        let bundle = write_config(
+            r#"{
+                "process": {
+                    "user": {"uid": 0, "gid": 0}
+                },
+                "mounts": []
+            }"#,
+        );
+        let credentials = container_credentials(bundle.path()).unwrap();
+        assert_eq!(
+            credentials,
+            ContainerCredentials {
+                uid_range: IdRange::single(0),
+                gid_range: IdRange::single(0),
+                additional_gids: Vec::new(),
+            }
+        );
+        // runc init keeps the UID when starting the container, but
+        // processes can't switch to other users.
+        assert!(credentials.uid_range.allows_change(0, 0));
+        assert!(!credentials.uid_range.allows_change(0, 1000));
+    }
+
+    #[test]
+    fn container_credentials_user_namespace() {
+        let bundle = write_config(
+            r#"{
+                "process": {
+                    "user": {"uid": 0, "gid": 0, "additionalGids": [10]}
+                },
+                "linux": {
+                    "uidMappings": [{"containerID": 0, "hostID": 100000, "size": 65536}],
+                    "gidMappings": [{"containerID": 0, "hostID": 200000, "size": 65536}]
+                },
+                "mounts": []
+            }"#,
+        );
+        let credentials = container_credentials(bundle.path()).unwrap();
+        assert_eq!(
+            credentials,
+            ContainerCredentials {
+                uid_range: IdRange::single(100000),
+                gid_range: IdRange::single(200000),
+                additional_gids: vec![200010],
+            }
+        );
+    }
+
+    #[test]
+    fn container_metadata_kubernetes() {
+        let bundle = write_config(
+            r#"{
+                "annotations": {
+                    "io.kubernetes.cri.sandbox-log-directory": "/var/log/pods/default_nginx_a3d8c0f4",
+                    "io.kubernetes.cri.sandbox-name": "nginx",
+                    "io.kubernetes.cri.sandbox-uid": "6f1b8d5e-2c3a-4e7f-9b0d-1a2c3e4f5a6b",
+                    "io.kubernetes.cri.container-name": "web",
+                    "io.kubernetes.cri.image-name": "docker.io/library/nginx:latest"
+                },
+                "mounts": []
+            }"#,
+        );
+        let metadata = container_metadata(bundle.path()).unwrap();
+        assert_eq!(
+            metadata,
+            ContainerMetadata {
+                namespace: Some("default".to_string()),
+                pod: Some("nginx".to_string()),
+                pod_uid: Some("6f1b8d5e-2c3a-4e7f-9b0d-1a2c3e4f5a6b".to_string()),
+                container_name: Some("web".to_string()),
+                image: Some("docker.io/library/nginx:latest".to_string()),
+                runtime: Some(ContainerRuntime::Containerd),
+            }
+        );
+    }
+
+    #[test]
+    fn container_metadata_kubernetes_sandbox() {
+        let bundle = write_config(
+            r#"{
+                "annotations": {
+                    "io.kubernetes.cri.sandbox-log-directory": "/var/log/pods/default_nginx_a3d8c0f4",
+                    "io.kubernetes.cri.sandbox-namespace": "default",
+                    "io.kubernetes.cri.sandbox-name": "nginx"
+                },
+                "mounts": []
+            }"#,
+        );
+        let metadata = container_metadata(bundle.path()).unwrap();
+        assert_eq!(metadata.namespace.as_deref(), Some("default"));
+        assert_eq!(metadata.pod.as_deref(), Some("nginx"));
+        // Sandbox (pause) containers have no name.
+        assert_eq!(metadata.container_name, None);
+    }
+
+    #[test]
+    fn container_metadata_docker() {
+        let container_dir = tempdir().unwrap();
+        fs::write(
+            container_dir.path().join("config.v2.json"),
+            r#"{"Name": "/web", "Config": {"Image": "nginx:latest"}}"#,
+        )
+        .unwrap();
+        let bundle = write_config(&format!(
+            r#"{{"mounts": [{{"source": "{}/hostname"}}]}}"#,
+            container_dir.path().display()
+        ));
+        let metadata = container_metadata(bundle.path()).unwrap();
+        assert_eq!(
+            metadata,
+            ContainerMetadata {
+                container_name: Some("web".to_string()),
+                image: Some("nginx:latest".to_string()),
+                runtime: Some(ContainerRuntime::Docker),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn runc_states_existing_containers() {
+        let state_dir = tempdir().unwrap();
+        let container_dir = state_dir.path().join("5833851e673d");
+        fs::create_dir(&container_dir).unwrap();
+        fs::write(
+            container_dir.join("state.json"),
+            r#"{
+                "id": "5833851e673d",
+                "init_process_pid": 4242,
+                "init_process_start": 123456,
+                "created": "2022-03-01T10:00:00.000000000Z",
+                "config": {
+                    "rootfs": "/run/containerd/rootfs",
+                    "labels": ["bundle=/run/containerd/5833851e673d"]
+                },
+                "cgroup_paths": {
+                    "": "/sys/fs/cgroup/system.slice/docker-5833851e673d.scope"
+                }
+            }"#,
+        )
+        .unwrap();
+        // Directories without state are skipped.
+        fs::create_dir(state_dir.path().join("foo")).unwrap();
+
+        let states = runc_states(state_dir.path());
+        assert_eq!(states.len(), 1);
+        let state = &states[0];
+        assert_eq!(state.id, "5833851e673d");
+        assert_eq!(state.init_process_pid, 4242);
+        assert_eq!(state.init_process_start, 123456);
+        assert_eq!(
+            state.bundle(),
+            Some(PathBuf::from("/run/containerd/5833851e673d"))
+        );
+        assert_eq!(
+            state.cgroup_path(),
+            Some(PathBuf::from(
+                "/sys/fs/cgroup/system.slice/docker-5833851e673d.scope"
+            ))
+        );
+    }
+}
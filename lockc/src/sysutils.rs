@@ -1,9 +1,21 @@
 use std::{
-    fs::File,
+    collections::HashSet,
+    fs::{File, Metadata},
     io::{self, prelude::*},
+    os::unix::fs::{FileTypeExt, MetadataExt},
     path::Path,
 };
 
+use lockc_common::{Device, InodeID, RUNTIME_SOCKETS_LIMIT};
+use nix::sys::stat::{major, minor};
+use tracing::warn;
+use walkdir::WalkDir;
+
+/// How deep runtime socket directories are searched. Runtime sockets live
+/// directly in them or in a subdirectory (i.e. `/run/k3s/containerd`), while
+/// the deeper levels contain state of containers.
+const RUNTIME_SOCKETS_MAX_DEPTH: usize = 3;
+
 #[derive(thiserror::Error, Debug)]
 pub enum CheckBpfLsmError {
     #[error("regex compilation error")]
@@ -30,12 +42,117 @@ pub fn check_bpf_lsm_enabled<P: AsRef<Path>>(sys_lsm_path: P) -> Result<(), Chec
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum PidMaxError {
+    #[error("I/O error")]
+    IO(#[from] io::Error),
+
+    #[error("could not parse pid_max")]
+    Parse(#[from] std::num::ParseIntError),
+}
+
+/// Reads the max PID from the given sysctl file (usually
+/// `/proc/sys/kernel/pid_max`).
+pub fn pid_max<P: AsRef<Path>>(pid_max_path: P) -> Result<u32, PidMaxError> {
+    let content = std::fs::read_to_string(pid_max_path)?;
+    Ok(content.trim().parse()?)
+}
+
+/// Returns the number of possible CPUs, read from the given file (usually
+/// `/sys/devices/system/cpu/possible`), which contains ranges of CPU IDs
+/// (i.e. `0-7` or `0,2-3`). Values of per-CPU eBPF maps are stored for all
+/// of them.
+pub fn possible_cpus<P: AsRef<Path>>(possible_cpus_path: P) -> Result<u32, io::Error> {
+    let content = std::fs::read_to_string(possible_cpus_path)?;
+    let parse = |id: &str| {
+        id.parse::<u32>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    };
+    let mut count = 0;
+    for range in content.trim().split(',') {
+        count += match range.split_once('-') {
+            Some((start, end)) => parse(end)?.saturating_sub(parse(start)?) + 1,
+            None => {
+                parse(range)?;
+                1
+            }
+        };
+    }
+    Ok(count)
+}
+
+fn inode_id(metadata: &Metadata) -> InodeID {
+    let dev = metadata.dev();
+    InodeID {
+        device: Device::new(major(dev) as u32, minor(dev) as u32),
+        ino: metadata.ino(),
+    }
+}
+
+/// Finds inodes of the given container runtime sockets. Directories are
+/// included together with all sockets inside them. Paths which don't exist
+/// are skipped. The search doesn't cross mount points (i.e. rootfs of
+/// containers) and returns at most [`RUNTIME_SOCKETS_LIMIT`] inodes.
+pub fn runtime_socket_inodes<P: AsRef<Path>>(paths: &[P]) -> HashSet<InodeID> {
+    let mut inodes = HashSet::new();
+    for path in paths {
+        let walker = WalkDir::new(path)
+            .max_depth(RUNTIME_SOCKETS_MAX_DEPTH)
+            .same_file_system(true);
+        for entry in walker.into_iter().flatten() {
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if entry.depth() == 0 || metadata.file_type().is_socket() {
+                if inodes.len() == RUNTIME_SOCKETS_LIMIT as usize {
+                    warn!(
+                        path = ?entry.path(),
+                        limit = RUNTIME_SOCKETS_LIMIT,
+                        "too many runtime sockets, ignoring the rest"
+                    );
+                    return inodes;
+                }
+                inodes.insert(inode_id(&metadata));
+            }
+        }
+    }
+    inodes
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::{fs, os::unix::net::UnixListener};
+
     use tempfile::tempdir;
 
+    #[test]
+    fn pid_max_parse() {
+        let dir = tempdir().unwrap();
+        let pid_max_path = dir.path().join("pid_max");
+        fs::write(&pid_max_path, "4194304\n").unwrap();
+        assert_eq!(pid_max(&pid_max_path).unwrap(), 4194304);
+
+        fs::write(&pid_max_path, "foo\n").unwrap();
+        assert!(pid_max(&pid_max_path).is_err());
+    }
+
+    #[test]
+    fn possible_cpus_parse() {
+        let dir = tempdir().unwrap();
+        let possible_path = dir.path().join("possible");
+        fs::write(&possible_path, "0-7\n").unwrap();
+        assert_eq!(possible_cpus(&possible_path).unwrap(), 8);
+
+        fs::write(&possible_path, "0,2-3\n").unwrap();
+        assert_eq!(possible_cpus(&possible_path).unwrap(), 3);
+
+        fs::write(&possible_path, "foo\n").unwrap();
+        assert!(possible_cpus(&possible_path).is_err());
+    }
+
     #[test]
     fn check_bpf_lsm_enabled_when_correct() {
         let dir = tempdir().unwrap();
@@ -55,4 +172,36 @@ mod tests {
         assert!(res.is_err());
         assert!(matches!(res.unwrap_err(), CheckBpfLsmError::BpfLsmDisabled));
     }
+
+    #[test]
+    fn runtime_socket_inodes_finds_sockets() {
+        let dir = tempdir().unwrap();
+        let runtime_dir = dir.path().join("k3s");
+        fs::create_dir_all(runtime_dir.join("containerd")).unwrap();
+        let socket_path = runtime_dir.join("containerd").join("containerd.sock");
+        let _listener = UnixListener::bind(&socket_path).unwrap();
+        let file_path = runtime_dir.join("containerd").join("containerd.pid");
+        File::create(&file_path).unwrap();
+        let missing_path = dir.path().join("docker.sock");
+
+        let inodes = runtime_socket_inodes(&[&runtime_dir, &missing_path]);
+        assert_eq!(inodes.len(), 2);
+        assert!(inodes.contains(&inode_id(&fs::metadata(&runtime_dir).unwrap())));
+        assert!(inodes.contains(&inode_id(&fs::metadata(&socket_path).unwrap())));
+        assert!(!inodes.contains(&inode_id(&fs::metadata(&file_path).unwrap())));
+    }
+
+    #[test]
+    fn runtime_socket_inodes_max_depth() {
+        let dir = tempdir().unwrap();
+        let runtime_dir = dir.path().join("containerd");
+        let deep_dir = (0..RUNTIME_SOCKETS_MAX_DEPTH)
+            .fold(runtime_dir.clone(), |path, i| path.join(i.to_string()));
+        fs::create_dir_all(&deep_dir).unwrap();
+        let _listener = UnixListener::bind(deep_dir.join("shim.sock")).unwrap();
+
+        let inodes = runtime_socket_inodes(&[&runtime_dir]);
+        assert_eq!(inodes.len(), 1);
+        assert!(inodes.contains(&inode_id(&fs::metadata(&runtime_dir).unwrap())));
+    }
 }
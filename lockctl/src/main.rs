@@ -1,18 +1,29 @@
-use std::str::FromStr;
-
-use aya::{
-    include_bytes_aligned,
-    maps::{HashMap, MapRef, MapRefMut},
-    Bpf, BpfLoader,
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
 };
-use clap::{Parser, Subcommand};
-use cli_table::{print_stdout, Cell, Style, Table};
-use lockc_common::{Container, ContainerID, ContainerPolicyLevel, Process};
 
-const PATH_BASE: &str = "/sys/fs/bpf/lockc";
+use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
+use cli_table::{print_stdout, Cell, Style, Table};
+use lockc_common::{
+    api::{ContainerInfo, ProcessInfo, Request, Response, DEFAULT_SOCKET_PATH},
+    events::{DaemonEvent, EventFilter},
+    registry::PolicySource,
+    ContainerPolicyLevel, Hook,
+};
+use serde::Serialize;
 
 #[derive(Parser)]
 struct Args {
+    /// Path of the socket of the lockc daemon.
+    #[clap(long, global = true, default_value = DEFAULT_SOCKET_PATH)]
+    socket: PathBuf,
+    /// Output format.
+    #[clap(short, long, global = true, value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
     #[command(subcommand)]
     subcommand: Sub,
 }
@@ -29,15 +40,91 @@ enum Sub {
         #[command(subcommand)]
         process: SubProcess,
     },
+    /// Show how many times LSM hooks allowed and denied actions in
+    /// containers.
+    Stats {
+        /// Show only containers with IDs starting with the given prefix.
+        #[clap(long)]
+        container: Option<String>,
+        /// Sort by container ID and hook, or by count (descending).
+        #[clap(long, value_enum, default_value_t = StatsSort::Container)]
+        sort: StatsSort,
+    },
+    /// Show recent denials and registrations of containers, reported by the
+    /// daemon.
+    Events {
+        /// Keep showing new events as they come.
+        #[clap(short, long)]
+        follow: bool,
+        /// Show only containers with IDs starting with the given prefix, or
+        /// with the given name.
+        #[clap(long)]
+        container: Option<String>,
+        /// Show only containers in the given Kubernetes namespace.
+        #[clap(long)]
+        namespace: Option<String>,
+        /// Show only denials made by the given hook.
+        #[clap(long, value_enum)]
+        hook: Option<Hook>,
+        /// Show only containers with the given policy level.
+        #[clap(long, value_enum)]
+        level: Option<ContainerPolicyLevel>,
+    },
+}
+
+#[derive(ValueEnum, Clone)]
+enum StatsSort {
+    Container,
+    Count,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Table,
+    /// Table with additional columns.
+    Wide,
+    /// JSON, or JSON lines for events.
+    Json,
+    /// YAML, or a stream of YAML documents for events.
+    Yaml,
+}
+
+impl OutputFormat {
+    /// Prints the value if the format is JSON or YAML. Returns `false` if the
+    /// value has to be printed as a table.
+    fn print<T: Serialize>(&self, value: &T) -> anyhow::Result<bool> {
+        match self {
+            OutputFormat::Table | OutputFormat::Wide => return Ok(false),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(value)?),
+            OutputFormat::Yaml => print!("{}", serde_yaml::to_string(value)?),
+        }
+        Ok(true)
+    }
+}
+
+/// Process with its container, printed by `process list`.
+#[derive(Serialize)]
+struct ProcessEntry {
+    #[serde(flatten)]
+    process: ProcessInfo,
+    /// Unknown if the container was deleted in the meantime.
+    container: Option<ContainerInfo>,
 }
 
 #[derive(Subcommand)]
 enum SubContainer {
     /// List all containers.
     List,
+    /// Show the container with its process tree, where its policy level
+    /// came from, what every hook does in it and its recent denials.
+    Inspect {
+        /// ID, ID prefix or name of the container, or name of its pod.
+        container: String,
+    },
+    /// Change the policy level of the container.
     ApplyPolicy {
-        /// The ID of the container.
-        container_id: String,
+        /// ID, ID prefix or name of the container, or name of its pod.
+        container: String,
         /// The policy to apply.
         #[clap(value_enum)]
         policy: ContainerPolicyLevel,
@@ -50,104 +137,543 @@ enum SubProcess {
     List,
 }
 
-fn load_bpf() -> anyhow::Result<Bpf> {
-    #[cfg(debug_assertions)]
-    let bpf = BpfLoader::new()
-        .map_pin_path(PATH_BASE)
-        .load(include_bytes_aligned!(
-            "../../target/bpfel-unknown-none/debug/lockc"
-        ))?;
-    #[cfg(not(debug_assertions))]
-    let bpf = BpfLoader::new()
-        .map_pin_path(PATH_BASE)
-        .load(include_bytes_aligned!(
-            "../../target/bpfel-unknown-none/release/lockc"
-        ))?;
+/// Sends the request to the daemon and returns the reader of its responses.
+fn request(socket: &Path, request: &Request) -> anyhow::Result<BufReader<UnixStream>> {
+    let mut stream = UnixStream::connect(socket)
+        .with_context(|| format!("could not connect to lockc at {}", socket.display()))?;
+    let mut data = serde_json::to_vec(request)?;
+    data.push(b'\n');
+    stream.write_all(&data)?;
+    Ok(BufReader::new(stream))
+}
+
+/// Sends the request to the daemon and returns its only response.
+fn call(socket: &Path, req: &Request) -> anyhow::Result<Response> {
+    let mut line = String::new();
+    request(socket, req)?.read_line(&mut line)?;
+    match serde_json::from_str(&line)? {
+        Response::Error(e) => anyhow::bail!(e),
+        response => Ok(response),
+    }
+}
 
-    Ok(bpf)
+fn list_containers(socket: &Path) -> anyhow::Result<Vec<ContainerInfo>> {
+    match call(socket, &Request::ListContainers)? {
+        Response::Containers(containers) => Ok(containers),
+        response => anyhow::bail!("unexpected response: {:?}", response),
+    }
 }
 
-fn container_list() -> anyhow::Result<()> {
-    let bpf = load_bpf()?;
+/// Returns the value for a table cell, which is `-` if unknown.
+fn or_dash<T: ToString>(value: Option<T>) -> String {
+    value
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| "-".to_owned())
+}
 
-    let containers: HashMap<MapRef, ContainerID, Container> = bpf.map("CONTAINERS")?.try_into()?;
+fn print_containers(containers: &[ContainerInfo], output: OutputFormat) -> anyhow::Result<()> {
+    let wide = output == OutputFormat::Wide;
     let mut table = Vec::new();
-    for res in containers.iter() {
-        let (container_id, container) = res?;
-        table.push(vec![
-            container_id.as_str()?.to_string().cell(),
-            format!("{}", container.policy_level).cell(),
-        ]);
+    for container in containers {
+        let metadata = &container.metadata;
+        let mut row = vec![
+            container.container_id.as_str().cell(),
+            or_dash(metadata.namespace.as_deref()).cell(),
+            or_dash(metadata.pod.as_deref()).cell(),
+            or_dash(metadata.container_name.as_deref()).cell(),
+            or_dash(metadata.image.as_deref()).cell(),
+            or_dash(metadata.runtime).cell(),
+            container.policy_level.to_string().cell(),
+        ];
+        if wide {
+            row.extend([
+                or_dash(container.policy_source).cell(),
+                container.enforcement_mode.to_string().cell(),
+                or_dash(container.registered_at.map(format_time)).cell(),
+            ]);
+        }
+        table.push(row);
     }
 
-    let table = table.table().title(vec![
+    let mut title = vec![
         "Container ID".cell().bold(true),
+        "Namespace".cell().bold(true),
+        "Pod".cell().bold(true),
+        "Container".cell().bold(true),
+        "Image".cell().bold(true),
+        "Runtime".cell().bold(true),
         "Policy Level".cell().bold(true),
-    ]);
+    ];
+    if wide {
+        title.extend([
+            "Policy Source".cell().bold(true),
+            "Enforcement".cell().bold(true),
+            "Registered".cell().bold(true),
+        ]);
+    }
 
-    print_stdout(table)?;
+    print_stdout(table.table().title(title))?;
 
     Ok(())
 }
 
-fn container_apply_policy(
-    container_id: String,
-    policy: ContainerPolicyLevel,
-) -> anyhow::Result<()> {
-    let bpf = load_bpf()?;
+fn container_list(socket: &Path, output: OutputFormat) -> anyhow::Result<()> {
+    let containers = list_containers(socket)?;
+    if output.print(&containers)? {
+        return Ok(());
+    }
 
-    let mut containers: HashMap<MapRefMut, ContainerID, Container> =
-        bpf.map_mut("CONTAINERS")?.try_into()?;
+    print_containers(&containers, output)
+}
 
-    let key = ContainerID::from_str(&container_id)?;
-    if containers.get(&key, 0).is_err() {
-        return Err(anyhow::anyhow!("container {} not found", container_id));
+/// Explains where the policy level of the container came from.
+fn explain_policy_source(info: &ContainerInfo) -> String {
+    match info.policy_source {
+        Some(PolicySource::NamespaceLabel) => format!(
+            "pod-security.kubernetes.io/enforce label of namespace {}",
+            or_dash(info.metadata.namespace.as_deref())
+        ),
+        Some(PolicySource::KubeSystem) => {
+            "kube-system namespace, which is always privileged".to_owned()
+        }
+        Some(PolicySource::DockerLabel) => "org.lockc.policy label of the container".to_owned(),
+        Some(PolicySource::Default) => {
+            "default policy level, the container has no policy label".to_owned()
+        }
+        Some(PolicySource::Fallback) => {
+            "fallback policy level for containers of unknown engines".to_owned()
+        }
+        Some(PolicySource::Manual) => "applied with lockctl, overriding labels".to_owned(),
+        None => "-".to_owned(),
     }
+}
 
-    let container = Container {
-        policy_level: policy,
+/// Returns the processes in depth-first order of the tree made by their
+/// parent PIDs, each with the prefix which draws its branch.
+fn process_tree(processes: &[ProcessInfo]) -> Vec<(String, &ProcessInfo)> {
+    fn visit<'a>(
+        process: &'a ProcessInfo,
+        indent: &str,
+        branch: &str,
+        children: &HashMap<i32, Vec<&'a ProcessInfo>>,
+        tree: &mut Vec<(String, &'a ProcessInfo)>,
+    ) {
+        tree.push((format!("{}{}", indent, branch), process));
+        if let Some(children_of_process) = children.get(&process.pid) {
+            let indent = match branch {
+                "" => indent.to_owned(),
+                "├─ " => format!("{}│  ", indent),
+                _ => format!("{}   ", indent),
+            };
+            for (i, child) in children_of_process.iter().enumerate() {
+                let branch = if i + 1 == children_of_process.len() {
+                    "└─ "
+                } else {
+                    "├─ "
+                };
+                visit(child, &indent, branch, children, tree);
+            }
+        }
+    }
+
+    let pids = processes
+        .iter()
+        .map(|process| process.pid)
+        .collect::<HashSet<_>>();
+    let mut roots = Vec::new();
+    let mut children: HashMap<i32, Vec<&ProcessInfo>> = HashMap::new();
+    for process in processes {
+        match process.ppid {
+            Some(ppid) if ppid != process.pid && pids.contains(&ppid) => {
+                children.entry(ppid).or_default().push(process)
+            }
+            _ => roots.push(process),
+        }
+    }
+
+    let mut tree = Vec::new();
+    for root in roots {
+        visit(root, "", "", &children, &mut tree);
+    }
+    tree
+}
+
+fn container_inspect(socket: &Path, container: String, output: OutputFormat) -> anyhow::Result<()> {
+    let details = match call(socket, &Request::InspectContainer { container })? {
+        Response::Container(details) => details,
+        response => anyhow::bail!("unexpected response: {:?}", response),
     };
-    containers.remove(&key)?;
-    containers.insert(key, container, 0)?;
+    if output.print(&details)? {
+        return Ok(());
+    }
+
+    let info = &details.container;
+    let table = vec![
+        vec!["Container ID".cell(), info.container_id.as_str().cell()],
+        vec!["Name".cell(), or_dash(info.metadata.display_name()).cell()],
+        vec![
+            "Image".cell(),
+            or_dash(info.metadata.image.as_deref()).cell(),
+        ],
+        vec!["Runtime".cell(), or_dash(info.metadata.runtime).cell()],
+        vec!["Policy Level".cell(), info.policy_level.to_string().cell()],
+        vec![
+            "Policy Source".cell(),
+            format!(
+                "{} ({})",
+                or_dash(info.policy_source),
+                explain_policy_source(info)
+            )
+            .cell(),
+        ],
+        vec![
+            "Enforcement".cell(),
+            info.enforcement_mode.to_string().cell(),
+        ],
+        vec![
+            "Registered".cell(),
+            or_dash(info.registered_at.map(format_time)).cell(),
+        ],
+    ]
+    .table();
+    print_stdout(table)?;
+
+    println!("\nProcesses:");
+    let table = process_tree(&details.processes)
+        .into_iter()
+        .map(|(branch, process)| {
+            vec![
+                format!("{}{}", branch, process.pid).cell(),
+                process.running.to_string().cell(),
+                or_dash(process.exe.as_deref()).cell(),
+            ]
+        })
+        .collect::<Vec<_>>()
+        .table()
+        .title(vec![
+            "PID".cell().bold(true),
+            "Running".cell().bold(true),
+            "Command".cell().bold(true),
+        ]);
+    print_stdout(table)?;
+
+    println!("\nHooks:");
+    let table = details
+        .hooks
+        .iter()
+        .map(|hook| {
+            vec![
+                hook.hook.to_string().cell(),
+                hook.behavior.as_str().cell(),
+                hook.allowed.to_string().cell(),
+                hook.denied.to_string().cell(),
+            ]
+        })
+        .collect::<Vec<_>>()
+        .table()
+        .title(vec![
+            "Hook".cell().bold(true),
+            "Behavior".cell().bold(true),
+            "Allowed".cell().bold(true),
+            "Denied".cell().bold(true),
+        ]);
+    print_stdout(table)?;
+
+    println!("\nRecent denials:");
+    let table = details
+        .recent_denials
+        .iter()
+        .map(|event| {
+            vec![
+                format_time(event.timestamp).cell(),
+                event.hook.to_string().cell(),
+                event.action_description().cell(),
+                event.comm.as_str().cell(),
+                event.pid.to_string().cell(),
+            ]
+        })
+        .collect::<Vec<_>>()
+        .table()
+        .title(vec![
+            "Time".cell().bold(true),
+            "Hook".cell().bold(true),
+            "Action".cell().bold(true),
+            "Command".cell().bold(true),
+            "PID".cell().bold(true),
+        ]);
+    print_stdout(table)?;
 
     Ok(())
 }
 
-fn process_list() -> anyhow::Result<()> {
-    let bpf = load_bpf()?;
+fn container_apply_policy(
+    socket: &Path,
+    container: String,
+    policy: ContainerPolicyLevel,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    let details = match call(
+        socket,
+        &Request::ApplyPolicy {
+            container,
+            policy_level: policy,
+        },
+    )? {
+        Response::Container(details) => details,
+        response => anyhow::bail!("unexpected response: {:?}", response),
+    };
+    if output.print(&details.container)? {
+        return Ok(());
+    }
 
-    let processes: HashMap<MapRef, i32, Process> = bpf.map("PROCESSES")?.try_into()?;
-    let containers: HashMap<MapRef, ContainerID, Container> = bpf.map("CONTAINERS")?.try_into()?;
+    print_containers(&[details.container], output)
+}
+
+fn process_list(socket: &Path, output: OutputFormat) -> anyhow::Result<()> {
+    let processes = match call(socket, &Request::ListProcesses)? {
+        Response::Processes(processes) => processes,
+        response => anyhow::bail!("unexpected response: {:?}", response),
+    };
+    let containers = list_containers(socket)?
+        .into_iter()
+        .map(|container| (container.container_id.clone(), container))
+        .collect::<HashMap<_, _>>();
+    let processes = processes
+        .into_iter()
+        .map(|process| ProcessEntry {
+            container: containers.get(&process.container_id).cloned(),
+            process,
+        })
+        .collect::<Vec<_>>();
+    if output.print(&processes)? {
+        return Ok(());
+    }
+
+    let wide = output == OutputFormat::Wide;
     let mut table = Vec::new();
-    for res in processes.iter() {
-        let (pid, process) = res?;
-        let (stat, running) = match procfs::process::Process::new(pid) {
-            Ok(stat) => (Some(stat), true),
-            Err(_) => (None, false),
-        };
-        let exe = match stat {
-            Some(stat) => stat.exe()?.to_string_lossy().to_string(),
-            None => "-".to_owned(),
-        };
-        let container = containers.get(&process.container_id, 0)?;
-        table.push(vec![
-            pid.to_string().cell(),
-            format!("{}", running).cell(),
-            exe.cell(),
-            process.container_id.as_str()?.to_string().cell(),
-            format!("{}", container.policy_level).cell(),
-        ]);
+    for ProcessEntry { process, container } in &processes {
+        let container = container.as_ref();
+        let mut row = vec![
+            process.pid.to_string().cell(),
+            process.running.to_string().cell(),
+            or_dash(process.exe.as_deref()).cell(),
+            process.container_id.as_str().cell(),
+            or_dash(container.and_then(|container| container.metadata.display_name())).cell(),
+            or_dash(container.map(|container| container.policy_level)).cell(),
+        ];
+        if wide {
+            row.extend([
+                or_dash(container.and_then(|container| container.metadata.image.as_deref())).cell(),
+                or_dash(container.and_then(|container| container.policy_source)).cell(),
+                or_dash(container.map(|container| container.enforcement_mode)).cell(),
+            ]);
+        }
+        table.push(row);
     }
 
-    let table = table.table().title(vec![
+    let mut title = vec![
         "PID".cell().bold(true),
         "Running".cell().bold(true),
         "Command".cell().bold(true),
         "Container ID".cell().bold(true),
+        "Container".cell().bold(true),
         "Policy Level".cell().bold(true),
-    ]);
+    ];
+    if wide {
+        title.extend([
+            "Image".cell().bold(true),
+            "Policy Source".cell().bold(true),
+            "Enforcement".cell().bold(true),
+        ]);
+    }
 
-    print_stdout(table)?;
+    print_stdout(table.table().title(title))?;
+
+    Ok(())
+}
+
+fn stats(
+    socket: &Path,
+    container: Option<String>,
+    sort: StatsSort,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    let mut stats = match call(socket, &Request::Stats { container })? {
+        Response::Stats(stats) => stats,
+        response => anyhow::bail!("unexpected response: {:?}", response),
+    };
+
+    match sort {
+        StatsSort::Container => {
+            stats.sort_by_key(|s| (s.container_id.clone(), s.hook as u32, s.decision as u32))
+        }
+        StatsSort::Count => stats.sort_by_key(|s| std::cmp::Reverse(s.count)),
+    }
+
+    if output.print(&stats)? {
+        return Ok(());
+    }
+
+    // Policy levels are shown only in the wide output, so the containers are
+    // not fetched otherwise.
+    let containers = if output == OutputFormat::Wide {
+        list_containers(socket)?
+            .into_iter()
+            .map(|container| (container.container_id.clone(), container))
+            .collect::<HashMap<_, _>>()
+    } else {
+        HashMap::new()
+    };
+
+    let mut table = Vec::new();
+    for s in &stats {
+        let mut row = vec![
+            s.container_id.as_str().cell(),
+            or_dash(s.container_name.as_deref()).cell(),
+            s.hook.to_string().cell(),
+            s.decision.to_string().cell(),
+            s.count.to_string().cell(),
+        ];
+        if output == OutputFormat::Wide {
+            let container = containers.get(&s.container_id);
+            row.extend([
+                or_dash(container.map(|container| container.policy_level)).cell(),
+                or_dash(container.map(|container| container.enforcement_mode)).cell(),
+            ]);
+        }
+        table.push(row);
+    }
+
+    let mut title = vec![
+        "Container ID".cell().bold(true),
+        "Container".cell().bold(true),
+        "Hook".cell().bold(true),
+        "Decision".cell().bold(true),
+        "Count".cell().bold(true),
+    ];
+    if output == OutputFormat::Wide {
+        title.extend([
+            "Policy Level".cell().bold(true),
+            "Enforcement".cell().bold(true),
+        ]);
+    }
+
+    print_stdout(table.table().title(title))?;
+
+    Ok(())
+}
+
+/// Formats the time in seconds since Unix epoch as UTC date and time.
+fn format_time(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+    // Conversion of days since epoch to a civil date, from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+fn print_event_row(event: &DaemonEvent, wide: bool) {
+    let container = event.metadata().display_name().unwrap_or_else(|| {
+        let id = event.container_id();
+        id[..id.len().min(12)].to_string()
+    });
+    let (kind, details) = match event {
+        DaemonEvent::Denial(event) => (
+            "denial",
+            format!(
+                "{} {} (comm: {}, pid: {})",
+                event.decision,
+                event.action_description(),
+                event.comm,
+                event.pid
+            ),
+        ),
+        DaemonEvent::TrackingFailure(event) => (
+            "untracked",
+            format!(
+                "could not register process, {} (comm: {}, pid: {})",
+                event.consequence(),
+                event.comm,
+                event.pid
+            ),
+        ),
+        DaemonEvent::ContainerRegistered(event) => (
+            "registered",
+            format!("policy source: {}", event.policy_source),
+        ),
+        DaemonEvent::ContainerDeleted(_) => ("deleted", String::new()),
+        DaemonEvent::PolicyChanged(event) => {
+            ("policy", format!("policy source: {}", event.policy_source))
+        }
+    };
+    print!(
+        "{:<20}  {:<10}  {:<40}  {:<20}  {:<10}  ",
+        format_time(event.timestamp()),
+        kind,
+        container,
+        or_dash(event.hook()),
+        event.policy_level(),
+    );
+    if wide {
+        print!(
+            "{:<64}  {:<40}  ",
+            event.container_id(),
+            or_dash(event.metadata().image.as_deref())
+        );
+    }
+    println!("{}", details);
+}
+
+fn events(
+    socket: &Path,
+    filter: EventFilter,
+    follow: bool,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    let reader = request(socket, &Request::Events { filter, follow })?;
+
+    let wide = output == OutputFormat::Wide;
+    if let OutputFormat::Table | OutputFormat::Wide = output {
+        print!(
+            "{:<20}  {:<10}  {:<40}  {:<20}  {:<10}  ",
+            "TIME", "TYPE", "CONTAINER", "HOOK", "LEVEL"
+        );
+        if wide {
+            print!("{:<64}  {:<40}  ", "CONTAINER ID", "IMAGE");
+        }
+        println!("DETAILS");
+    }
+    for line in reader.lines() {
+        match serde_json::from_str(&line?)? {
+            Response::Event(event) => match output {
+                OutputFormat::Table | OutputFormat::Wide => print_event_row(&event, wide),
+                OutputFormat::Json => println!("{}", serde_json::to_string(&event)?),
+                // Every document starts with `---`, so the events make a
+                // valid YAML stream.
+                OutputFormat::Yaml => print!("{}", serde_yaml::to_string(&event)?),
+            },
+            Response::Lagged(skipped) => eprintln!("warning: missed {} events", skipped),
+            Response::Error(e) => anyhow::bail!(e),
+            response => anyhow::bail!("unexpected response: {:?}", response),
+        }
+    }
 
     Ok(())
 }
@@ -157,15 +683,33 @@ fn main() -> anyhow::Result<()> {
 
     match args.subcommand {
         Sub::Container { container } => match container {
-            SubContainer::List => container_list()?,
-            SubContainer::ApplyPolicy {
-                container_id,
-                policy,
-            } => container_apply_policy(container_id, policy)?,
+            SubContainer::List => container_list(&args.socket, args.output)?,
+            SubContainer::Inspect { container } => {
+                container_inspect(&args.socket, container, args.output)?
+            }
+            SubContainer::ApplyPolicy { container, policy } => {
+                container_apply_policy(&args.socket, container, policy, args.output)?
+            }
         },
         Sub::Process { process } => match process {
-            SubProcess::List => process_list()?,
+            SubProcess::List => process_list(&args.socket, args.output)?,
         },
+        Sub::Stats { container, sort } => stats(&args.socket, container, sort, args.output)?,
+        Sub::Events {
+            follow,
+            container,
+            namespace,
+            hook,
+            level,
+        } => {
+            let filter = EventFilter {
+                container,
+                namespace,
+                hook,
+                policy_level: level,
+            };
+            events(&args.socket, filter, follow, args.output)?
+        }
     }
 
     Ok(())
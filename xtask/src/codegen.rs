@@ -4,7 +4,14 @@ use aya_tool::generate::InputFile;
 
 pub fn generate() -> Result<(), anyhow::Error> {
     let dir = PathBuf::from("lockc-ebpf/src");
-    let names: Vec<&str> = vec!["cred", "file", "sock", "sock_common", "task_struct"];
+    let names: Vec<&str> = vec![
+        "cred",
+        "file",
+        "sock",
+        "sock_common",
+        "task_struct",
+        "unix_sock",
+    ];
     let bindings = aya_tool::generate(
         InputFile::Btf(PathBuf::from("/sys/kernel/btf/vmlinux")),
         &names,